@@ -0,0 +1,80 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use nutt_web::modules::limits::Limits;
+use nutt_web::modules::parser::HeadParser;
+
+fn request(headers: usize) -> Vec<u8> {
+    let mut req = String::from("POST /api/v1/users?page=2 HTTP/1.1\r\nHost: example.com\r\n");
+    for i in 0..headers {
+        req.push_str(&format!("X-Header-{}: value-{}-with-some-padding\r\n", i, i));
+    }
+    req.push_str("Content-Length: 0\r\n\r\n");
+    req.into_bytes()
+}
+
+fn limits() -> Limits {
+    Limits::new().max_headers(1000).max_header_size(1024 * 1024)
+}
+
+/// The line reader used before `HeadParser`: every line searches the whole
+/// accumulated head, then every header is found again with `lines().nth(i)`
+fn previous_parser(bytes: &[u8]) -> (usize, usize) {
+    let text = std::str::from_utf8(bytes).unwrap();
+    let mut req = String::new();
+    let mut content_length = 0;
+    for line in text.split_inclusive('\n') {
+        req.push_str(line);
+        if req.ends_with("\r\n\r\n") {
+            break;
+        }
+        if let Some(start) = req.find("Content-Length: ") {
+            let len_str = &req[start + 16..];
+            if let Some(end) = len_str.find("\r\n") {
+                content_length = len_str[..end].trim().parse::<usize>().unwrap();
+            }
+        }
+    }
+    let mut headers = Vec::new();
+    let mut i = 1;
+    while let Some(line) = req.lines().nth(i) {
+        if line.is_empty() {
+            break;
+        }
+        headers.push(line.to_string());
+        i += 1;
+    }
+    (headers.len(), content_length)
+}
+
+fn head_parser(bytes: &[u8], chunk: usize) -> (usize, usize) {
+    let mut parser = HeadParser::new(&limits());
+    let mut end = 0;
+    let len = loop {
+        end = (end + chunk).min(bytes.len());
+        if let Some(len) = parser.advance(&bytes[..end]).unwrap() {
+            break len;
+        }
+    };
+    let head = parser.head(&bytes[..len]).unwrap();
+    (head.get_headers().len(), head.get_content_length().unwrap())
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_head");
+    for headers in [8, 32, 100, 400] {
+        let req = request(headers);
+        group.bench_with_input(BenchmarkId::new("previous", headers), &req, |b, req| {
+            b.iter(|| previous_parser(black_box(req)))
+        });
+        group.bench_with_input(BenchmarkId::new("head_parser", headers), &req, |b, req| {
+            b.iter(|| head_parser(black_box(req), req.len()))
+        });
+        // A client sending its headers in small writes
+        group.bench_with_input(BenchmarkId::new("head_parser_64b_reads", headers), &req, |b, req| {
+            b.iter(|| head_parser(black_box(req), 64))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
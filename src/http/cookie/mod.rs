@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::time::SystemTime;
 
 #[derive(Debug, Clone)]
@@ -79,8 +80,10 @@ impl CookieRes {
     pub fn set_same_site(&mut self, same_site: SameSite) {
         self.same_site = Some(same_site);
     }
+}
 
-    pub fn to_string(&self) -> String {
+impl Display for CookieRes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut cookie_string = format!("{}={}", self.name, self.value);
 
         if let Some(ref domain) = self.domain {
@@ -121,7 +124,7 @@ impl CookieRes {
             cookie_string.push_str(&format!("; SameSite={}", same_site_str));
         }
 
-        cookie_string
+        write!(f, "{}", cookie_string)
     }
 }
 
@@ -134,6 +137,12 @@ pub struct CookieJar {
     cookies: HashMap<String, CookieReq>,
 }
 
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CookieJar {
     pub fn new() -> Self {
         Self {
@@ -145,7 +154,7 @@ impl CookieJar {
         self.cookies.insert(name.to_string(), cookie);
     }
 
-    pub fn iter(&self) -> CookieJarIter {
+    pub fn iter(&self) -> CookieJarIter<'_> {
         CookieJarIter {
             slice: self.cookies.iter(),
         }
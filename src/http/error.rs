@@ -0,0 +1,95 @@
+use crate::http::method::Method;
+use crate::http::status::StatusCode;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The request could not be parsed from the stream
+    Parse,
+    /// An extractor could not build a handler argument from the request
+    Rejection,
+    /// The handler itself failed while producing a response
+    Handler,
+    /// The client was too slow sending the request, or the handler too slow answering it
+    Timeout,
+    /// The request line, headers or body are larger than allowed
+    Limit,
+    /// The request waited too long for a free slot and was shed
+    Overload,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpError {
+    kind: ErrorKind,
+    status: StatusCode,
+    message: String,
+    method: Option<Method>,
+    path: Option<String>,
+    backtrace: Option<String>,
+}
+
+impl HttpError {
+    pub fn new(kind: ErrorKind, status: StatusCode, message: &str) -> Self {
+        Self {
+            kind,
+            status,
+            message: message.to_string(),
+            method: None,
+            path: None,
+            backtrace: None,
+        }
+    }
+
+    pub(crate) fn with_request(mut self, method: Method, path: String) -> Self {
+        self.method = Some(method);
+        self.path = Some(path);
+        self
+    }
+
+    pub(crate) fn with_backtrace(mut self, backtrace: Option<String>) -> Self {
+        self.backtrace = backtrace;
+        self
+    }
+}
+
+impl HttpError {
+    pub fn get_kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn get_status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn get_message(&self) -> String {
+        self.message.clone()
+    }
+
+    pub fn get_method(&self) -> Option<Method> {
+        self.method.clone()
+    }
+
+    pub fn get_path(&self) -> Option<String> {
+        self.path.clone()
+    }
+
+    /// Backtrace of a handler panic, only captured in debug builds
+    pub fn get_backtrace(&self) -> Option<String> {
+        self.backtrace.clone()
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.method, &self.path) {
+            (Some(method), Some(path)) => write!(
+                f,
+                "{:?} error on {} {}: {} ({})",
+                self.kind, method, path, self.message, self.status
+            ),
+            _ => write!(f, "{:?} error: {} ({})", self.kind, self.message, self.status),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
@@ -1,4 +1,5 @@
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub enum Method {
@@ -22,3 +23,17 @@ impl Display for Method {
         )
     }
 }
+
+impl FromStr for Method {
+    type Err = anyhow::Error;
+
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
+        match method {
+            "GET" => Ok(Method::GET),
+            "POST" => Ok(Method::POST),
+            "PUT" => Ok(Method::PUT),
+            "DELETE" => Ok(Method::DELETE),
+            _ => Err(anyhow::Error::msg("Unsupported HTTP method")),
+        }
+    }
+}
@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 
 pub mod cookie;
+pub mod error;
 pub mod method;
 pub mod request;
 pub mod response;
@@ -16,9 +17,13 @@ pub struct HttpHeader {
 impl HttpHeader {
     pub fn new<T: Serialize + Clone + Send>(response: T) -> Self {
         let content = serde_json::to_string(&response).unwrap();
+        Self::with_content("application/json", content.len())
+    }
+
+    pub fn with_content(content_type: &str, content_length: usize) -> Self {
         let mut base_headers = HashMap::new();
-        base_headers.insert("Content-Type".to_string(), "application/json".to_string());
-        base_headers.insert("Content-Length".to_string(), content.len().to_string());
+        base_headers.insert("Content-Type".to_string(), content_type.to_string());
+        base_headers.insert("Content-Length".to_string(), content_length.to_string());
         base_headers.insert("Connection".to_string(), "keep-alive".to_string());
 
         Self {
@@ -40,10 +45,28 @@ impl Display for HttpHeader {
 #[derive(Clone, Debug)]
 pub struct HttpBody {
     body: Value,
+    raw: bool,
 }
 
 impl HttpBody {
     pub fn new(value: Value) -> HttpBody {
-        Self { body: value }
+        Self { body: value, raw: false }
+    }
+
+    /// Body written as is instead of being serialized to JSON
+    pub fn raw(text: String) -> HttpBody {
+        Self {
+            body: Value::String(text),
+            raw: true,
+        }
+    }
+}
+
+impl Display for HttpBody {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.body {
+            Value::String(text) if self.raw => write!(f, "{}", text),
+            body => write!(f, "{}", body),
+        }
     }
 }
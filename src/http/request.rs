@@ -1,27 +1,37 @@
-use crate::http::cookie::CookieJar;
+use crate::http::cookie::{CookieJar, CookieReq};
 use crate::http::method::Method;
-use crate::http::HttpBody;
+use crate::modules::config::Config;
 use crate::modules::session::Session;
+use crate::modules::state::{State, StateMap};
+use crate::modules::tls::PeerCertificate;
+use crate::modules::websocket::WebSocketConfig;
+use bytes::Bytes;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::Error;
-use std::any::Any;
+use serde_json::{Error, Value};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 
 #[derive(Debug)]
 pub struct Request {
     method: Method,
+    path: String,
+    headers: HashMap<String, String>,
     session: Arc<Option<Session>>,
-    states: Arc<RwLock<HashMap<String, Box<dyn Any + Send + Sync>>>>,
-    body: HttpBody,
+    states: Arc<RwLock<StateMap>>,
+    body: Bytes,
     cookie_jar: CookieJar,
+    peer_certificate: Option<PeerCertificate>,
+    preflighted: bool,
+    websocket: Option<Arc<WebSocketConfig>>,
+    mount_path: Option<String>,
+    body_error: Arc<Mutex<Option<String>>>,
 }
 
 impl Request {
     pub(crate) fn set_states(
         &mut self,
-        states: Arc<RwLock<HashMap<String, Box<dyn Any + Send + Sync>>>>,
+        states: Arc<RwLock<StateMap>>,
     ) {
         self.states = states;
     }
@@ -29,17 +39,57 @@ impl Request {
         self.session = session;
     }
 
-    pub(crate) fn set_cookie_jar(&mut self, cookie_jar: CookieJar) {
-        self.cookie_jar = cookie_jar
+    pub(crate) fn set_peer_certificate(&mut self, peer_certificate: Option<PeerCertificate>) {
+        self.peer_certificate = peer_certificate;
+    }
+
+    /// Marks the route middleware as passed before the body was read
+    pub(crate) fn set_preflighted(&mut self) {
+        self.preflighted = true;
+    }
+
+    pub(crate) fn is_preflighted(&self) -> bool {
+        self.preflighted
+    }
+
+    pub(crate) fn set_websocket_config(&mut self, config: Arc<WebSocketConfig>) {
+        self.websocket = Some(config);
+    }
+
+    pub(crate) fn get_websocket_config(&self) -> Option<Arc<WebSocketConfig>> {
+        self.websocket.clone()
+    }
+
+    /// Prefix of the route that matched, for routes serving a whole subtree
+    pub(crate) fn set_mount_path(&mut self, path: &str) {
+        self.mount_path = Some(path.to_string());
+    }
+
+    pub(crate) fn get_mount_path(&self) -> Option<String> {
+        self.mount_path.clone()
     }
 }
 
 impl Request {
     pub fn body_json<T: for<'a> Deserialize<'a> + DeserializeOwned>(&self) -> Result<T, Error> {
-        serde_json::from_str::<T>(self.body.body.as_str().unwrap())
+        let parsed = serde_json::from_slice::<T>(&self.body);
+        if let Err(e) = &parsed {
+            *self.body_error.lock().unwrap() = Some(e.to_string());
+        }
+        parsed
+    }
+
+    /// Body as it was received, after any `Content-Encoding` is undone
+    pub fn get_body_bytes(&self) -> Bytes {
+        self.body.clone()
+    }
+
+    /// Why the body last failed to parse, shared with clones of the handle
+    pub(crate) fn body_error(&self) -> Arc<Mutex<Option<String>>> {
+        self.body_error.clone()
     }
 
-    pub fn get_state(&self) -> RwLockReadGuard<'_, HashMap<String, Box<dyn Any + Send + Sync>>> {
+    pub fn get_state(&self) -> RwLockReadGuard<'_, StateMap> {
         self.states.try_read().unwrap()
     }
 
@@ -50,30 +100,125 @@ impl Request {
         self.method.clone()
     }
 
+    pub fn get_path(&self) -> String {
+        self.path.clone()
+    }
+
+    /// Header value by case-insensitive name
+    pub fn get_header(&self, name: &str) -> Option<String> {
+        self.headers.get(&name.to_ascii_lowercase()).cloned()
+    }
+
+    pub fn get_headers(&self) -> HashMap<String, String> {
+        self.headers.clone()
+    }
+
+    /// Id of the last server-sent event the client got, sent when it reconnects
+    pub fn get_last_event_id(&self) -> Option<String> {
+        self.get_header("Last-Event-ID")
+    }
+
+    /// Host the request was sent to, without the port
+    pub fn get_host(&self) -> Option<String> {
+        let host = self.get_header("Host")?;
+        let host = if host.starts_with('[') {
+            // IPv6 literal, e.g. `[::1]:8080`
+            host.split_inclusive(']').next().unwrap_or_default()
+        } else {
+            host.split(':').next().unwrap_or_default()
+        };
+        Some(host.to_ascii_lowercase())
+    }
+
     pub fn get_cookie_jar(&self) -> CookieJar {
         self.cookie_jar.clone()
     }
+
+    /// Client certificate when the connection uses mutual TLS
+    pub fn get_peer_certificate(&self) -> Option<PeerCertificate> {
+        self.peer_certificate.clone()
+    }
+
+    pub(crate) fn set_body(&mut self, body: Bytes) {
+        self.body = body;
+    }
+
+    pub(crate) fn remove_header(&mut self, name: &str) {
+        self.headers.remove(&name.to_ascii_lowercase());
+    }
+
+    /// App section registered with `NuttServer::config`
+    pub fn get_config<T: Send + Sync + 'static>(&self, section: &str) -> Option<Config<T>> {
+        let states = self.get_state();
+        let state = states.get(section)?.downcast_ref::<State<Config<T>>>()?;
+        let config = state.read().clone();
+        Some(config)
+    }
 }
 
 pub struct RequestBuilder {
     method: Method,
-    body: HttpBody,
-    states: HashMap<String, Box<dyn Any + Send + Sync>>,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Bytes,
+    states: StateMap,
     session: Option<Session>,
     cookie_jar: CookieJar,
 }
 
 impl RequestBuilder {
+    /// A string body is used as it is, anything else is serialized to JSON
     pub fn new<T: Serialize + Clone + Send>(method: Method, body: T) -> Self {
+        let body = match serde_json::to_value(body).unwrap() {
+            Value::String(text) => Bytes::from(text),
+            value => Bytes::from(value.to_string()),
+        };
         Self {
             method,
-            body: HttpBody::new(serde_json::to_value(body).unwrap()),
+            path: "/".to_string(),
+            headers: HashMap::new(),
+            body,
             states: HashMap::new(),
             session: None,
             cookie_jar: CookieJar::new(),
         }
     }
 
+    pub fn set_path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    /// Builder for a request read from the wire, cookies are taken from
+    /// the `Cookie` headers
+    pub(crate) fn from_parts<N: AsRef<str>, V: AsRef<str>>(
+        method: Method,
+        path: &str,
+        headers: &[(N, V)],
+    ) -> anyhow::Result<Self> {
+        let mut builder = Self::new(method, "").set_path(path);
+        let mut cookies = CookieJar::new();
+        for (name, value) in headers {
+            let (name, value) = (name.as_ref(), value.as_ref());
+            if name.eq_ignore_ascii_case("Cookie") {
+                for cookie in value.split(';') {
+                    let Some((key, value)) = cookie.split_once('=') else {
+                        return Err(anyhow::Error::msg("Invalid Cookie header"));
+                    };
+                    cookies.push_cookie(key.trim(), CookieReq::new(value.to_string()));
+                }
+            }
+            builder = builder.set_header(name, value);
+        }
+        Ok(builder.set_cookie_jar(cookies))
+    }
+
+    pub fn set_header(mut self, name: &str, value: &str) -> Self {
+        self.headers
+            .insert(name.to_ascii_lowercase(), value.to_string());
+        self
+    }
+
     pub(crate) fn set_cookie_jar(mut self, cookie_jar: CookieJar) -> Self {
         self.cookie_jar = cookie_jar;
         self
@@ -82,10 +227,17 @@ impl RequestBuilder {
     pub fn build(self) -> Request {
         Request {
             method: self.method,
+            path: self.path,
+            headers: self.headers,
             body: self.body,
             states: Arc::new(RwLock::new(self.states)),
             session: Arc::new(self.session),
             cookie_jar: self.cookie_jar,
+            peer_certificate: None,
+            preflighted: false,
+            websocket: None,
+            mount_path: None,
+            body_error: Arc::new(Mutex::new(None)),
         }
     }
 }
@@ -1,33 +1,134 @@
 pub mod responder;
+pub mod sse;
 
 use crate::http::response::responder::Responder;
 use crate::http::status::StatusCode;
 use crate::http::{HttpBody, HttpHeader};
+use crate::modules::conditional::{self, ETag};
+use crate::modules::upgrade::OnUpgrade;
+use bytes::Bytes;
+use futures_util::Stream;
 use serde::Serialize;
-use serde_json::json;
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Body chunks written as they are produced, e.g. server-sent events
+pub type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
 
 pub struct Response {
     header: HttpHeader,
     status: StatusCode,
-    body: HttpBody,
+    body: Bytes,
+    upgrade: Option<Box<Mutex<OnUpgrade>>>,
+    stream: Option<Box<Mutex<BodyStream>>>,
 }
 
 impl Display for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let resp = format!(
-            "HTTP/1.1 {}\r\n{}\r\n{}\r\n\r\n",
-            self.status,
-            self.header,
-            json!(self.body.body)
-        );
-        write!(f, "{}", resp)
+        write!(f, "{}{}", self.get_head(), String::from_utf8_lossy(&self.body))
+    }
+}
+impl Response {
+    pub fn get_status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn get_headers(&self) -> HashMap<String, String> {
+        self.header.headers.clone()
+    }
+
+    /// Header value, the name is matched ignoring case
+    pub fn get_header(&self, name: &str) -> Option<String> {
+        self.header
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    pub(crate) fn set_status(&mut self, status: StatusCode) {
+        self.status = status;
+    }
+
+    pub(crate) fn set_header(&mut self, key: &str, value: &str) {
+        self.header
+            .headers
+            .insert(key.to_string(), value.to_string());
+    }
+
+    pub(crate) fn remove_header(&mut self, name: &str) {
+        self.header
+            .headers
+            .retain(|key, _| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// Body as it is written to the client
+    pub fn get_body(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    pub fn get_body_bytes(&self) -> Bytes {
+        self.body.clone()
+    }
+
+    /// Replaces the buffered body and its `Content-Length`
+    pub(crate) fn set_body(&mut self, body: Bytes) {
+        self.set_header("Content-Length", &body.len().to_string());
+        self.body = body;
+    }
+
+    /// Status line and headers, ending with the blank line
+    pub(crate) fn get_head(&self) -> String {
+        format!("HTTP/1.1 {}\r\n{}\r\n", self.status, self.header)
+    }
+
+    /// `101 Switching Protocols` with only the given headers, `on_upgrade`
+    /// gets the connection once the response is written
+    pub(crate) fn switching_protocols(headers: &[(&str, &str)], on_upgrade: OnUpgrade) -> Self {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Self {
+            header: HttpHeader { headers },
+            status: StatusCode::SwitchingProtocols,
+            body: Bytes::new(),
+            upgrade: Some(Box::new(Mutex::new(on_upgrade))),
+            stream: None,
+        }
+    }
+
+    pub(crate) fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        self.upgrade
+            .take()
+            .map(|upgrade| upgrade.into_inner().unwrap())
+    }
+
+    /// Streamed body, written after the head instead of the buffered one
+    pub(crate) fn take_stream(&mut self) -> Option<BodyStream> {
+        self.stream
+            .take()
+            .map(|stream| stream.into_inner().unwrap())
+    }
+
+    pub(crate) fn is_streaming(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    pub(crate) fn set_stream(&mut self, stream: BodyStream) {
+        self.stream = Some(Box::new(Mutex::new(stream)));
     }
 }
+
 pub struct ResponseBuilder {
     status: StatusCode,
     header: HttpHeader,
-    body: HttpBody,
+    body: Bytes,
+    stream: Option<BodyStream>,
 }
 
 impl ResponseBuilder {
@@ -37,6 +138,27 @@ impl ResponseBuilder {
             .insert("Set-Cookie".to_string(), format!("{}={};", key, item));
         self
     }
+
+    pub fn set_header(mut self, key: &str, value: &str) -> Self {
+        self.header
+            .headers
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Strong `ETag`, for bodies that are the same byte for byte while it is
+    pub fn etag(self, tag: &str) -> Self {
+        self.set_header("ETag", &ETag::strong(tag).to_string())
+    }
+
+    /// Weak `ETag`, for bodies that may differ in ways that don't matter, e.g. formatting
+    pub fn weak_etag(self, tag: &str) -> Self {
+        self.set_header("ETag", &ETag::weak(tag).to_string())
+    }
+
+    pub fn last_modified(self, time: SystemTime) -> Self {
+        self.set_header("Last-Modified", &conditional::format_http_date(time))
+    }
 }
 
 impl ResponseBuilder {
@@ -44,7 +166,45 @@ impl ResponseBuilder {
         Self {
             status: status_code,
             header: HttpHeader::new(response.clone()),
-            body: HttpBody::new(serde_json::to_value(response).unwrap()),
+            body: Bytes::from(HttpBody::new(serde_json::to_value(response).unwrap()).to_string()),
+            stream: None,
+        }
+    }
+
+    /// Response with a body that is sent as is, e.g. an HTML page
+    pub fn raw(status_code: StatusCode, content_type: &str, body: &str) -> Self {
+        Self {
+            status: status_code,
+            header: HttpHeader::with_content(content_type, body.len()),
+            body: Bytes::from(body.to_string()),
+            stream: None,
+        }
+    }
+
+    /// Response with a binary body, e.g. an image
+    pub fn bytes<B: Into<Bytes>>(status_code: StatusCode, content_type: &str, body: B) -> Self {
+        let body = body.into();
+        Self {
+            status: status_code,
+            header: HttpHeader::with_content(content_type, body.len()),
+            body,
+            stream: None,
+        }
+    }
+
+    /// Response with a body sent in chunks as `stream` yields them, so it has
+    /// no `Content-Length`
+    pub fn stream<S>(status_code: StatusCode, content_type: &str, stream: S) -> Self
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        let mut header = HttpHeader::with_content(content_type, 0);
+        header.headers.remove("Content-Length");
+        Self {
+            status: status_code,
+            header,
+            body: Bytes::new(),
+            stream: Some(Box::pin(stream)),
         }
     }
 
@@ -53,6 +213,8 @@ impl ResponseBuilder {
             status: self.status,
             header: self.header,
             body: self.body,
+            upgrade: None,
+            stream: self.stream.map(|stream| Box::new(Mutex::new(stream))),
         }
     }
 }
@@ -0,0 +1,229 @@
+use crate::http::response::responder::Responder;
+use crate::http::response::{Response, ResponseBuilder};
+use crate::http::status::StatusCode;
+use bytes::Bytes;
+use futures_util::Stream;
+use serde::Serialize;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+/// Idle time after which a comment is sent, so proxies don't close the stream
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// One event of a `text/event-stream`
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sent back by the client in `Last-Event-ID` when it reconnects
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// Event type, `message` on the client when not set
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    /// Every line is sent as its own `data` field
+    pub fn data(mut self, data: &str) -> Self {
+        self.data = Some(data.to_string());
+        self
+    }
+
+    pub fn json_data<T: Serialize>(mut self, data: &T) -> Self {
+        self.data = Some(serde_json::to_string(data).unwrap());
+        self
+    }
+
+    /// Time the client waits before reconnecting
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Ignored by the client
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    fn encode(&self) -> Bytes {
+        let mut out = String::new();
+        if let Some(comment) = &self.comment {
+            for line in lines(comment) {
+                out.push_str(&format!(": {}\n", line));
+            }
+        }
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = self.retry {
+            out.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        if let Some(data) = &self.data {
+            for line in lines(data) {
+                out.push_str(&format!("data: {}\n", line));
+            }
+        }
+        out.push('\n');
+        Bytes::from(out)
+    }
+}
+
+/// Server-sent events response. Each event is written and flushed as soon as
+/// the stream yields it
+pub struct Sse {
+    events: Pin<Box<dyn Stream<Item = Event> + Send>>,
+    keep_alive: Option<Duration>,
+}
+
+impl Sse {
+    pub fn new<S: Stream<Item = Event> + Send + 'static>(events: S) -> Self {
+        Self {
+            events: Box::pin(events),
+            keep_alive: Some(KEEP_ALIVE_INTERVAL),
+        }
+    }
+
+    /// Idle time before a keep-alive comment is sent, `None` turns them off
+    pub fn keep_alive(mut self, interval: Option<Duration>) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+}
+
+impl Responder for Sse {
+    fn into_response(self) -> Response {
+        let stream = EventStream {
+            events: self.events,
+            keep_alive: self.keep_alive,
+            timer: None,
+        };
+        ResponseBuilder::stream(StatusCode::Ok, "text/event-stream", stream)
+            .set_header("Cache-Control", "no-cache")
+            .build()
+    }
+}
+
+struct EventStream {
+    events: Pin<Box<dyn Stream<Item = Event> + Send>>,
+    keep_alive: Option<Duration>,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl Stream for EventStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(interval) = self.keep_alive else {
+            return self
+                .events
+                .as_mut()
+                .poll_next(cx)
+                .map(|event| event.map(|event| Ok(event.encode())));
+        };
+        match self.events.as_mut().poll_next(cx) {
+            Poll::Ready(Some(event)) => {
+                self.timer = None;
+                return Poll::Ready(Some(Ok(event.encode())));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {}
+        }
+        let timer = self
+            .timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(interval)));
+        if timer.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        timer.as_mut().reset(Instant::now() + interval);
+        Poll::Ready(Some(Ok(Bytes::from_static(b": keep-alive\n\n"))))
+    }
+}
+
+/// Drops line breaks, which would end the field early
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// Splits on `\r\n`, `\r` and `\n` like the client does
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split('\n')
+        .flat_map(|line| line.strip_suffix('\r').unwrap_or(line).split('\r'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    fn body(sse: Sse) -> crate::http::response::BodyStream {
+        sse.into_response().take_stream().unwrap()
+    }
+
+    #[test]
+    fn frames_every_field() {
+        let event = Event::new()
+            .comment("first\nsecond")
+            .event("up\ndate")
+            .id("7\r")
+            .retry(Duration::from_millis(2500))
+            .data("one\r\ntwo\rthree\nfour");
+        assert_eq!(
+            event.encode(),
+            ": first\n: second\nevent: update\nid: 7\nretry: 2500\n\
+             data: one\ndata: two\ndata: three\ndata: four\n\n"
+        );
+        assert_eq!(Event::new().json_data(&[1, 2]).encode(), "data: [1,2]\n\n");
+        assert_eq!(Event::new().data("").encode(), "data: \n\n");
+    }
+
+    #[tokio::test]
+    async fn streams_events_as_they_come() {
+        let events = futures_util::stream::iter([Event::new().data("a"), Event::new().data("b")]);
+        let mut resp = Sse::new(events).into_response();
+        assert_eq!(resp.get_header("Content-Type").as_deref(), Some("text/event-stream"));
+        assert_eq!(resp.get_header("Cache-Control").as_deref(), Some("no-cache"));
+        assert!(resp.get_header("Content-Length").is_none());
+
+        let chunks: Vec<_> = resp.take_stream().unwrap().map(Result::unwrap).collect().await;
+        assert_eq!(chunks, ["data: a\n\n", "data: b\n\n"]);
+    }
+
+    #[tokio::test]
+    async fn sends_keep_alive_comments_while_idle() {
+        let interval = Duration::from_millis(50);
+        let events = futures_util::stream::iter([Event::new().data("a")])
+            .chain(futures_util::stream::pending());
+        let mut stream = body(Sse::new(events).keep_alive(Some(interval)));
+
+        let started = Instant::now();
+        assert_eq!(stream.next().await.unwrap().unwrap(), "data: a\n\n");
+        assert_eq!(stream.next().await.unwrap().unwrap(), ": keep-alive\n\n");
+        assert_eq!(stream.next().await.unwrap().unwrap(), ": keep-alive\n\n");
+        assert!(started.elapsed() >= interval * 2);
+
+        let mut stream = body(Sse::new(futures_util::stream::pending()).keep_alive(None));
+        assert!(tokio::time::timeout(interval * 2, stream.next()).await.is_err());
+    }
+}
@@ -1,6 +1,9 @@
 use std::fmt::Display;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum StatusCode {
+    SwitchingProtocols = 101,
+
     Ok = 200,
     Created = 201,
     Accepted = 202,
@@ -38,7 +41,9 @@ pub enum StatusCode {
     ExpectationFailed = 417,
     ImATeapot = 418,
     UnprocessableEntity = 422,
+    UpgradeRequired = 426,
     TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
 
     InternalServerError = 500,
     NotImplemented = 501,
@@ -53,6 +58,8 @@ pub enum StatusCode {
 impl Display for StatusCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
+            StatusCode::SwitchingProtocols => "101 Switching Protocols".to_string(),
+
             StatusCode::Ok => "200 OK".to_string(),
             StatusCode::Created => "201 Created".to_string(),
             StatusCode::Accepted => "202 Accepted".to_string(),
@@ -94,7 +101,11 @@ impl Display for StatusCode {
             StatusCode::ExpectationFailed => "417 Expectation Failed".to_string(),
             StatusCode::ImATeapot => "418 I'm a teapot".to_string(),
             StatusCode::UnprocessableEntity => "422 Unprocessable Entity".to_string(),
+            StatusCode::UpgradeRequired => "426 Upgrade Required".to_string(),
             StatusCode::TooManyRequests => "429 Too Many Requests".to_string(),
+            StatusCode::RequestHeaderFieldsTooLarge => {
+                "431 Request Header Fields Too Large".to_string()
+            }
 
             StatusCode::InternalServerError => "500 Internal Server Error".to_string(),
             StatusCode::NotImplemented => "501 Not Implemented".to_string(),
@@ -108,3 +119,9 @@ impl Display for StatusCode {
         write!(f, "{}", str)
     }
 }
+
+impl StatusCode {
+    pub fn as_u16(&self) -> u16 {
+        *self as u16
+    }
+}
@@ -1,47 +1,98 @@
 pub mod http;
 pub mod modules;
 
-use crate::http::cookie::{CookieJar, CookieReq};
+use crate::http::error::{ErrorKind, HttpError};
 use crate::http::method::Method;
 use crate::http::request::{Request, RequestBuilder};
-use crate::http::response::ResponseBuilder;
+use crate::http::response::{BodyStream, Response};
 use crate::http::status::StatusCode;
+use crate::modules::compression::{self, Compression};
+use crate::modules::config::{Config, ConfigSections};
+use crate::modules::dispatcher::{Dispatcher, ErrorHandler, FallbackHandler};
 use crate::modules::displayable::DisplayableVec;
+use crate::modules::http2::{self, Http2Config};
+use crate::modules::limits::Limits;
+use crate::modules::load_shedding::LoadShedding;
+use crate::modules::metrics::ServerMetrics;
+use crate::modules::listener::{BoundListener, Connection, Listener, Protocol};
+use crate::modules::panic_guard;
+use crate::modules::parser::reader::RequestReader;
+use crate::modules::profile::{Profile, ProfileConfig};
 use crate::modules::router::route::Route;
+use crate::modules::router::host::{HostPattern, VirtualHosts};
 use crate::modules::router::Router;
 use crate::modules::session::cookie_session::CookieSession;
+use crate::modules::server_handle::ServerHandle;
 use crate::modules::session::{Session, SessionType};
-use crate::modules::state::State;
-use crate::modules::stream_reader::StreamReader;
+use crate::modules::shutdown::{self, Drain, ShutdownHook, ShutdownSummary};
+use crate::modules::state::{State, StateMap};
+use crate::modules::timeouts::Timeouts;
+use crate::modules::tls::{CertResolver, ClientAuth, PeerCertificate};
+use crate::modules::upgrade::Upgraded;
+use crate::modules::websocket::WebSocketConfig;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use std::any::Any;
 use std::collections::HashMap;
-use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::{Path, PathBuf};
+use std::future::Future;
+use std::time::{Duration, Instant};
 use std::sync::{Arc, RwLock};
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use rustls::pki_types::pem::PemObject;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio_rustls::rustls::ServerConfig;
-use tokio_rustls::{TlsAcceptor, server::TlsStream};
-use tracing_log::log::{log, Level};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinSet;
+use tokio_rustls::server::TlsStream;
+use tracing_log::log::{log, log_enabled, Level};
 use anyhow::Result;
 
-pub trait Stream {}
+pub trait Stream {
+    /// Client certificate presented during the TLS handshake
+    fn peer_certificate(&self) -> Option<PeerCertificate> {
+        None
+    }
+}
 
-impl Stream for TlsStream<TcpStream> {}
+impl<IO> Stream for TlsStream<IO> {
+    fn peer_certificate(&self) -> Option<PeerCertificate> {
+        let certs = self.get_ref().1.peer_certificates()?;
+        PeerCertificate::from_der(certs.first()?)
+    }
+}
 impl Stream for TcpStream {}
+impl<IO: Stream> Stream for http2::Rewind<IO> {}
+#[cfg(unix)]
+impl Stream for UnixStream {}
 
 pub struct NuttServer {
-    address_dev: Option<(String, u16)>,
-    address_release: Option<(String, u16)>,
+    profiles: HashMap<Profile, ProfileConfig>,
+    active_profile: Option<Profile>,
+    default_profile: ProfileConfig,
+    verbose_errors: bool,
+    config: ConfigSections,
+    listener: Option<std::net::TcpListener>,
+    listeners: Vec<Listener>,
     router: Router,
-    states: Arc<RwLock<HashMap<String, Box<dyn Any + Send + Sync>>>>,
+    hosts: Vec<(HostPattern, Router)>,
+    states: Arc<RwLock<StateMap>>,
     session: Option<Session>,
-    tls_certs: Option<(String, String)>
+    tls_certs: CertResolver,
+    watch_certs: Option<Duration>,
+    client_auth: Option<ClientAuth>,
+    http2: Option<Http2Config>,
+    shutdown_timeout: Duration,
+    timeouts: Timeouts,
+    limits: Limits,
+    load_shedding: Option<LoadShedding>,
+    compression: Option<Compression>,
+    auto_etag: bool,
+    metrics: ServerMetrics,
+    websocket: WebSocketConfig,
+    on_shutdown: Vec<ShutdownHook>,
+    fallback: Option<FallbackHandler>,
+    on_error: Option<ErrorHandler>,
 }
 
 impl Default for NuttServer {
@@ -53,12 +104,32 @@ impl Default for NuttServer {
 impl NuttServer {
     pub fn new() -> Self {
         Self {
-            address_dev: None,
-            address_release: None,
+            profiles: HashMap::new(),
+            active_profile: None,
+            default_profile: ProfileConfig::new(),
+            verbose_errors: false,
+            config: ConfigSections::default(),
+            listener: None,
+            listeners: Vec::new(),
             router: Router::new(),
+            hosts: Vec::new(),
             states: Arc::new(RwLock::new(HashMap::new())),
             session: None,
-            tls_certs: None,
+            tls_certs: CertResolver::new(),
+            watch_certs: None,
+            client_auth: None,
+            http2: None,
+            shutdown_timeout: Duration::from_secs(30),
+            timeouts: Timeouts::new(),
+            limits: Limits::new(),
+            load_shedding: None,
+            compression: None,
+            auto_etag: false,
+            metrics: ServerMetrics::new(),
+            websocket: WebSocketConfig::new(),
+            on_shutdown: Vec::new(),
+            fallback: None,
+            on_error: None,
         }
     }
 
@@ -69,13 +140,65 @@ impl NuttServer {
         self
     }
 
+    /// Merges a router built elsewhere, e.g. exported by a feature module
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = self.router.merge(router);
+        self
+    }
+
+    /// Serves `router` for requests whose `Host` matches `pattern`: an exact
+    /// name (`api.example.com`), a wildcard subdomain (`*.example.com`) or `*`.
+    /// Requests matching no host fall back to the routes set with `routes`/`router`
+    pub fn host(mut self, pattern: &str, router: Router) -> Self {
+        self.hosts.push((HostPattern::parse(pattern), router));
+        self
+    }
+
+    /// Address of the dev profile, also used by profiles without an address
     pub fn bind_dev(mut self, address: (&str, u16)) -> Self {
-        self.address_dev = Some((address.0.to_string(), address.1));
+        let config = self.profiles.remove(&Profile::Dev).unwrap_or_default();
+        self.profiles.insert(Profile::Dev, config.bind(address));
         self
     }
 
+    /// Address of the release profile
     pub fn bind_release(mut self, address: (&str, u16)) -> Self {
-        self.address_release = Some((address.0.to_string(), address.1));
+        let config = self.profiles.remove(&Profile::Release).unwrap_or_default();
+        self.profiles.insert(Profile::Release, config.bind(address));
+        self
+    }
+
+    /// Settings used when `profile` is selected, replacing earlier ones
+    /// including the address set with `bind_dev`/`bind_release`
+    pub fn profile(mut self, profile: Profile, config: ProfileConfig) -> Self {
+        self.profiles.insert(profile, config);
+        self
+    }
+
+    /// Settings for every profile that doesn't set them itself
+    pub fn default_profile(mut self, config: ProfileConfig) -> Self {
+        self.default_profile = config;
+        self
+    }
+
+    /// Runs with `profile` regardless of the `--profile` flag and `NUTT_PROFILE`
+    pub fn active_profile(mut self, profile: Profile) -> Self {
+        self.active_profile = Some(profile);
+        self
+    }
+
+    /// Serves on a listener bound by the caller instead of `bind_dev`/`bind_release`.
+    /// Sockets passed through `LISTEN_FDS` still take its place
+    pub fn listener(mut self, listener: std::net::TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Serves the same routes on another socket as well. The TLS and HTTP/2
+    /// settings of the server only apply to the `bind_dev`/`bind_release`
+    /// address, this listener brings its own
+    pub fn listen(mut self, listener: Listener) -> Self {
+        self.listeners.push(listener);
         self
     }
 
@@ -86,7 +209,25 @@ impl NuttServer {
         self.states
             .try_write()
             .unwrap()
-            .insert(state.0, Box::new(state.1));
+            .insert(state.0, Arc::new(state.1));
+        self
+    }
+
+    /// Reads the settings from a TOML file, overridden by `NUTT_*` environment
+    /// variables. Every invalid setting is reported at once
+    pub fn from_config(path: &str) -> Result<Self> {
+        modules::config::load(Self::new(), path)
+    }
+
+    /// Deserializes the `[section]` table of the config file, handlers get it
+    /// as `section: State<Config<T>>`. Errors are reported when the server starts
+    pub fn config<T: DeserializeOwned + Send + Sync + 'static>(mut self, section: &str) -> Self {
+        if let Some(value) = self.config.take::<T>(section) {
+            self.states
+                .try_write()
+                .unwrap()
+                .insert(section.to_string(), Arc::new(State::new(Config::new(value))));
+        }
         self
     }
 
@@ -97,151 +238,826 @@ impl NuttServer {
         self
     }
 
-    pub fn set_tls_certs(mut self, certs: Option<(&str, &str)>) -> Self {
+    /// Default certificate, used when no certificate matches the SNI name
+    pub fn set_tls_certs(self, certs: Option<(&str, &str)>) -> Self {
         if let Some(certs) = certs {
-            self.tls_certs = Some((certs.0.to_string(), certs.1.to_string()))
+            self.tls_certs.add(None, certs.0, certs.1)
         }
         self
     }
 
-    pub async fn run(self) -> Result<()> {
-        tracing_subscriber::fmt::init();
-        let address = if cfg!(not(debug_assertions)) && self.address_release.is_some() {
-            self.address_release
-        } else {
-            self.address_dev
-        };
-        if let Some(address) = address {
-            let (cert, key) = self.tls_certs.unwrap();
-            let certs = Self::load_certs(&PathBuf::from(cert.clone()));
-            let key = Self::load_private_key(&PathBuf::from(key.clone()));
-
-            // Configure the server with the certificate and private key
-            let mut config = ServerConfig::builder()
-                .with_no_client_auth() // No client certificate authentication
-                .with_single_cert(certs, key).unwrap();
-
-            let acceptor = TlsAcceptor::from(Arc::new(config));
-
-            let listener = tokio::net::TcpListener::bind(format!("{}:{}", address.0, address.1))
-                .await
-                .unwrap();
-            log!(Level::Info, "Server started on {}:{}", address.0, address.1);
-            let router = Arc::new(self.router);
-            let states = self.states.clone();
-            let session = Arc::new(self.session);
-            loop {
-                let router_arc = router.clone();
-                let states_arc = states.clone();
-                let session_arc = session.clone();
-                match listener.accept().await {
-                    Ok((stream, _)) => {
-                        let acceptor_ = acceptor.clone();
-                        tokio::task::spawn(async move {
-                            if let Ok(stream) = acceptor_.accept(stream).await {
-                                match Self::handle_stream(stream).await {
-                                    Ok((method, path, mut stream, mut req)) => {
-                                        if let Some(route) = router_arc.get((method, path)) {
-                                            req.set_states(states_arc.clone());
-                                            req.set_session(session_arc.clone());
-                                            route.run_fabric(stream, req)
-                                        } else {
-                                            stream
-                                                .write(not_found!().to_string().as_bytes()).await?;
-                                        }
-                                    }
-                                    Err(e) => log!(Level::Error, "Error handling stream: {}", e),
-                                }
-                            }
-                            anyhow::Ok(())
+    /// Certificate for the SNI names matching `pattern`, with the same
+    /// pattern syntax as `host`
+    pub fn set_host_tls_certs(self, pattern: &str, certs: (&str, &str)) -> Self {
+        self.tls_certs
+            .add(Some(HostPattern::parse(pattern)), certs.0, certs.1);
+        self
+    }
+
+    /// Checks the certificate files every `interval` and reloads them on change
+    pub fn watch_tls_certs(mut self, interval: Duration) -> Self {
+        self.watch_certs = Some(interval);
+        self
+    }
+
+    /// Verifies client certificates against a CA bundle (mutual TLS)
+    pub fn client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = Some(client_auth);
+        self
+    }
+
+    /// Enables HTTP/2 next to HTTP/1.1
+    pub fn http2(mut self, config: Http2Config) -> Self {
+        self.http2 = Some(config);
+        self
+    }
+
+    /// How long in-flight requests may take to finish after a shutdown
+    /// signal before their connections are aborted
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Deadlines for reading requests, running handlers and writing responses
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Size limits for the request line, headers and body
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Message limits, keepalive and compression of WebSocket routes
+    pub fn websocket(mut self, websocket: WebSocketConfig) -> Self {
+        self.websocket = websocket;
+        self
+    }
+
+    /// Compresses responses with a coding the client accepts
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Tags successful GET responses without an `ETag` with a hash of their body,
+    /// so clients can revalidate them and send conditional writes
+    pub fn auto_etag(mut self, enabled: bool) -> Self {
+        self.auto_etag = enabled;
+        self
+    }
+
+    /// Sheds requests that wait too long for a route concurrency slot
+    pub fn load_shedding(mut self, load_shedding: LoadShedding) -> Self {
+        self.load_shedding = Some(load_shedding);
+        self
+    }
+
+    /// Hook run once all connections are closed during shutdown
+    pub fn on_shutdown<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_shutdown.push(Box::new(move || Box::pin(hook())));
+        self
+    }
+
+    /// Handle for reloading the certificates while the server is running
+    pub fn cert_resolver(&self) -> CertResolver {
+        self.tls_certs.clone()
+    }
+
+    /// Handle for reading connection and load shedding counters
+    pub fn metrics(&self) -> ServerMetrics {
+        self.metrics.clone()
+    }
+
+    /// Handler called for requests that don't match any route
+    pub fn fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.fallback = Some(Arc::new(move |req| Box::pin(handler(req))));
+        self
+    }
+
+    /// Handler called for parse errors, extractor rejections and handler failures
+    pub fn on_error<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(HttpError) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.on_error = Some(Arc::new(move |err| Box::pin(handler(err))));
+        self
+    }
+
+    /// Runs until SIGINT or SIGTERM, then shuts down gracefully
+    pub async fn run(self) -> Result<ShutdownSummary> {
+        self.run_until(shutdown::signal()).await
+    }
+
+    /// Runs until `shutdown` resolves. The server then stops accepting,
+    /// waits up to `shutdown_timeout` for in-flight requests, closes idle
+    /// keep-alive connections and runs the `on_shutdown` hooks
+    pub async fn run_until<F: Future<Output = ()>>(
+        mut self,
+        shutdown: F,
+    ) -> Result<ShutdownSummary> {
+        let listeners = self.bind().await?;
+        self.serve(listeners, shutdown).await
+    }
+
+    /// Binds and starts the server on a background task. Together with
+    /// port 0 this gives every test its own free port
+    pub async fn spawn(mut self) -> Result<ServerHandle> {
+        let listeners = self.bind().await?;
+        let local_addrs = listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr())
+            .collect::<Vec<_>>();
+        if local_addrs.is_empty() {
+            anyhow::bail!("Server don't have a TCP listener");
+        }
+        let shutdown = Arc::new(Notify::new());
+        let notified = shutdown.clone();
+        let metrics = self.metrics.clone();
+        let join = tokio::spawn(self.serve(listeners, async move { notified.notified().await }));
+        Ok(ServerHandle::new(local_addrs, metrics, shutdown, join))
+    }
+
+    async fn bind(&mut self) -> Result<Vec<BoundListener>> {
+        let profile = self.active_profile.take().unwrap_or_else(Profile::from_env);
+        let config = self
+            .profiles
+            .get(&profile)
+            .cloned()
+            .unwrap_or_default()
+            .or(&self.default_profile);
+        let _ = tracing_subscriber::fmt()
+            .with_max_level(config.get_log_level())
+            .try_init();
+        log!(Level::Info, "Using the {} profile", profile);
+        self.config.check()?;
+        self.verbose_errors = config.is_verbose_errors(&profile);
+        panic_guard::install_hook();
+        if let Some((cert, key)) = config.get_tls_certs() {
+            self.tls_certs.add(None, &cert, &key);
+        }
+
+        // Bind the named listeners first so they claim their inherited sockets
+        let mut named = vec![];
+        for listener in std::mem::take(&mut self.listeners) {
+            named.push(listener.bind().await?);
+        }
+        let mut primary = Listener::inherited();
+        if !primary.is_empty() && self.listener.is_some() {
+            log!(
+                Level::Warn,
+                "Serving on {} inherited sockets instead of the listener passed to `listener`",
+                primary.len()
+            );
+        }
+        if primary.is_empty() {
+            if let Some(listener) = self.listener.take() {
+                primary.push(Listener::from_std(listener));
+            } else {
+                let address = config.get_address().or_else(|| {
+                    self.profiles
+                        .get(&Profile::Dev)
+                        .and_then(|dev| dev.get_address())
+                });
+                primary.extend(address.map(|address| Listener::tcp((&address.0, address.1))));
+            }
+        }
+        let mut listeners = vec![];
+        for mut listener in primary {
+            listener = listener.with_cert_resolver(self.tls_certs.clone());
+            if let Some(client_auth) = self.client_auth.clone() {
+                listener = listener.client_auth(client_auth);
+            }
+            if let Some(config) = self.http2.clone() {
+                listener = listener.http2(config);
+            }
+            listeners.push(listener);
+        }
+        if listeners.is_empty() && named.is_empty() {
+            anyhow::bail!("Server don't have address");
+        }
+
+        let mut bound = Vec::with_capacity(listeners.len() + named.len());
+        for listener in listeners {
+            bound.push(listener.bind().await?);
+        }
+        bound.append(&mut named);
+        for listener in &bound {
+            log!(Level::Info, "Server started on {}", listener);
+        }
+        Ok(bound)
+    }
+
+    async fn serve<F: Future<Output = ()>>(
+        self,
+        listeners: Vec<BoundListener>,
+        shutdown: F,
+    ) -> Result<ShutdownSummary> {
+        let mut hosts = VirtualHosts::new(Self::resolve_router(self.router, &self.states));
+        for (pattern, router) in self.hosts {
+            hosts.insert(pattern, Self::resolve_router(router, &self.states));
+        }
+        let (drain_signal, drain) = Drain::new();
+        let dispatcher = Dispatcher::new(
+            hosts,
+            self.states.clone(),
+            self.session,
+            self.fallback,
+            self.on_error,
+        )
+        .debug(self.verbose_errors)
+        .timeouts(self.timeouts)
+        .limits(self.limits)
+        .load_shedding(self.load_shedding)
+        .compression(self.compression)
+        .auto_etag(self.auto_etag)
+        .metrics(self.metrics)
+        .websocket(self.websocket)
+        .drain(drain.clone());
+        // Shared by all listeners so the limit holds for the whole server
+        let connection_limit = dispatcher
+            .get_limits()
+            .get_max_connections()
+            .map(|count| Arc::new(Semaphore::new(count)));
+        let dispatcher = Arc::new(dispatcher);
+        // One watcher per set of certificates, listeners may share them
+        let mut watchers = JoinSet::new();
+        if let Some(interval) = self.watch_certs {
+            let mut resolvers: Vec<CertResolver> = Vec::new();
+            for resolver in listeners.iter().filter_map(|listener| listener.get_cert_resolver()) {
+                if !resolvers.iter().any(|known| known.shares_certs(&resolver)) {
+                    resolvers.push(resolver);
+                }
+            }
+            for resolver in resolvers {
+                watchers.spawn(resolver.watch(interval, drain.clone()));
+            }
+        }
+        let mut accept_loops = JoinSet::new();
+        for listener in listeners {
+            accept_loops.spawn(Self::accept_loop(
+                listener,
+                dispatcher.clone(),
+                drain.clone(),
+                connection_limit.clone(),
+            ));
+        }
+        shutdown.await;
+
+        log!(
+            Level::Info,
+            "Shutting down, waiting for {} connections",
+            drain.connections()
+        );
+        let started = Instant::now();
+        let open = drain.connections();
+        let _ = drain_signal.send(true);
+        while watchers.join_next().await.is_some() {}
+        let _ = tokio::time::timeout(self.shutdown_timeout, async {
+            while accept_loops.join_next().await.is_some() {}
+        })
+        .await;
+        let aborted_connections = drain.connections();
+        let aborted_requests = drain.in_flight();
+        // Dropping an accept loop aborts the connections it spawned
+        accept_loops.shutdown().await;
+        let summary = ShutdownSummary::new(
+            open.saturating_sub(aborted_connections),
+            aborted_connections,
+            aborted_requests,
+            started.elapsed(),
+        );
+
+        for hook in self.on_shutdown {
+            if let Err(e) = panic_guard::catch(hook()).await {
+                dispatcher.get_metrics().record_panic();
+                log!(Level::Error, "Shutdown hook panicked: {}", e.get_message());
+            }
+        }
+        log!(Level::Info, "Server stopped: {}", summary);
+        Ok(summary)
+    }
+
+    /// Accepts connections until the server drains, then waits for the
+    /// connections of this listener to close
+    async fn accept_loop(
+        listener: BoundListener,
+        dispatcher: Arc<Dispatcher>,
+        drain: Drain,
+        connection_limit: Option<Arc<Semaphore>>,
+    ) {
+        let mut connections = JoinSet::new();
+        let mut signal = drain.clone();
+        loop {
+            // Wait for a free slot before accepting, so clients beyond the
+            // limit queue in the listen backlog instead of holding a task
+            let slot = match &connection_limit {
+                Some(limit) => {
+                    if limit.available_permits() == 0 {
+                        dispatcher.get_metrics().record_accept_wait();
+                    }
+                    tokio::select! {
+                        _ = signal.wait() => break,
+                        permit = limit.clone().acquire_owned() => permit.ok(),
+                    }
+                }
+                None => None,
+            };
+            tokio::select! {
+                _ = signal.wait() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(connection) => {
+                        let protocol = listener.get_protocol();
+                        let dispatcher_arc = dispatcher.clone();
+                        let drain_ = drain.clone();
+                        let open = drain.connection();
+                        let active = dispatcher.get_metrics().connection();
+                        connections.spawn(async move {
+                            let _open = (open, active, slot);
+                            Self::handle_socket(connection, protocol, dispatcher_arc, drain_)
+                                .await
                         });
                     }
                     Err(e) => {
                         log!(Level::Error, "Failed to accept connection: {}", e);
                     }
-                }
+                },
+                // Reap finished connections so the set doesn't grow
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
             }
-        } else {
-            panic!("Server don't have address")
         }
+        drop(listener);
+        while connections.join_next().await.is_some() {}
     }
 
-    async fn handle_stream<T: Stream + AsyncReadExt + Unpin>(
-        mut stream: T,
-    ) -> Result<(Method, String, T, Request)> {
-        let request = StreamReader::new(&mut stream).read_req().await;
-        let tokens: Vec<&str> = request.lines().nth(0).unwrap().split_whitespace().collect();
-        if tokens.len() != 3 {
-            return Err(anyhow::Error::msg("Invalid HTTP request line"));
-        }
-
-        let method = match tokens[0] {
-            "GET" => Method::GET,
-            "POST" => Method::POST,
-            "PUT" => Method::PUT,
-            "DELETE" => Method::DELETE,
-            _ => return Err(anyhow::Error::msg("Unsupported HTTP method")),
-        };
+    fn resolve_router(router: Router, states: &Arc<RwLock<StateMap>>) -> Router {
+        let mut resolved = Router::new();
+        for mut route in router.into_routes() {
+            route.resolve_states(states);
+            resolved.insert(route.get(), route)
+        }
+        resolved
+    }
 
-        let path = tokens[1].to_string();
+    async fn handle_socket(
+        connection: Connection,
+        protocol: Arc<Protocol>,
+        dispatcher: Arc<Dispatcher>,
+        mut drain: Drain,
+    ) -> Result<()> {
+        match connection {
+            Connection::Tcp(mut stream) => match (&protocol.acceptor, &protocol.http2) {
+                (None, Some(config)) if config.is_prior_knowledge() => {
+                    // The bytes read to look for the preface are replayed to whichever protocol it is
+                    let mut prefix = Vec::new();
+                    let handshake = dispatcher.get_timeouts().get_handshake();
+                    let preface = http2::read_preface(&mut stream, &mut prefix);
+                    let is_http2 = tokio::select! {
+                        read = tokio::time::timeout(handshake, preface) => read.unwrap_or(Ok(false))?,
+                        // No request has started yet
+                        _ = drain.wait() => return Ok(()),
+                    };
+                    let stream = http2::Rewind::new(prefix, stream);
+                    if is_http2 {
+                        http2::serve(stream, dispatcher, config, None, drain).await
+                    } else {
+                        Self::handle_io(stream, &protocol, dispatcher, drain).await
+                    }
+                }
+                _ => Self::handle_io(stream, &protocol, dispatcher, drain).await,
+            },
+            #[cfg(unix)]
+            Connection::Unix(stream) => Self::handle_io(stream, &protocol, dispatcher, drain).await,
+        }
+    }
 
-        let mut headers = DisplayableVec(vec![]);
-        let mut i = 1;
-        let mut is_header = true;
-        let mut body = String::new();
-        while let Some(line) = request.lines().nth(i) {
-            if line.is_empty() {
-                is_header = false
-            }
-            if is_header {
-                headers.0.push(line.to_string());
-            } else {
-                body.push_str(line.trim())
+    async fn handle_io<T: Stream + AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        stream: T,
+        protocol: &Protocol,
+        dispatcher: Arc<Dispatcher>,
+        drain: Drain,
+    ) -> Result<()> {
+        let Some(acceptor) = &protocol.acceptor else {
+            return Self::handle_connection(stream, dispatcher, drain).await;
+        };
+        let handshake = dispatcher.get_timeouts().get_handshake();
+        let Ok(Ok(stream)) = tokio::time::timeout(handshake, acceptor.accept(stream)).await
+        else {
+            return Ok(());
+        };
+        match &protocol.http2 {
+            Some(config) if stream.get_ref().1.alpn_protocol() == Some(b"h2") => {
+                let peer_certificate = stream.peer_certificate();
+                http2::serve(stream, dispatcher, config, peer_certificate, drain).await
             }
-            i += 1;
+            _ => Self::handle_connection(stream, dispatcher, drain).await,
         }
+    }
 
-        let mut cookies = CookieJar::new();
+    async fn handle_connection<T: Stream + AsyncReadExt + AsyncWriteExt + Send + Unpin + 'static>(
+        mut stream: T,
+        dispatcher: Arc<Dispatcher>,
+        mut drain: Drain,
+    ) -> Result<()> {
+        let peer_certificate = stream.peer_certificate();
+        let mut reader = RequestReader::new(dispatcher.get_timeouts(), dispatcher.get_limits());
+        // Keep-alive: serve requests until the client closes the connection,
+        // asks for `Connection: close` or the server starts draining
+        while !drain.is_draining() {
+            let started = tokio::select! {
+                started = reader.wait_request(&mut stream) => started,
+                // Idle connections are closed right away
+                _ = drain.wait() => break,
+            };
+            if !started {
+                break;
+            }
+            // A request that has started is read and answered in full
+            let _in_flight = drain.request();
+            let read = Self::handle_stream(&mut reader, &mut stream, &dispatcher).await;
+            let (mut resp, keep_alive, http11) = match read {
+                Ok(None) => break,
+                Ok(Some(Incoming::Request(mut req, http11))) => {
+                    let keep_alive = Self::keep_alive(&req, http11);
+                    req.set_peer_certificate(peer_certificate.clone());
+                    (dispatcher.dispatch(*req).await, keep_alive, http11)
+                }
+                // The body may still be on its way, so it can't be skipped reliably
+                Ok(Some(Incoming::Rejected(resp))) => (resp, false, true),
+                Err(e) => {
+                    let err = e.downcast::<HttpError>().unwrap_or_else(|e| {
+                        HttpError::new(ErrorKind::Parse, StatusCode::BadRequest, &e.to_string())
+                    });
+                    (dispatcher.error(err).await, false, true)
+                }
+            };
+            let upgrade = resp.take_upgrade();
+            let body = resp.take_stream();
+            // Streams of a known size, e.g. files, are sent as they are
+            let length = resp
+                .get_header("Content-Length")
+                .and_then(|value| value.trim().parse::<u64>().ok());
+            let unsized_body = body.is_some() && length.is_none();
+            // HTTP/1.0 has no chunked coding, the end of such a body is the end of the connection
+            let keep_alive = keep_alive && !drain.is_draining() && (http11 || !unsized_body);
+            if !keep_alive && upgrade.is_none() {
+                resp.set_header("Connection", "close");
+            } else if keep_alive && !http11 {
+                resp.set_header("Connection", "keep-alive");
+            }
+            if unsized_body && http11 {
+                resp.set_header("Transfer-Encoding", "chunked");
+            }
+            let write = dispatcher.get_timeouts().get_write();
+            let written = tokio::time::timeout(write, async {
+                stream.write_all(resp.get_head().as_bytes()).await?;
+                stream.write_all(&resp.get_body_bytes()).await?;
+                stream.flush().await
+            })
+            .await;
+            match written {
+                Ok(written) => written?,
+                // The client stopped reading, drop the connection
+                Err(_) => return Ok(()),
+            }
+            if let Some(body) = body {
+                let chunked = http11 && length.is_none();
+                if !Self::write_stream(&mut stream, body, length, chunked, write).await? {
+                    return Ok(());
+                }
+            }
+            if let Some(on_upgrade) = upgrade {
+                let buffered = reader.take_buffered();
+                on_upgrade(Upgraded::new(stream, buffered, drain)).await;
+                return Ok(());
+            }
+            if !keep_alive {
+                break;
+            }
+        }
+        let write = dispatcher.get_timeouts().get_write();
+        let _ = tokio::time::timeout(write, stream.shutdown()).await;
+        Ok(())
+    }
 
-        for header in &headers.0 {
-            if header.starts_with("Cookie: ") {
-                for cookie in header[8..].split(";") {
-                    let eq_pos = cookie.find("=").unwrap();
-                    cookies.push_cookie(
-                        &cookie[..eq_pos],
-                        CookieReq::new(cookie[eq_pos + 1..].to_string()),
-                    )
+    /// Writes a streamed body, flushing every chunk. Without a `length` it is sent
+    /// with chunked encoding, or as it is for HTTP/1.0 clients. `false` when the
+    /// client stopped reading or the body didn't match `length`
+    async fn write_stream<T: AsyncWrite + Unpin>(
+        stream: &mut T,
+        mut body: BodyStream,
+        length: Option<u64>,
+        chunked: bool,
+        write: Duration,
+    ) -> Result<bool> {
+        let mut sent = 0;
+        loop {
+            let chunk = match body.next().await {
+                Some(chunk) => chunk?,
+                None => break,
+            };
+            // An empty chunk would end the body
+            if chunk.is_empty() {
+                continue;
+            }
+            sent += chunk.len() as u64;
+            if length.is_some_and(|length| sent > length) {
+                return Ok(false);
+            }
+            let written = tokio::time::timeout(write, async {
+                if !chunked {
+                    stream.write_all(&chunk).await?;
+                } else {
+                    stream
+                        .write_all(format!("{:X}\r\n", chunk.len()).as_bytes())
+                        .await?;
+                    stream.write_all(&chunk).await?;
+                    stream.write_all(b"\r\n").await?;
                 }
+                stream.flush().await
+            })
+            .await;
+            match written {
+                Ok(written) => written?,
+                Err(_) => return Ok(false),
             }
         }
+        if let Some(length) = length {
+            // A short body leaves the client waiting for the rest, only closing ends it
+            return Ok(sent == length);
+        }
+        if !chunked {
+            return Ok(true);
+        }
+        let written = tokio::time::timeout(write, async {
+            stream.write_all(b"0\r\n\r\n").await?;
+            stream.flush().await
+        })
+        .await;
+        match written {
+            Ok(written) => written?,
+            Err(_) => return Ok(false),
+        }
+        Ok(true)
+    }
 
-        log!(
-            Level::Info,
-            "Request Method: {}, Path: {}, Headers: {}, Body: {}",
+    /// Reads the next request, `None` when the client closed the connection
+    async fn handle_stream<T: Stream + AsyncReadExt + AsyncWriteExt + Unpin>(
+        reader: &mut RequestReader<'_>,
+        stream: &mut T,
+        dispatcher: &Dispatcher,
+    ) -> Result<Option<Incoming>> {
+        let Some(head) = reader.read_head(stream).await? else {
+            return Ok(None);
+        };
+        let method = head.get_method().parse::<Method>()?;
+        let content_length = head.get_content_length()?;
+        let http11 = head.get_version() == "HTTP/1.1";
+        // HTTP/1.0 clients don't wait for `100 Continue`, RFC 9110 section 10.1.1
+        let expects_continue = match head.get_header("Expect") {
+            Some(expect) if expect.eq_ignore_ascii_case("100-continue") => http11,
+            Some(_) => {
+                return Err(HttpError::new(
+                    ErrorKind::Parse,
+                    StatusCode::ExpectationFailed,
+                    "Only the 100-continue expectation is supported",
+                )
+                .into())
+            }
+            None => false,
+        };
+        let headers = log_enabled!(Level::Info).then(|| {
+            DisplayableVec(
+                head.get_headers()
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, value))
+                    .collect(),
+            )
+        });
+        let encodings = match head.get_header("Content-Encoding") {
+            Some(header) => compression::parse_content_encoding(header)?,
+            None => Vec::new(),
+        };
+        // Routing happens before the body is read, so the route's limit applies
+        let mut req = RequestBuilder::from_parts(
             method,
-            path,
-            headers,
-            body
-        );
+            head.get_target(),
+            head.get_headers(),
+        )?
+        .build();
+        if content_length > dispatcher.body_limit(&req) {
+            return Err(HttpError::new(
+                ErrorKind::Limit,
+                StatusCode::PayloadTooLarge,
+                "Request body is too large",
+            )
+            .into());
+        }
+        if expects_continue && content_length > 0 {
+            req = match dispatcher.preflight(req).await {
+                Ok(req) => req,
+                Err(resp) => return Ok(Some(Incoming::Rejected(resp))),
+            };
+            let write = dispatcher.get_timeouts().get_write();
+            let written = tokio::time::timeout(write, async {
+                stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+                stream.flush().await
+            })
+            .await;
+            if !matches!(written, Ok(Ok(()))) {
+                return Ok(None);
+            }
+        }
+        let mut body = Bytes::new();
+        if content_length > 0 {
+            body = reader.read_body(stream, content_length).await?;
+            if !encodings.is_empty() {
+                let max_size = dispatcher.decompressed_limit(&req);
+                body = compression::decompress_request(&mut req, body, encodings, max_size).await?;
+            }
+        }
+        if let Some(headers) = headers {
+            log!(
+                Level::Info,
+                "Request Method: {}, Path: {}, Headers: {}, Body: {}",
+                req.get_method(),
+                req.get_path(),
+                headers,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        req.set_body(body);
+        Ok(Some(Incoming::Request(Box::new(req), http11)))
+    }
 
-        Ok((
-            method.clone(),
-            path,
-            stream,
-            RequestBuilder::new(method, serde_json::to_value(body).unwrap())
-                .set_cookie_jar(cookies)
-                .build(),
-        ))
+    /// HTTP/1.1 connections stay open unless the client sends `Connection: close`,
+    /// HTTP/1.0 ones only when it sends `Connection: keep-alive`
+    fn keep_alive(req: &Request, http11: bool) -> bool {
+        let connection = req.get_header("Connection").unwrap_or_default();
+        let has_token = |token: &str| {
+            connection
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        };
+        if http11 {
+            !has_token("close")
+        } else {
+            has_token("keep-alive")
+        }
     }
+}
+
+/// Request read from a connection, or the answer sent in place of
+/// `100 Continue` when the request was refused before its body was read
+enum Incoming {
+    /// `true` for HTTP/1.1, HTTP/1.0 clients don't get chunked bodies
+    Request(Box<Request>, bool),
+    Rejected(Response),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::response::ResponseBuilder;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+
+    type Handler = Pin<Box<dyn Future<Output = Response> + Send + Sync>>;
+
+    fn hello(_: Request) -> Handler {
+        Box::pin(async { ResponseBuilder::raw(StatusCode::Ok, "text/plain", "hello").build() })
+    }
+
+    fn chunks(_: Request) -> Handler {
+        let chunks = ["one", "two"].map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())));
+        Box::pin(async move {
+            ResponseBuilder::stream(StatusCode::Ok, "text/plain", futures_util::stream::iter(chunks))
+                .build()
+        })
+    }
+
+    fn slow(_: Request) -> Handler {
+        Box::pin(async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            ResponseBuilder::raw(StatusCode::Ok, "text/plain", "done").build()
+        })
+    }
+
+    async fn spawn(server: NuttServer) -> ServerHandle {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        server.listener(listener).spawn().await.unwrap()
+    }
+
+    /// Sends `request` and reads until the server closes the connection
+    async fn exchange(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("the server kept the connection open")
+            .unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn closes_http10_connections_by_default() {
+        let server = spawn(NuttServer::new().routes(vec![Route::new(Method::GET, "/", hello)])).await;
+        let response = exchange(server.local_addr(), "GET / HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("hello"));
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn keeps_http10_connections_open_when_asked() {
+        let server = spawn(NuttServer::new().routes(vec![Route::new(Method::GET, "/", hello)])).await;
+        let response = exchange(
+            server.local_addr(),
+            "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n",
+        )
+        .await;
+        let (first, second) = response.split_once("hello").unwrap();
+        assert!(first.contains("Connection: keep-alive\r\n"));
+        assert!(second.contains("Connection: close\r\n"));
+        assert!(second.ends_with("hello"));
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn streams_http10_bodies_until_close() {
+        let server =
+            spawn(NuttServer::new().routes(vec![Route::new(Method::GET, "/", chunks)])).await;
+        let response = exchange(
+            server.local_addr(),
+            "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+        )
+        .await;
+        assert!(!response.contains("Transfer-Encoding"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\nonetwo"));
+
+        let response = exchange(
+            server.local_addr(),
+            "GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(response.ends_with("3\r\none\r\n3\r\ntwo\r\n0\r\n\r\n"));
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn finishes_requests_in_flight_when_draining() {
+        let server = spawn(NuttServer::new().routes(vec![Route::new(Method::GET, "/", slow)])).await;
+        let addr = server.local_addr();
+        let request = tokio::spawn(exchange(addr, "GET / HTTP/1.1\r\n\r\n"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        server.shutdown();
 
-    fn load_certs(filename: &Path) -> Vec<CertificateDer<'static>> {
-        CertificateDer::pem_file_iter(filename)
-            .expect("cannot open certificate file")
-            .map(|result| result.unwrap())
-            .collect()
+        // The keep-alive connection is closed once the response is out
+        let response = request.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("done"));
+        let summary = server.join().await.unwrap();
+        assert_eq!(summary.get_drained_connections(), 1);
+        assert_eq!(summary.get_aborted_connections(), 0);
+        assert_eq!(summary.get_aborted_requests(), 0);
+        assert!(TcpStream::connect(addr).await.is_err());
     }
 
-    fn load_private_key(filename: &Path) -> PrivateKeyDer<'static> {
-        PrivateKeyDer::from_pem_file(filename).expect("cannot read private key file")
+    #[tokio::test]
+    async fn aborts_requests_outliving_the_shutdown_timeout() {
+        let server = NuttServer::new()
+            .routes(vec![Route::new(Method::GET, "/", slow)])
+            .shutdown_timeout(Duration::from_millis(100));
+        let server = spawn(server).await;
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        server.shutdown();
+
+        let summary = server.join().await.unwrap();
+        assert_eq!(summary.get_drained_connections(), 0);
+        assert_eq!(summary.get_aborted_connections(), 1);
+        assert_eq!(summary.get_aborted_requests(), 1);
+        // Closed without an answer, possibly with a reset
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.is_empty());
     }
 }
@@ -0,0 +1,589 @@
+use crate::http::error::{ErrorKind, HttpError};
+use crate::http::request::Request;
+use crate::http::response::{BodyStream, Response};
+use crate::http::status::StatusCode;
+use crate::modules::conditional::ETag;
+use bytes::Bytes;
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use futures_util::StreamExt;
+use std::io::{self, Read, Write};
+
+/// Bodies below this size gain too little to be worth compressing
+const MIN_SIZE: usize = 1024;
+
+/// Brotli quality, fast enough for responses compressed on the fly
+const BROTLI_QUALITY: u32 = 5;
+
+const BROTLI_WINDOW: u32 = 22;
+
+/// Content types that are compressed already
+const COMPRESSED_TYPES: [&str; 11] = [
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/pdf",
+    "font/woff",
+    "font/woff2",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// Response compression negotiated from `Accept-Encoding`, set with `NuttServer::compression`
+#[derive(Debug, Clone)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            min_size: MIN_SIZE,
+        }
+    }
+
+    /// Codings offered, the first one wins when the client weighs them equally
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Smaller buffered bodies are sent as they are. Streamed bodies are always compressed
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    pub fn get_min_size(&self) -> usize {
+        self.min_size
+    }
+
+    /// Picks the coding with the highest q-value in `Accept-Encoding`, `None`
+    /// when the client accepts none of them
+    pub fn negotiate(&self, accept_encoding: &str) -> Option<Encoding> {
+        let mut weights = Vec::new();
+        let mut wildcard = None;
+        for entry in accept_encoding.split(',') {
+            let mut params = entry.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let mut q = Some(1.0);
+            for param in params {
+                if let Some(value) = param.strip_prefix("q=").or(param.strip_prefix("Q=")) {
+                    q = value.trim().parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q));
+                }
+            }
+            // Entries with an invalid weight are ignored
+            let Some(q) = q else {
+                continue;
+            };
+            if name == "*" {
+                wildcard = Some(q);
+            } else if let Some(encoding) = Encoding::parse(name) {
+                weights.push((encoding, q));
+            }
+        }
+        let mut best: Option<(Encoding, f32)> = None;
+        for &encoding in &self.encodings {
+            let q = weights
+                .iter()
+                .find(|(listed, _)| *listed == encoding)
+                .map(|(_, q)| *q)
+                .or(wildcard)
+                .unwrap_or(0.0);
+            if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    /// Compresses the body of `resp` when it is worth it and the client accepts a coding
+    pub(crate) fn apply(&self, mut resp: Response, accept_encoding: Option<&str>) -> Response {
+        if !is_compressible(&resp) {
+            return resp;
+        }
+        // The body depends on the header whether it ends up compressed or not
+        let vary = match resp.get_header("Vary") {
+            Some(vary) if vary.split(',').any(|name| {
+                let name = name.trim();
+                name == "*" || name.eq_ignore_ascii_case("Accept-Encoding")
+            }) => vary,
+            Some(vary) => format!("{}, Accept-Encoding", vary),
+            None => "Accept-Encoding".to_string(),
+        };
+        resp.remove_header("Vary");
+        resp.set_header("Vary", &vary);
+
+        if !resp.is_streaming() && resp.get_body_bytes().len() < self.min_size {
+            return resp;
+        }
+        let Some(encoding) = accept_encoding.and_then(|header| self.negotiate(header)) else {
+            return resp;
+        };
+        if let Some(body) = resp.take_stream() {
+            resp.remove_header("Content-Length");
+            // Without a length the compressed stream can't be cut into ranges
+            resp.remove_header("Accept-Ranges");
+            resp.set_stream(compress_stream(body, encoding));
+        } else {
+            let body = resp.get_body_bytes();
+            let Ok(compressed) = compress(encoding, &body) else {
+                return resp;
+            };
+            if compressed.len() >= body.len() {
+                return resp;
+            }
+            resp.set_body(compressed);
+        }
+        resp.set_header("Content-Encoding", encoding.as_str());
+        // The compressed bytes are another representation, with a tag of their own
+        if let Some(etag) = resp.get_header("ETag").and_then(|etag| ETag::parse(&etag)) {
+            resp.remove_header("ETag");
+            resp.set_header("ETag", &etag.with_coding(encoding.as_str()).to_string());
+        }
+        resp
+    }
+}
+
+/// Codings of a request body in the order they were applied. Unknown ones are answered with 415
+pub(crate) fn parse_content_encoding(header: &str) -> Result<Vec<Encoding>, HttpError> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty() && !name.eq_ignore_ascii_case("identity"))
+        .map(|name| {
+            Encoding::parse(name).ok_or_else(|| {
+                HttpError::new(
+                    ErrorKind::Parse,
+                    StatusCode::UnsupportedMediaType,
+                    &format!("Unsupported content encoding `{}`", name),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Undoes the codings of a request body. Answered with 413 as soon as it grows
+/// past `max_size`, so a small compressed body can't expand without bound
+pub(crate) fn decompress_body(
+    mut body: Bytes,
+    encodings: &[Encoding],
+    max_size: usize,
+) -> Result<Bytes, HttpError> {
+    for &encoding in encodings.iter().rev() {
+        let data: &[u8] = &body;
+        let decoder: Box<dyn Read> = match encoding {
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+            Encoding::Gzip => Box::new(MultiGzDecoder::new(data)),
+            Encoding::Deflate if is_zlib(data) => Box::new(ZlibDecoder::new(data)),
+            // Some clients send raw deflate data without the zlib wrapper
+            Encoding::Deflate => Box::new(DeflateDecoder::new(data)),
+        };
+        let mut decoded = Vec::new();
+        decoder
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|_| {
+                HttpError::new(
+                    ErrorKind::Parse,
+                    StatusCode::BadRequest,
+                    "Request body can't be decompressed",
+                )
+            })?;
+        if decoded.len() > max_size {
+            return Err(HttpError::new(
+                ErrorKind::Limit,
+                StatusCode::PayloadTooLarge,
+                "Decompressed request body is too large",
+            ));
+        }
+        body = Bytes::from(decoded);
+    }
+    Ok(body)
+}
+
+/// Decodes the body of `req` on the blocking pool, so a large one doesn't hold
+/// up the other connections of the worker. The headers describing the coded
+/// body are removed once it is decoded
+pub(crate) async fn decompress_request(
+    req: &mut Request,
+    body: Bytes,
+    encodings: Vec<Encoding>,
+    max_size: usize,
+) -> Result<Bytes, HttpError> {
+    let decoded = tokio::task::spawn_blocking(move || decompress_body(body, &encodings, max_size))
+        .await
+        .map_err(|_| {
+            HttpError::new(
+                ErrorKind::Parse,
+                StatusCode::BadRequest,
+                "Request body can't be decompressed",
+            )
+        })??;
+    req.remove_header("Content-Encoding");
+    req.remove_header("Content-Length");
+    Ok(decoded)
+}
+
+/// zlib header, RFC 1950 section 2.2
+fn is_zlib(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] & 0x0F == 8 && u16::from_be_bytes([data[0], data[1]]).is_multiple_of(31)
+}
+
+fn is_compressible(resp: &Response) -> bool {
+    let status = resp.get_status();
+    if status.as_u16() < 200
+        || matches!(
+            status,
+            StatusCode::NoContent | StatusCode::NotModified | StatusCode::PartialContent
+        )
+    {
+        return false;
+    }
+    if resp.get_header("Content-Encoding").is_some() {
+        return false;
+    }
+    if resp.get_header("Cache-Control").is_some_and(|value| {
+        value
+            .split(',')
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+    }) {
+        return false;
+    }
+    is_compressible_type(&resp.get_header("Content-Type").unwrap_or_default())
+}
+
+/// Whether a body of `content_type` may be compressed, not the case for
+/// formats that are compressed already
+pub(crate) fn is_compressible_type(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    // Proxies and browsers buffer compressed event streams, holding events back
+    if media_type == "text/event-stream" {
+        return false;
+    }
+    let compressed = (media_type.starts_with("image/") && media_type != "image/svg+xml")
+        || media_type.starts_with("audio/")
+        || media_type.starts_with("video/")
+        || COMPRESSED_TYPES.contains(&media_type);
+    !compressed
+}
+
+fn compress(encoding: Encoding, data: &[u8]) -> io::Result<Bytes> {
+    let mut encoder = Encoder::new(encoding);
+    encoder.write(data)?;
+    encoder.finish()
+}
+
+/// Compresses chunk by chunk, flushing each one so it can be decoded as soon
+/// as it arrives. The coding is finished when the body ends
+fn compress_stream(body: BodyStream, encoding: Encoding) -> BodyStream {
+    let stream = futures_util::stream::unfold(
+        Some((body, Encoder::new(encoding))),
+        |state| async move {
+            let (mut body, mut encoder) = state?;
+            loop {
+                let compressed = match body.next().await {
+                    Some(Ok(chunk)) if chunk.is_empty() => continue,
+                    Some(Ok(chunk)) => encoder.write(&chunk).and_then(|_| encoder.flush()),
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None => return Some((encoder.finish(), None)),
+                };
+                return match compressed {
+                    Ok(compressed) => Some((Ok(compressed), Some((body, encoder)))),
+                    Err(e) => Some((Err(e), None)),
+                };
+            }
+        },
+    );
+    Box::pin(stream)
+}
+
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        let level = flate2::Compression::default();
+        match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), level)),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), level)),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Brotli(encoder) => encoder.write_all(data),
+            Encoder::Gzip(encoder) => encoder.write_all(data),
+            Encoder::Deflate(encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Output so far, including everything written
+    fn flush(&mut self) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Brotli(encoder) => {
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(out)))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Brotli(encoder) => encoder.into_inner(),
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Deflate(encoder) => encoder.finish()?,
+        };
+        Ok(Bytes::from(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::method::Method;
+    use crate::http::request::RequestBuilder;
+    use crate::http::response::ResponseBuilder;
+
+    fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        Compression::new().negotiate(accept_encoding)
+    }
+
+    fn text(len: usize) -> Response {
+        ResponseBuilder::raw(StatusCode::Ok, "text/plain", &"a".repeat(len)).build()
+    }
+
+    #[test]
+    fn prefers_the_server_order_on_equal_weights() {
+        assert_eq!(negotiate("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn follows_q_values() {
+        assert_eq!(negotiate("br;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0.5, gzip;Q=0.8, deflate;q=0.9"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip;q=0, *;q=0.1"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*;q=0.5, deflate"), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn refuses_codings_not_accepted() {
+        assert_eq!(negotiate(""), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("zstd, compress"), None);
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate("*;q=0"), None);
+        // An invalid weight drops the entry
+        assert_eq!(negotiate("gzip;q=2"), None);
+        assert_eq!(negotiate("gzip;q=abc"), None);
+    }
+
+    #[test]
+    fn only_offers_configured_codings() {
+        let gzip_only = Compression::new().encodings(&[Encoding::Gzip]);
+        assert_eq!(gzip_only.negotiate("br, gzip;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(gzip_only.negotiate("br"), None);
+    }
+
+    #[test]
+    fn compresses_large_bodies_and_tags_the_coding() {
+        let mut resp = text(4096);
+        resp.set_header("ETag", "\"v1\"");
+        let resp = Compression::new().apply(resp, Some("gzip"));
+        assert_eq!(resp.get_header("Content-Encoding").as_deref(), Some("gzip"));
+        assert_eq!(resp.get_header("Vary").as_deref(), Some("Accept-Encoding"));
+        assert_eq!(resp.get_header("ETag").as_deref(), Some("\"v1-gzip\""));
+        let decoded = decompress_body(resp.get_body_bytes(), &[Encoding::Gzip], 1 << 20).unwrap();
+        assert_eq!(decoded.len(), 4096);
+    }
+
+    #[test]
+    fn leaves_small_and_unaccepted_bodies_alone() {
+        let resp = Compression::new().apply(text(10), Some("gzip"));
+        assert!(resp.get_header("Content-Encoding").is_none());
+        // The body still depends on the header
+        assert_eq!(resp.get_header("Vary").as_deref(), Some("Accept-Encoding"));
+
+        let resp = Compression::new().apply(text(4096), None);
+        assert!(resp.get_header("Content-Encoding").is_none());
+        assert_eq!(resp.get_body_bytes().len(), 4096);
+    }
+
+    #[test]
+    fn skips_responses_that_must_not_change() {
+        let png = ResponseBuilder::raw(StatusCode::Ok, "image/png", &"a".repeat(4096)).build();
+        assert!(Compression::new().apply(png, Some("gzip")).get_header("Content-Encoding").is_none());
+
+        let mut no_transform = text(4096);
+        no_transform.set_header("Cache-Control", "public, no-transform");
+        let resp = Compression::new().apply(no_transform, Some("gzip"));
+        assert!(resp.get_header("Content-Encoding").is_none());
+
+        let events = ResponseBuilder::raw(StatusCode::Ok, "text/event-stream", &"a".repeat(4096));
+        let resp = Compression::new().apply(events.build(), Some("gzip"));
+        assert!(resp.get_header("Content-Encoding").is_none());
+
+        let mut encoded = text(4096);
+        encoded.set_header("Content-Encoding", "br");
+        let resp = Compression::new().apply(encoded, Some("gzip"));
+        assert_eq!(resp.get_header("Content-Encoding").as_deref(), Some("br"));
+    }
+
+    #[test]
+    fn extends_an_existing_vary() {
+        let mut resp = text(10);
+        resp.set_header("Vary", "Origin");
+        let resp = Compression::new().apply(resp, Some("gzip"));
+        assert_eq!(resp.get_header("Vary").as_deref(), Some("Origin, Accept-Encoding"));
+    }
+
+    fn decompress(body: Bytes, encodings: &[Encoding], max_size: usize) -> Result<Bytes, StatusCode> {
+        decompress_body(body, encodings, max_size).map_err(|e| e.get_status())
+    }
+
+    #[test]
+    fn round_trips_every_coding() {
+        let data = "hello world ".repeat(100);
+        for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+            let compressed = compress(encoding, data.as_bytes()).unwrap();
+            assert_ne!(&compressed[..], data.as_bytes());
+            let decoded = decompress(compressed, &[encoding], data.len()).unwrap();
+            assert_eq!(&decoded[..], data.as_bytes(), "{}", encoding.as_str());
+        }
+    }
+
+    #[tokio::test]
+    async fn describes_the_decoded_request() {
+        let compressed = compress(Encoding::Gzip, b"decoded").unwrap();
+        let length = compressed.len().to_string();
+        let mut req = RequestBuilder::new(Method::POST, "")
+            .set_header("Content-Encoding", "gzip")
+            .set_header("Content-Length", &length)
+            .build();
+        let body = decompress_request(&mut req, compressed, vec![Encoding::Gzip], 1024).await;
+        assert_eq!(&body.unwrap()[..], b"decoded");
+        assert!(req.get_header("Content-Encoding").is_none());
+        assert!(req.get_header("Content-Length").is_none());
+    }
+
+    #[test]
+    fn accepts_raw_deflate() {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"raw deflate body").unwrap();
+        let compressed = Bytes::from(encoder.finish().unwrap());
+        let decoded = decompress(compressed, &[Encoding::Deflate], 1024).unwrap();
+        assert_eq!(&decoded[..], b"raw deflate body");
+    }
+
+    #[test]
+    fn undoes_stacked_codings_in_reverse() {
+        let gzipped = compress(Encoding::Gzip, b"stacked").unwrap();
+        let both = compress(Encoding::Brotli, &gzipped).unwrap();
+        let decoded = decompress(both, &[Encoding::Gzip, Encoding::Brotli], 1024).unwrap();
+        assert_eq!(&decoded[..], b"stacked");
+    }
+
+    #[test]
+    fn caps_the_decompressed_size() {
+        // A few kilobytes that expand to a megabyte
+        let bomb = compress(Encoding::Gzip, &vec![0; 1 << 20]).unwrap();
+        assert!(bomb.len() < 8 * 1024);
+        assert_eq!(
+            decompress(bomb.clone(), &[Encoding::Gzip], 1000),
+            Err(StatusCode::PayloadTooLarge)
+        );
+        assert_eq!(decompress(bomb, &[Encoding::Gzip], 1 << 20).unwrap().len(), 1 << 20);
+
+        let exact = compress(Encoding::Brotli, &[7; 100]).unwrap();
+        assert!(decompress(exact.clone(), &[Encoding::Brotli], 100).is_ok());
+        assert_eq!(
+            decompress(exact, &[Encoding::Brotli], 99),
+            Err(StatusCode::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn refuses_corrupt_bodies() {
+        let garbage = Bytes::from_static(b"definitely not compressed");
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            assert_eq!(
+                decompress(garbage.clone(), &[encoding], 1024),
+                Err(StatusCode::BadRequest)
+            );
+        }
+        let mut truncated = compress(Encoding::Gzip, &[1; 4096]).unwrap();
+        truncated.truncate(truncated.len() / 2);
+        assert_eq!(
+            decompress(truncated, &[Encoding::Gzip], 1 << 20),
+            Err(StatusCode::BadRequest)
+        );
+    }
+
+    #[test]
+    fn parses_content_encoding() {
+        let encodings = parse_content_encoding("gzip, identity, BR").unwrap();
+        assert_eq!(encodings, vec![Encoding::Gzip, Encoding::Brotli]);
+        assert!(parse_content_encoding("identity").unwrap().is_empty());
+        let err = parse_content_encoding("gzip, zstd").unwrap_err();
+        assert_eq!(err.get_status(), StatusCode::UnsupportedMediaType);
+    }
+}
@@ -0,0 +1,697 @@
+use crate::http::request::Request;
+use crate::http::response::{Response, ResponseBuilder};
+use crate::http::status::StatusCode;
+use chrono::{DateTime, Utc};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::OwnedMutexGuard;
+
+/// Content codings the compression adds to a tag, see `ETag::with_coding`
+const CODINGS: [&str; 3] = ["br", "gzip", "deflate"];
+
+/// Resources whose validators are remembered, past this they are all forgotten
+const MAX_REMEMBERED: usize = 4096;
+
+/// Headers a `304 Not Modified` keeps from the response it stands for, RFC 9110 section 15.4.5
+const NOT_MODIFIED_HEADERS: [&str; 7] = [
+    "Cache-Control",
+    "Content-Location",
+    "Date",
+    "ETag",
+    "Expires",
+    "Last-Modified",
+    "Vary",
+];
+
+/// Entity tag identifying one version of a representation. Strong tags change
+/// with every byte of the body, weak ones only when its meaning does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ETag {
+    tag: String,
+    weak: bool,
+}
+
+impl ETag {
+    pub fn strong(tag: &str) -> Self {
+        Self {
+            tag: tag.replace('"', ""),
+            weak: false,
+        }
+    }
+
+    pub fn weak(tag: &str) -> Self {
+        Self {
+            tag: tag.replace('"', ""),
+            weak: true,
+        }
+    }
+
+    /// Strong tag hashed from a body
+    pub fn from_body(body: &[u8]) -> Self {
+        let hash = Sha1::digest(body);
+        let tag = hash[..16].iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        Self::strong(&tag)
+    }
+
+    /// Tag of an `ETag` header value, e.g. `W/"v2"`
+    pub fn parse(value: &str) -> Option<Self> {
+        match parse_list(value)?.as_slice() {
+            [etag] => Some(etag.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn get_tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// Same tag with the weak flag set
+    pub fn to_weak(&self) -> Self {
+        Self::weak(&self.tag)
+    }
+
+    /// Tag of the representation sent with a content coding, e.g. `"v2-gzip"`.
+    /// It stays strong, the encoded bytes are as stable as the original ones
+    pub fn with_coding(&self, coding: &str) -> Self {
+        Self {
+            tag: format!("{}-{}", self.tag, coding),
+            weak: self.weak,
+        }
+    }
+
+    /// Tag without the suffix of `with_coding`, `None` when it has none
+    pub fn without_coding(&self) -> Option<Self> {
+        CODINGS.iter().find_map(|coding| {
+            let tag = self.tag.strip_suffix(coding)?.strip_suffix('-')?;
+            Some(Self {
+                tag: tag.to_string(),
+                weak: self.weak,
+            })
+        })
+    }
+
+    /// Equal and both strong, used by `If-Match` and `If-Range`
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Equal ignoring the weak flags, used by `If-None-Match`
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl Display for ETag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.weak {
+            write!(f, "W/\"{}\"", self.tag)
+        } else {
+            write!(f, "\"{}\"", self.tag)
+        }
+    }
+}
+
+/// Entity tags of a header such as `If-None-Match: "a", W/"b"`, `None` when malformed
+fn parse_list(value: &str) -> Option<Vec<ETag>> {
+    let mut etags = Vec::new();
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let (weak, tagged) = match rest.strip_prefix("W/") {
+            Some(tagged) => (true, tagged),
+            None => (false, rest),
+        };
+        let (tag, after) = tagged.strip_prefix('"')?.split_once('"')?;
+        etags.push(ETag {
+            tag: tag.to_string(),
+            weak,
+        });
+        rest = after.trim_start();
+        rest = match rest.strip_prefix(',') {
+            Some(after) => after.trim_start(),
+            None if rest.is_empty() => rest,
+            None => return None,
+        };
+    }
+    Some(etags)
+}
+
+/// `true` when the header is `*` or lists a tag matching `current`
+fn matches(header: &str, current: Option<&ETag>, eq: impl Fn(&ETag, &ETag) -> bool) -> bool {
+    if header.trim() == "*" {
+        return true;
+    }
+    let Some(current) = current else {
+        return false;
+    };
+    parse_list(header).is_some_and(|etags| etags.iter().any(|etag| eq(etag, current)))
+}
+
+/// IMF-fixdate used by `Last-Modified` and the other date headers
+pub fn format_http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Date of a header, `None` when it isn't a valid HTTP date
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    let secs = u64::try_from(date.timestamp()).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Dates are compared in whole seconds, the precision of the headers
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+/// Validators of the current representation of a resource, returned by a
+/// route validator so the preconditions of a write can be checked before it runs
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    etag: Option<ETag>,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn etag(mut self, etag: ETag) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    pub fn last_modified(mut self, time: SystemTime) -> Self {
+        self.last_modified = Some(time);
+        self
+    }
+
+    pub fn get_etag(&self) -> Option<&ETag> {
+        self.etag.as_ref()
+    }
+
+    pub fn get_last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
+    }
+
+    /// Validators of a successful response, `None` for other statuses
+    pub(crate) fn from_response(resp: &Response) -> Option<Self> {
+        if !(200..300).contains(&resp.get_status().as_u16()) {
+            return None;
+        }
+        Some(Self {
+            etag: resp.get_header("ETag").and_then(|etag| ETag::parse(&etag)),
+            last_modified: resp
+                .get_header("Last-Modified")
+                .and_then(|date| parse_http_date(&date)),
+        })
+    }
+}
+
+/// Precondition headers of a request, evaluated in the order of RFC 9110 section 13.2.2
+#[derive(Debug)]
+pub(crate) struct Preconditions {
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    if_unmodified_since: Option<String>,
+}
+
+impl Preconditions {
+    /// `None` when the request has no precondition
+    pub fn from_request(req: &Request) -> Option<Self> {
+        let preconditions = Self {
+            if_match: req.get_header("If-Match"),
+            if_none_match: req.get_header("If-None-Match"),
+            if_modified_since: req.get_header("If-Modified-Since"),
+            if_unmodified_since: req.get_header("If-Unmodified-Since"),
+        };
+        let any = preconditions.if_match.is_some()
+            || preconditions.if_none_match.is_some()
+            || preconditions.if_modified_since.is_some()
+            || preconditions.if_unmodified_since.is_some();
+        any.then_some(preconditions)
+    }
+
+    /// Whether any of them applies to a write, `If-Modified-Since` only applies to reads
+    pub fn for_writes(&self) -> bool {
+        self.if_match.is_some() || self.if_none_match.is_some() || self.if_unmodified_since.is_some()
+    }
+
+    /// Checks the preconditions against the `current` representation, `None`
+    /// when there is none. Reads compare the tag that was sent, writes also
+    /// take a tag with the coding suffix of a compressed GET.
+    /// `Err` is `304 Not Modified` for reads that can use their cached copy,
+    /// or `412 Precondition Failed`
+    pub fn evaluate(&self, current: Option<&Validators>, read: bool) -> Result<(), StatusCode> {
+        // Preconditions only apply to a representation that is there to be read
+        if read && current.is_none() {
+            return Ok(());
+        }
+        let exists = current.is_some();
+        let etag = current.and_then(|current| current.etag.as_ref());
+        let last_modified = current
+            .and_then(|current| current.last_modified)
+            .map(unix_secs);
+        let decoded = |eq: fn(&ETag, &ETag) -> bool| {
+            move |sent: &ETag, current: &ETag| {
+                eq(sent, current)
+                    || (!read && sent.without_coding().is_some_and(|sent| eq(&sent, current)))
+            }
+        };
+
+        if let Some(if_match) = &self.if_match {
+            if !exists || !matches(if_match, etag, decoded(ETag::strong_eq)) {
+                return Err(StatusCode::PreconditionFailed);
+            }
+        } else if let Some(date) = self.if_unmodified_since.as_deref().and_then(parse_http_date) {
+            if last_modified.is_some_and(|modified| modified > unix_secs(date)) {
+                return Err(StatusCode::PreconditionFailed);
+            }
+        }
+
+        if let Some(if_none_match) = &self.if_none_match {
+            if exists && matches(if_none_match, etag, decoded(ETag::weak_eq)) {
+                return Err(if read {
+                    StatusCode::NotModified
+                } else {
+                    StatusCode::PreconditionFailed
+                });
+            }
+        } else if let Some(date) = self.if_modified_since.as_deref().and_then(parse_http_date) {
+            if read && last_modified.is_some_and(|modified| modified <= unix_secs(date)) {
+                return Err(StatusCode::NotModified);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Validators of the representations the server sent, so writes to routes
+/// without a validator can be checked, and a lock per resource so conditional
+/// writes to the same one run one at a time
+#[derive(Debug, Default)]
+pub(crate) struct Resources {
+    /// `None` for a resource that was answered with 404
+    sent: Mutex<HashMap<String, Option<Validators>>>,
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resources are told apart by host and target
+    pub fn key(req: &Request) -> String {
+        format!("{} {}", req.get_host().unwrap_or_default(), req.get_path())
+    }
+
+    /// Keeps the validators of a GET response. Other errors say nothing about the resource
+    pub fn remember(&self, key: &str, resp: &Response) {
+        let current = match resp.get_status() {
+            StatusCode::NotFound => None,
+            _ => match Validators::from_response(resp) {
+                Some(current) => Some(current),
+                None => return,
+            },
+        };
+        let mut sent = self.sent.lock().unwrap();
+        if sent.len() >= MAX_REMEMBERED && !sent.contains_key(key) {
+            sent.clear();
+        }
+        sent.insert(key.to_string(), current);
+    }
+
+    /// Validators last sent for the resource, `None` when they aren't known
+    pub fn get_sent(&self, key: &str) -> Option<Option<Validators>> {
+        self.sent.lock().unwrap().get(key).cloned()
+    }
+
+    /// Takes the validators of a successful write response, the resource is
+    /// unknown until it is read again when it has none
+    pub fn written(&self, key: &str, resp: &Response) {
+        let Some(current) = Validators::from_response(resp) else {
+            return;
+        };
+        let mut sent = self.sent.lock().unwrap();
+        if current.etag.is_none() && current.last_modified.is_none() {
+            sent.remove(key);
+        } else {
+            sent.insert(key.to_string(), Some(current));
+        }
+    }
+
+    /// Waits until no other conditional write to the resource is running
+    pub async fn lock(self: &Arc<Self>, key: &str) -> ResourceGuard {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        ResourceGuard {
+            resources: self.clone(),
+            key: key.to_string(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+/// Held while a conditional write runs, the lock is dropped with the last guard
+pub(crate) struct ResourceGuard {
+    resources: Arc<Resources>,
+    key: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for ResourceGuard {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self.resources.locks.lock().unwrap();
+        // Writes still waiting hold the lock as well
+        if locks
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+/// Answer to a failed precondition, a `304` keeps the validators and caching headers of `resp`
+pub(crate) fn refuse(status: StatusCode, resp: &Response) -> Response {
+    let mut refused = ResponseBuilder::new(status, "").build();
+    if status == StatusCode::NotModified {
+        refused.set_body(Default::default());
+        refused.remove_header("Content-Type");
+        refused.remove_header("Content-Length");
+        for name in NOT_MODIFIED_HEADERS {
+            if let Some(value) = resp.get_header(name) {
+                refused.set_header(name, &value);
+            }
+        }
+    }
+    refused
+}
+
+/// Sets a strong `ETag` hashed from the body of a successful buffered response without one
+pub(crate) fn auto_etag(resp: &mut Response) {
+    let status = resp.get_status().as_u16();
+    if !(200..300).contains(&status) || resp.is_streaming() || resp.get_header("ETag").is_some() {
+        return;
+    }
+    let etag = ETag::from_body(&resp.get_body_bytes());
+    resp.set_header("ETag", &etag.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preconditions(headers: &[(&str, &str)]) -> Preconditions {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        };
+        Preconditions {
+            if_match: header("If-Match"),
+            if_none_match: header("If-None-Match"),
+            if_modified_since: header("If-Modified-Since"),
+            if_unmodified_since: header("If-Unmodified-Since"),
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn current(etag: &str, modified: u64) -> Validators {
+        Validators::new()
+            .etag(ETag::parse(etag).unwrap())
+            .last_modified(at(modified))
+    }
+
+    #[test]
+    fn parses_and_formats_tags() {
+        assert_eq!(ETag::parse("\"v1\""), Some(ETag::strong("v1")));
+        assert_eq!(ETag::parse(" W/\"v1\" "), Some(ETag::weak("v1")));
+        assert_eq!(ETag::parse("\"\""), Some(ETag::strong("")));
+        assert_eq!(ETag::parse("v1"), None);
+        assert_eq!(ETag::parse("\"a\", \"b\""), None);
+        assert_eq!(ETag::strong("v1").to_string(), "\"v1\"");
+        assert_eq!(ETag::weak("v1").to_string(), "W/\"v1\"");
+    }
+
+    #[test]
+    fn parses_tag_lists() {
+        let list = parse_list("\"a\", W/\"b\",\"c\"").unwrap();
+        assert_eq!(list, vec![ETag::strong("a"), ETag::weak("b"), ETag::strong("c")]);
+        assert_eq!(parse_list("").unwrap(), vec![]);
+        assert!(parse_list("\"a\" \"b\"").is_none());
+        assert!(parse_list("\"a").is_none());
+    }
+
+    #[test]
+    fn strong_and_weak_comparison() {
+        let (strong, weak) = (ETag::strong("1"), ETag::weak("1"));
+        assert!(strong.strong_eq(&ETag::strong("1")));
+        assert!(!strong.strong_eq(&weak));
+        assert!(!weak.strong_eq(&weak));
+        assert!(!strong.strong_eq(&ETag::strong("2")));
+        assert!(strong.weak_eq(&weak));
+        assert!(weak.weak_eq(&weak));
+        assert!(!weak.weak_eq(&ETag::weak("2")));
+    }
+
+    #[test]
+    fn body_tags_follow_the_bytes() {
+        let tag = ETag::from_body(b"hello");
+        assert!(!tag.is_weak());
+        assert_eq!(tag.get_tag().len(), 32);
+        assert_eq!(tag, ETag::from_body(b"hello"));
+        assert_ne!(tag, ETag::from_body(b"hello!"));
+    }
+
+    #[test]
+    fn coding_suffix_round_trips() {
+        let tag = ETag::strong("v1");
+        let gzip = tag.with_coding("gzip");
+        assert_eq!(gzip.to_string(), "\"v1-gzip\"");
+        assert_eq!(gzip.without_coding(), Some(tag.clone()));
+        assert_eq!(tag.with_coding("br").without_coding(), Some(tag));
+        assert_eq!(ETag::strong("v1").without_coding(), None);
+        assert_eq!(ETag::strong("gzip").without_coding(), None);
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let time = parse_http_date(date).unwrap();
+        assert_eq!(format_http_date(time), date);
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn if_none_match_on_reads() {
+        let current = current("\"v1\"", 100);
+        let check = |header: &str| {
+            preconditions(&[("If-None-Match", header)]).evaluate(Some(&current), true)
+        };
+        assert_eq!(check("\"v1\""), Err(StatusCode::NotModified));
+        assert_eq!(check("W/\"v1\""), Err(StatusCode::NotModified));
+        assert_eq!(check("\"v0\", \"v1\""), Err(StatusCode::NotModified));
+        assert_eq!(check("*"), Err(StatusCode::NotModified));
+        assert_eq!(check("\"v2\""), Ok(()));
+        // A read doesn't undo the coding, the tag of the body that was sent must match
+        assert_eq!(check("\"v1-gzip\""), Ok(()));
+    }
+
+    #[test]
+    fn if_none_match_overrides_if_modified_since() {
+        let current = current("\"v1\"", 100);
+        let stale_tag = preconditions(&[
+            ("If-None-Match", "\"v0\""),
+            ("If-Modified-Since", "Thu, 01 Jan 1970 00:03:20 GMT"),
+        ]);
+        assert_eq!(stale_tag.evaluate(Some(&current), true), Ok(()));
+    }
+
+    #[test]
+    fn if_modified_since_on_reads() {
+        let current = current("\"v1\"", 100);
+        let check = |date: &str| {
+            preconditions(&[("If-Modified-Since", date)]).evaluate(Some(&current), true)
+        };
+        assert_eq!(check(&format_http_date(at(100))), Err(StatusCode::NotModified));
+        assert_eq!(check(&format_http_date(at(200))), Err(StatusCode::NotModified));
+        assert_eq!(check(&format_http_date(at(99))), Ok(()));
+        assert_eq!(check("garbage"), Ok(()));
+    }
+
+    #[test]
+    fn reads_of_missing_resources_ignore_preconditions() {
+        let check = preconditions(&[("If-Match", "\"v1\"")]);
+        assert_eq!(check.evaluate(None, true), Ok(()));
+    }
+
+    #[test]
+    fn if_match_on_writes() {
+        let current = current("\"v1\"", 100);
+        let check = |header: &str| preconditions(&[("If-Match", header)]).evaluate(Some(&current), false);
+        assert_eq!(check("\"v1\""), Ok(()));
+        assert_eq!(check("*"), Ok(()));
+        assert_eq!(check("\"v2\""), Err(StatusCode::PreconditionFailed));
+        assert_eq!(check("W/\"v1\""), Err(StatusCode::PreconditionFailed));
+        // The tag a compressed GET sent names the same version
+        assert_eq!(check("\"v1-gzip\""), Ok(()));
+        assert_eq!(check("\"v2-br\""), Err(StatusCode::PreconditionFailed));
+        assert_eq!(
+            preconditions(&[("If-Match", "*")]).evaluate(None, false),
+            Err(StatusCode::PreconditionFailed)
+        );
+    }
+
+    #[test]
+    fn if_none_match_on_writes() {
+        let current = current("\"v1\"", 100);
+        let create = preconditions(&[("If-None-Match", "*")]);
+        assert_eq!(create.evaluate(None, false), Ok(()));
+        assert_eq!(create.evaluate(Some(&current), false), Err(StatusCode::PreconditionFailed));
+        let check = preconditions(&[("If-None-Match", "\"v1-deflate\"")]);
+        assert_eq!(check.evaluate(Some(&current), false), Err(StatusCode::PreconditionFailed));
+    }
+
+    #[test]
+    fn if_unmodified_since_on_writes() {
+        let current = current("\"v1\"", 100);
+        let check = |date: SystemTime| {
+            preconditions(&[("If-Unmodified-Since", &format_http_date(date))])
+                .evaluate(Some(&current), false)
+        };
+        assert_eq!(check(at(100)), Ok(()));
+        assert_eq!(check(at(99)), Err(StatusCode::PreconditionFailed));
+        // If-Match takes precedence
+        let both = preconditions(&[
+            ("If-Match", "\"v1\""),
+            ("If-Unmodified-Since", &format_http_date(at(0))),
+        ]);
+        assert_eq!(both.evaluate(Some(&current), false), Ok(()));
+    }
+
+    #[test]
+    fn validators_of_a_response() {
+        let resp = ResponseBuilder::new(StatusCode::Ok, "body")
+            .etag("v1")
+            .last_modified(at(100))
+            .build();
+        let validators = Validators::from_response(&resp).unwrap();
+        assert_eq!(validators.get_etag(), Some(&ETag::strong("v1")));
+        assert_eq!(validators.get_last_modified(), Some(at(100)));
+        let missing = ResponseBuilder::new(StatusCode::NotFound, "").build();
+        assert!(Validators::from_response(&missing).is_none());
+    }
+
+    #[test]
+    fn not_modified_keeps_the_validators() {
+        let resp = ResponseBuilder::new(StatusCode::Ok, "body")
+            .etag("v1")
+            .set_header("Vary", "Accept-Encoding")
+            .build();
+        let refused = refuse(StatusCode::NotModified, &resp);
+        assert_eq!(refused.get_status(), StatusCode::NotModified);
+        assert_eq!(refused.get_header("ETag").as_deref(), Some("\"v1\""));
+        assert_eq!(refused.get_header("Vary").as_deref(), Some("Accept-Encoding"));
+        assert!(refused.get_header("Content-Type").is_none());
+        assert!(refused.get_body_bytes().is_empty());
+    }
+
+    #[test]
+    fn auto_etag_only_tags_successful_buffered_bodies() {
+        let mut resp = ResponseBuilder::new(StatusCode::Ok, "body").build();
+        auto_etag(&mut resp);
+        let expected = ETag::from_body(&resp.get_body_bytes()).to_string();
+        assert_eq!(resp.get_header("ETag"), Some(expected));
+
+        let mut tagged = ResponseBuilder::new(StatusCode::Ok, "body").etag("mine").build();
+        auto_etag(&mut tagged);
+        assert_eq!(tagged.get_header("ETag").as_deref(), Some("\"mine\""));
+
+        let mut failed = ResponseBuilder::new(StatusCode::BadRequest, "body").build();
+        auto_etag(&mut failed);
+        assert!(failed.get_header("ETag").is_none());
+    }
+
+    fn response(status: StatusCode, etag: Option<&str>) -> Response {
+        let mut builder = ResponseBuilder::new(status, "");
+        if let Some(etag) = etag {
+            builder = builder.set_header("ETag", etag);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn remembers_what_was_sent() {
+        let resources = Resources::new();
+        assert!(resources.get_sent("/a").is_none());
+
+        resources.remember("/a", &response(StatusCode::Ok, Some("\"v1\"")));
+        let sent = resources.get_sent("/a").unwrap().unwrap();
+        assert_eq!(sent.get_etag(), Some(&ETag::strong("v1")));
+
+        // Errors other than 404 don't change what is known
+        resources.remember("/a", &response(StatusCode::InternalServerError, None));
+        assert!(resources.get_sent("/a").unwrap().is_some());
+        resources.remember("/a", &response(StatusCode::NotFound, None));
+        assert!(resources.get_sent("/a").unwrap().is_none());
+
+        resources.written("/a", &response(StatusCode::Created, Some("\"v2\"")));
+        let sent = resources.get_sent("/a").unwrap().unwrap();
+        assert_eq!(sent.get_etag(), Some(&ETag::strong("v2")));
+        resources.written("/a", &response(StatusCode::BadRequest, None));
+        assert!(resources.get_sent("/a").is_some());
+        // Changed without saying to what
+        resources.written("/a", &response(StatusCode::NoContent, None));
+        assert!(resources.get_sent("/a").is_none());
+    }
+
+    #[tokio::test]
+    async fn locks_each_resource_separately() {
+        let resources = Arc::new(Resources::new());
+        let first = resources.lock("/items/1").await;
+        // Another resource isn't held up
+        let other = tokio::time::timeout(Duration::from_secs(1), resources.lock("/items/2"))
+            .await
+            .unwrap();
+        drop(other);
+
+        let waiting = tokio::spawn({
+            let resources = resources.clone();
+            async move { resources.lock("/items/1").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        drop(first);
+        let second = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resources.locks.lock().unwrap().len(), 1);
+        drop(second);
+        assert!(resources.locks.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,865 @@
+use crate::modules::compression::{Compression, Encoding};
+use crate::modules::http2::Http2Config;
+use crate::modules::limits::Limits;
+use crate::modules::listener::Listener;
+use crate::modules::load_shedding::LoadShedding;
+use crate::modules::profile::{Level, Profile, ProfileConfig};
+use crate::modules::session::SessionType;
+use crate::modules::timeouts::Timeouts;
+use crate::modules::tls::ClientAuth;
+use crate::modules::websocket::WebSocketConfig;
+use crate::NuttServer;
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use toml::{Table, Value};
+use tracing_log::log::{log, Level as LogLevel};
+
+/// Prefix of the variables overriding the config file. `__` separates nested
+/// keys and array indexes: `NUTT_TIMEOUTS__SHUTDOWN=10s`, `NUTT_LISTENERS__0__ADDRESS=0.0.0.0:8443`.
+/// Only server keys and the app sections of the file can be overridden
+pub const ENV_PREFIX: &str = "NUTT_";
+
+/// Top level keys read by the server, every other table is an app section
+const SERVER_KEYS: [&str; 13] = [
+    "profile",
+    "logging",
+    "profiles",
+    "tls",
+    "http2",
+    "listeners",
+    "timeouts",
+    "limits",
+    "load_shedding",
+    "compression",
+    "auto_etag",
+    "session",
+    "websocket",
+];
+
+/// Typed app section of the config file, registered with `NuttServer::config`
+pub struct Config<T>(Arc<T>);
+
+impl<T> Config<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self(Arc::new(value))
+    }
+}
+
+impl<T> Clone for Config<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Debug> Debug for Config<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T> Deref for Config<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// App sections not registered yet and the errors found while registering
+#[derive(Debug, Default)]
+pub(crate) struct ConfigSections {
+    sections: Table,
+    errors: Vec<String>,
+}
+
+impl ConfigSections {
+    pub fn take<T: DeserializeOwned>(&mut self, section: &str) -> Option<T> {
+        let Some(value) = self.sections.remove(section) else {
+            self.errors.push(format!("missing section [{}]", section));
+            return None;
+        };
+        match value.try_into::<T>() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.push(format!("[{}]: {}", section, e.message()));
+                None
+            }
+        }
+    }
+
+    /// Fails with every registration error, warns about sections nobody asked for
+    pub fn check(&self) -> Result<()> {
+        if !self.errors.is_empty() {
+            anyhow::bail!("invalid configuration:\n  {}", self.errors.join("\n  "));
+        }
+        for section in self.sections.keys() {
+            log!(LogLevel::Warn, "Config section [{}] is not used", section);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerSettings {
+    profile: Option<String>,
+    #[serde(default)]
+    logging: ProfileSection,
+    #[serde(default)]
+    profiles: HashMap<String, ProfileSection>,
+    tls: Option<TlsSection>,
+    http2: Option<Http2Section>,
+    #[serde(default)]
+    listeners: Vec<ListenerSection>,
+    #[serde(default)]
+    timeouts: TimeoutsSection,
+    #[serde(default)]
+    limits: LimitsSection,
+    load_shedding: Option<LoadSheddingSection>,
+    compression: Option<CompressionSection>,
+    auto_etag: Option<bool>,
+    session: Option<SessionSection>,
+    websocket: Option<WebSocketSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileSection {
+    address: Option<String>,
+    tls: Option<CertSection>,
+    level: Option<String>,
+    verbose_errors: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CertSection {
+    cert: String,
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostCertSection {
+    pattern: String,
+    cert: String,
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsSection {
+    cert: Option<String>,
+    key: Option<String>,
+    #[serde(default)]
+    hosts: Vec<HostCertSection>,
+    client_auth: Option<ClientAuthSection>,
+    watch_interval: Option<DurationValue>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientAuthSection {
+    ca: String,
+    #[serde(default = "default_required")]
+    required: bool,
+    #[serde(default)]
+    crls: Vec<String>,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Http2Section {
+    max_concurrent_streams: Option<u32>,
+    initial_window_size: Option<u32>,
+    initial_connection_window_size: Option<u32>,
+    max_frame_size: Option<u32>,
+    max_header_list_size: Option<u32>,
+    prior_knowledge: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerSection {
+    address: Option<String>,
+    unix: Option<String>,
+    fd_name: Option<String>,
+    tls: Option<TlsSection>,
+    http2: Option<Http2Section>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsSection {
+    shutdown: Option<DurationValue>,
+    handshake: Option<DurationValue>,
+    header_read: Option<DurationValue>,
+    body_read: Option<DurationValue>,
+    handler: Option<DurationValue>,
+    write: Option<DurationValue>,
+    /// Bytes per second
+    min_body_rate: Option<u64>,
+}
+
+/// Sizes in bytes, header count for `headers`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsSection {
+    request_line: Option<usize>,
+    headers: Option<usize>,
+    header_size: Option<usize>,
+    body_size: Option<usize>,
+    decompressed_size: Option<usize>,
+    connections: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoadSheddingSection {
+    max_queue_time: DurationValue,
+    retry_after: Option<DurationValue>,
+}
+
+/// `encodings` in order of preference: `br`, `gzip`, `deflate`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressionSection {
+    encodings: Option<Vec<String>>,
+    min_size: Option<usize>,
+}
+
+/// Sizes in bytes, a `ping_interval` of 0 turns keepalive pings off
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebSocketSection {
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    fragment_size: Option<usize>,
+    ping_interval: Option<DurationValue>,
+    close_timeout: Option<DurationValue>,
+    deflate: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SessionSection {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// Seconds as a number, or a string with a unit: `500ms`, `30s`, `5m`, `1h`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum DurationValue {
+    Secs(u64),
+    Text(String),
+}
+
+impl DurationValue {
+    fn parse(&self) -> Result<Duration, String> {
+        let text = match self {
+            DurationValue::Secs(secs) => return Ok(Duration::from_secs(*secs)),
+            DurationValue::Text(text) => text.trim(),
+        };
+        let split = text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+        let (amount, unit) = text.split_at(split);
+        let amount = amount
+            .parse::<u64>()
+            .map_err(|_| format!("invalid duration `{}`", text))?;
+        let secs = |per_unit: u64| {
+            amount
+                .checked_mul(per_unit)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("duration `{}` is too long", text))
+        };
+        match unit.trim() {
+            "ms" => Ok(Duration::from_millis(amount)),
+            "" | "s" => secs(1),
+            "m" => secs(60),
+            "h" => secs(3600),
+            _ => Err(format!("invalid duration `{}`", text)),
+        }
+    }
+}
+
+pub(crate) fn load(server: NuttServer, path: &str) -> Result<NuttServer> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read config file {}", path))?;
+    let mut table = text
+        .parse::<Table>()
+        .with_context(|| format!("cannot parse config file {}", path))?;
+    apply_env(&mut table, std::env::vars_os())
+        .with_context(|| format!("invalid {}* environment variable", ENV_PREFIX))?;
+
+    let mut settings = Table::new();
+    for key in SERVER_KEYS {
+        if let Some(value) = table.remove(key) {
+            settings.insert(key.to_string(), value);
+        }
+    }
+    let settings = settings
+        .try_into::<ServerSettings>()
+        .map_err(|e| anyhow::anyhow!("invalid configuration in {}: {}", path, e.message()))?;
+
+    let mut errors = Errors::default();
+    let mut server = apply(server, settings, &mut errors);
+    if !errors.0.is_empty() {
+        anyhow::bail!(
+            "invalid configuration in {}:\n  {}",
+            path,
+            errors.0.join("\n  ")
+        );
+    }
+    server.config = ConfigSections {
+        sections: table,
+        errors: vec![],
+    };
+    Ok(server)
+}
+
+/// Sets `NUTT_A__B=value` as `a.b = value`. Values are read as TOML when
+/// they parse (numbers, booleans, arrays) and as strings otherwise. Variables
+/// for other top level keys, or that aren't valid UTF-8, are left out. They are
+/// applied sorted by key, so array items are created in index order
+fn apply_env(table: &mut Table, vars: impl Iterator<Item = (OsString, OsString)>) -> Result<()> {
+    let sections = table
+        .iter()
+        .filter(|(_, value)| value.is_table())
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<_>>();
+    let mut overrides = Vec::new();
+    for (name, raw) in vars {
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let Ok(raw) = raw.into_string() else {
+            log!(LogLevel::Warn, "Ignoring {}, its value is not valid UTF-8", name);
+            continue;
+        };
+        let path = key
+            .split("__")
+            .map(|part| part.to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        if !SERVER_KEYS.contains(&path[0].as_str()) && !sections.contains(&path[0]) {
+            continue;
+        }
+        overrides.push((path, name.to_string(), raw));
+    }
+    overrides.sort_by(|(a, ..), (b, ..)| compare_paths(a, b));
+    for (path, name, raw) in overrides {
+        let value = format!("value = {}", raw)
+            .parse::<Table>()
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or(Value::String(raw));
+        set_path(table, &path, value).with_context(|| name)?;
+    }
+    Ok(())
+}
+
+/// Orders key paths segment by segment, array indexes by their number
+fn compare_paths(a: &[String], b: &[String]) -> Ordering {
+    for (a, b) in a.iter().zip(b) {
+        let order = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+fn set_path(table: &mut Table, path: &[String], value: Value) -> Result<()> {
+    let (first, rest) = path.split_first().context("empty key")?;
+    if rest.is_empty() {
+        table.insert(first.clone(), value);
+        return Ok(());
+    }
+    let entry = table
+        .entry(first.clone())
+        .or_insert_with(|| Value::Table(Table::new()));
+    set_value(entry, rest, value)
+}
+
+fn set_value(target: &mut Value, path: &[String], value: Value) -> Result<()> {
+    match target {
+        Value::Table(table) => set_path(table, path, value),
+        Value::Array(array) => {
+            let (index, rest) = path.split_first().context("empty key")?;
+            let index = index
+                .parse::<usize>()
+                .with_context(|| format!("`{}` is not an array index", index))?;
+            if index == array.len() {
+                array.push(Value::Table(Table::new()));
+            }
+            let item = array
+                .get_mut(index)
+                .with_context(|| format!("index {} is out of range", index))?;
+            if rest.is_empty() {
+                *item = value;
+                Ok(())
+            } else {
+                set_value(item, rest, value)
+            }
+        }
+        _ => anyhow::bail!("`{}` is not a table", path[0]),
+    }
+}
+
+/// Validation errors, collected so they can be reported together
+#[derive(Default)]
+struct Errors(Vec<String>);
+
+impl Errors {
+    fn check<T>(&mut self, result: Result<T, String>) -> Option<T> {
+        result.map_err(|e| self.0.push(e)).ok()
+    }
+
+    fn file(&mut self, what: &str, path: &str) {
+        if !Path::new(path).is_file() {
+            self.0.push(format!("{} file {} does not exist", what, path));
+        }
+    }
+}
+
+fn parse_address(address: &str) -> Result<(String, u16), String> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| format!("address `{}` must be host:port", address))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("invalid port in address `{}`", address))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok((host.to_string(), port))
+}
+
+fn apply(mut server: NuttServer, settings: ServerSettings, errors: &mut Errors) -> NuttServer {
+    // The command line flag still wins over the file
+    if let Some(profile) = Profile::from_flag().or(settings.profile.as_deref().map(Profile::parse))
+    {
+        server = server.active_profile(profile);
+    }
+    server = server.default_profile(profile_config(&settings.logging, errors));
+    for (name, section) in &settings.profiles {
+        server = server.profile(Profile::parse(name), profile_config(section, errors));
+    }
+
+    if let Some(tls) = &settings.tls {
+        match (&tls.cert, &tls.key) {
+            (Some(cert), Some(key)) => {
+                errors.file("certificate", cert);
+                errors.file("private key", key);
+                server = server.set_tls_certs(Some((cert, key)));
+            }
+            (None, None) => {}
+            _ => errors.0.push("[tls] needs both cert and key".to_string()),
+        }
+        for host in &tls.hosts {
+            errors.file("certificate", &host.cert);
+            errors.file("private key", &host.key);
+            server = server.set_host_tls_certs(&host.pattern, (&host.cert, &host.key));
+        }
+        if let Some(client_auth) = &tls.client_auth {
+            server = server.client_auth(client_auth_config(client_auth, errors));
+        }
+        if let Some(interval) = &tls.watch_interval {
+            if let Some(interval) = errors.check(interval.parse()) {
+                server = server.watch_tls_certs(interval);
+            }
+        }
+    }
+    if let Some(http2) = &settings.http2 {
+        server = server.http2(http2_config(http2));
+    }
+    for section in &settings.listeners {
+        if let Some(listener) = listener(section, errors) {
+            server = server.listen(listener);
+        }
+    }
+    let timeouts = &settings.timeouts;
+    let mut duration = |value: &Option<DurationValue>| {
+        value
+            .as_ref()
+            .and_then(|value| errors.check(value.parse()))
+    };
+    if let Some(timeout) = duration(&timeouts.shutdown) {
+        server = server.shutdown_timeout(timeout);
+    }
+    let mut connection_timeouts = Timeouts::new();
+    if let Some(timeout) = duration(&timeouts.handshake) {
+        connection_timeouts = connection_timeouts.handshake(timeout);
+    }
+    if let Some(timeout) = duration(&timeouts.header_read) {
+        connection_timeouts = connection_timeouts.header_read(timeout);
+    }
+    if let Some(timeout) = duration(&timeouts.body_read) {
+        connection_timeouts = connection_timeouts.body_read(timeout);
+    }
+    if let Some(timeout) = duration(&timeouts.handler) {
+        connection_timeouts = connection_timeouts.handler(timeout);
+    }
+    if let Some(timeout) = duration(&timeouts.write) {
+        connection_timeouts = connection_timeouts.write(timeout);
+    }
+    if let Some(rate) = timeouts.min_body_rate {
+        connection_timeouts = connection_timeouts.min_body_rate(rate);
+    }
+    server = server.timeouts(connection_timeouts);
+    if let Some(section) = &settings.load_shedding {
+        if let Some(max_queue_time) = duration(&Some(section.max_queue_time.clone())) {
+            let mut load_shedding = LoadShedding::new(max_queue_time);
+            if let Some(retry_after) = duration(&section.retry_after) {
+                load_shedding = load_shedding.retry_after(retry_after);
+            }
+            server = server.load_shedding(load_shedding);
+        }
+    }
+    if let Some(section) = &settings.websocket {
+        let mut websocket = WebSocketConfig::new();
+        if let Some(bytes) = section.max_message_size {
+            websocket = websocket.max_message_size(bytes);
+        }
+        if let Some(bytes) = section.max_frame_size {
+            websocket = websocket.max_frame_size(bytes);
+        }
+        if let Some(bytes) = section.fragment_size {
+            websocket = websocket.fragment_size(bytes);
+        }
+        if let Some(interval) = duration(&section.ping_interval) {
+            websocket =
+                websocket.ping_interval(Some(interval).filter(|interval| !interval.is_zero()));
+        }
+        if let Some(timeout) = duration(&section.close_timeout) {
+            websocket = websocket.close_timeout(timeout);
+        }
+        if let Some(enabled) = section.deflate {
+            websocket = websocket.deflate(enabled);
+        }
+        server = server.websocket(websocket);
+    }
+
+    let mut limits = Limits::new();
+    if let Some(bytes) = settings.limits.request_line {
+        limits = limits.max_request_line(bytes);
+    }
+    if let Some(count) = settings.limits.headers {
+        limits = limits.max_headers(count);
+    }
+    if let Some(bytes) = settings.limits.header_size {
+        limits = limits.max_header_size(bytes);
+    }
+    if let Some(bytes) = settings.limits.body_size {
+        limits = limits.max_body_size(bytes);
+    }
+    if let Some(bytes) = settings.limits.decompressed_size {
+        limits = limits.max_decompressed_size(bytes);
+    }
+    if let Some(count) = settings.limits.connections {
+        limits = limits.max_connections(count);
+    }
+    server = server.limits(limits);
+    if let Some(section) = &settings.compression {
+        let mut compression = Compression::new();
+        if let Some(names) = &section.encodings {
+            let encodings = names
+                .iter()
+                .filter_map(|name| {
+                    let encoding = Encoding::parse(name)
+                        .ok_or_else(|| format!("unknown compression encoding `{}`", name));
+                    errors.check(encoding)
+                })
+                .collect::<Vec<_>>();
+            compression = compression.encodings(&encodings);
+        }
+        if let Some(bytes) = section.min_size {
+            compression = compression.min_size(bytes);
+        }
+        server = server.compression(compression);
+    }
+    if let Some(enabled) = settings.auto_etag {
+        server = server.auto_etag(enabled);
+    }
+    if let Some(session) = &settings.session {
+        match session.kind.as_str() {
+            "cookie" => server = server.session(SessionType::Cookie),
+            other => errors.0.push(format!("unknown session type `{}`", other)),
+        }
+    }
+    server
+}
+
+fn profile_config(section: &ProfileSection, errors: &mut Errors) -> ProfileConfig {
+    let mut config = ProfileConfig::new();
+    if let Some(address) = &section.address {
+        if let Some(address) = errors.check(parse_address(address)) {
+            config = config.bind((&address.0, address.1));
+        }
+    }
+    if let Some(tls) = &section.tls {
+        errors.file("certificate", &tls.cert);
+        errors.file("private key", &tls.key);
+        config = config.tls_certs((&tls.cert, &tls.key));
+    }
+    if let Some(level) = &section.level {
+        let level = level
+            .parse::<Level>()
+            .map_err(|_| format!("unknown log level `{}`", level));
+        if let Some(level) = errors.check(level) {
+            config = config.log_level(level);
+        }
+    }
+    if let Some(verbose) = section.verbose_errors {
+        config = config.verbose_errors(verbose);
+    }
+    config
+}
+
+fn client_auth_config(section: &ClientAuthSection, errors: &mut Errors) -> ClientAuth {
+    errors.file("CA", &section.ca);
+    let mut client_auth = if section.required {
+        ClientAuth::required(&section.ca)
+    } else {
+        ClientAuth::optional(&section.ca)
+    };
+    for crl in &section.crls {
+        errors.file("CRL", crl);
+        client_auth = client_auth.crl(crl);
+    }
+    client_auth
+}
+
+fn http2_config(section: &Http2Section) -> Http2Config {
+    let mut config = Http2Config::new();
+    if let Some(max) = section.max_concurrent_streams {
+        config = config.max_concurrent_streams(max);
+    }
+    if let Some(size) = section.initial_window_size {
+        config = config.initial_window_size(size);
+    }
+    if let Some(size) = section.initial_connection_window_size {
+        config = config.initial_connection_window_size(size);
+    }
+    if let Some(size) = section.max_frame_size {
+        config = config.max_frame_size(size);
+    }
+    if let Some(size) = section.max_header_list_size {
+        config = config.max_header_list_size(size);
+    }
+    if let Some(enabled) = section.prior_knowledge {
+        config = config.prior_knowledge(enabled);
+    }
+    config
+}
+
+fn listener(section: &ListenerSection, errors: &mut Errors) -> Option<Listener> {
+    let mut listener = match (&section.address, &section.unix) {
+        (Some(address), None) => {
+            let address = errors.check(parse_address(address))?;
+            Listener::tcp((&address.0, address.1))
+        }
+        #[cfg(unix)]
+        (None, Some(path)) => Listener::unix(path),
+        #[cfg(not(unix))]
+        (None, Some(_)) => {
+            errors.0.push("unix listeners need a unix platform".to_string());
+            return None;
+        }
+        _ => {
+            errors
+                .0
+                .push("a listener needs either address or unix".to_string());
+            return None;
+        }
+    };
+    if let Some(name) = &section.fd_name {
+        listener = listener.fd_name(name);
+    }
+    if let Some(tls) = &section.tls {
+        if tls.watch_interval.is_some() {
+            errors
+                .0
+                .push("watch_interval is only read from [tls]".to_string());
+        }
+        match (&tls.cert, &tls.key) {
+            (Some(cert), Some(key)) => {
+                errors.file("certificate", cert);
+                errors.file("private key", key);
+                listener = listener.set_tls_certs((cert, key));
+            }
+            (None, None) => {}
+            _ => errors
+                .0
+                .push("listener tls needs both cert and key".to_string()),
+        }
+        for host in &tls.hosts {
+            errors.file("certificate", &host.cert);
+            errors.file("private key", &host.key);
+            listener = listener.set_host_tls_certs(&host.pattern, (&host.cert, &host.key));
+        }
+        if let Some(client_auth) = &tls.client_auth {
+            listener = listener.client_auth(client_auth_config(client_auth, errors));
+        }
+    }
+    if let Some(http2) = &section.http2 {
+        listener = listener.http2(http2_config(http2));
+    }
+    Some(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duration(text: &str) -> Result<Duration, String> {
+        DurationValue::Text(text.to_string()).parse()
+    }
+
+    fn overlay(file: &str, vars: &[(&str, &str)]) -> Result<Table> {
+        let mut table = file.parse::<Table>().unwrap();
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (OsString::from(name), OsString::from(value)));
+        apply_env(&mut table, vars.collect::<Vec<_>>().into_iter())?;
+        Ok(table)
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(DurationValue::Secs(30).parse(), Ok(Duration::from_secs(30)));
+        assert_eq!(duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(duration(" 500ms "), Ok(Duration::from_millis(500)));
+        assert_eq!(duration("5 m"), Ok(Duration::from_secs(300)));
+        assert_eq!(duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(duration("0s"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn refuses_invalid_durations() {
+        for text in ["", "s", "-5s", "1.5s", "10d", "5 minutes", "ten"] {
+            assert_eq!(duration(text), Err(format!("invalid duration `{}`", text.trim())));
+        }
+        assert_eq!(
+            duration("18446744073709551615h"),
+            Err("duration `18446744073709551615h` is too long".to_string())
+        );
+        assert!(duration("18446744073709551615s").is_ok());
+        assert!(duration("18446744073709551616s").is_err());
+    }
+
+    #[test]
+    fn overrides_nested_keys_with_typed_values() {
+        let table = overlay(
+            "[timeouts]\nshutdown = 30\n",
+            &[
+                ("NUTT_TIMEOUTS__SHUTDOWN", "10s"),
+                ("NUTT_TIMEOUTS__READ", "5"),
+                ("NUTT_AUTO_ETAG", "true"),
+                ("NUTT_COMPRESSION__ENCODINGS", "[\"gzip\"]"),
+                ("NUTT_PROFILE", "release"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(table["timeouts"]["shutdown"].as_str(), Some("10s"));
+        assert_eq!(table["timeouts"]["read"].as_integer(), Some(5));
+        assert_eq!(table["auto_etag"].as_bool(), Some(true));
+        assert_eq!(table["compression"]["encodings"][0].as_str(), Some("gzip"));
+        assert_eq!(table["profile"].as_str(), Some("release"));
+    }
+
+    #[test]
+    fn indexes_arrays_of_tables() {
+        let file = "[[listeners]]\naddress = \"127.0.0.1:80\"\n";
+        let names = (0..=10)
+            .rev()
+            .map(|i| format!("NUTT_LISTENERS__{}__ADDRESS", i))
+            .collect::<Vec<_>>();
+        let mut vars = names
+            .iter()
+            .zip((8000..=8010).rev())
+            .map(|(name, port)| (name.as_str(), format!("0.0.0.0:{}", port)))
+            .collect::<Vec<_>>();
+        vars.insert(5, ("NUTT_LISTENERS__1__PORT", "8443".to_string()));
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect::<Vec<_>>();
+        let table = overlay(file, &vars);
+        // Applied by index whatever order the environment lists them in
+        let listeners = table.unwrap()["listeners"].as_array().unwrap().clone();
+        assert_eq!(listeners.len(), 11);
+        for (i, listener) in listeners.iter().enumerate() {
+            assert_eq!(listener["address"].as_str(), Some(&*format!("0.0.0.0:{}", 8000 + i)));
+        }
+        assert_eq!(listeners[1]["port"].as_integer(), Some(8443));
+
+        assert!(overlay(file, &[("NUTT_LISTENERS__5__ADDRESS", "x")]).is_err());
+        assert!(overlay(file, &[("NUTT_LISTENERS__FIRST__ADDRESS", "x")]).is_err());
+        assert!(overlay("auto_etag = true\n", &[("NUTT_AUTO_ETAG__ON", "x")]).is_err());
+    }
+
+    #[test]
+    fn only_overrides_known_keys() {
+        let table = overlay(
+            "[database]\nurl = \"postgres://localhost\"\n",
+            &[
+                ("NUTT_DATABASE__URL", "postgres://db"),
+                ("NUTT_DATABASE__POOL", "8"),
+                ("NUTT_INSTANCE_ID", "a1"),
+                ("NUTT_CACHE__SIZE", "5"),
+                ("OTHER_TIMEOUTS__READ", "5"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(table["database"]["url"].as_str(), Some("postgres://db"));
+        assert_eq!(table["database"]["pool"].as_integer(), Some(8));
+        assert!(!table.contains_key("instance_id"));
+        assert!(!table.contains_key("cache"));
+        assert!(!table.contains_key("timeouts"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_variables_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let mut table = Table::new();
+        let vars = vec![
+            (OsString::from_vec(b"NUTT_PROFILE\xff".to_vec()), OsString::from("release")),
+            (OsString::from("NUTT_LOGGING"), OsString::from_vec(b"\xffdebug".to_vec())),
+            (OsString::from("NUTT_AUTO_ETAG"), OsString::from("false")),
+        ];
+        apply_env(&mut table, vars.into_iter()).unwrap();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table["auto_etag"].as_bool(), Some(false));
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse_address("0.0.0.0:8080"), Ok(("0.0.0.0".to_string(), 8080)));
+        assert_eq!(parse_address("[::1]:443"), Ok(("::1".to_string(), 443)));
+        assert!(parse_address("localhost").is_err());
+        assert!(parse_address("localhost:http").is_err());
+        assert!(parse_address("localhost:70000").is_err());
+    }
+}
@@ -0,0 +1,544 @@
+use crate::http::error::{ErrorKind, HttpError};
+use crate::http::request::Request;
+use crate::http::response::{Response, ResponseBuilder};
+use crate::http::status::StatusCode;
+use crate::modules::limits::Limits;
+use crate::modules::compression::Compression;
+use crate::http::method::Method;
+use crate::modules::conditional::{self, Preconditions, Resources, Validators};
+use crate::modules::load_shedding::LoadShedding;
+use crate::modules::metrics::ServerMetrics;
+use crate::modules::panic_guard::{self, CaughtPanic};
+use crate::modules::range::RangeRequest;
+use crate::modules::router::host::VirtualHosts;
+use crate::modules::session::Session;
+use crate::modules::shutdown::{self, Drain};
+use crate::modules::state::StateMap;
+use crate::modules::timeouts::Timeouts;
+use crate::modules::websocket::WebSocketConfig;
+use crate::not_found;
+use serde_json::json;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing_log::log::{log, Level};
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Response> + Send>>;
+pub type FallbackHandler = Arc<dyn Fn(Request) -> HandlerFuture + Send + Sync>;
+pub type ErrorHandler = Arc<dyn Fn(HttpError) -> HandlerFuture + Send + Sync>;
+
+pub(crate) struct Dispatcher {
+    hosts: VirtualHosts,
+    states: Arc<RwLock<StateMap>>,
+    session: Arc<Option<Session>>,
+    fallback: Option<FallbackHandler>,
+    on_error: Option<ErrorHandler>,
+    debug: bool,
+    timeouts: Timeouts,
+    limits: Limits,
+    load_shedding: Option<LoadShedding>,
+    compression: Option<Compression>,
+    auto_etag: bool,
+    metrics: ServerMetrics,
+    websocket: Arc<WebSocketConfig>,
+    resources: Arc<Resources>,
+    drain: Option<Drain>,
+}
+
+impl Dispatcher {
+    pub fn new(
+        hosts: VirtualHosts,
+        states: Arc<RwLock<StateMap>>,
+        session: Option<Session>,
+        fallback: Option<FallbackHandler>,
+        on_error: Option<ErrorHandler>,
+    ) -> Self {
+        Self {
+            hosts,
+            states,
+            session: Arc::new(session),
+            fallback,
+            on_error,
+            debug: false,
+            timeouts: Timeouts::new(),
+            limits: Limits::new(),
+            load_shedding: None,
+            compression: None,
+            auto_etag: false,
+            metrics: ServerMetrics::new(),
+            websocket: Arc::new(WebSocketConfig::new()),
+            resources: Arc::new(Resources::new()),
+            drain: None,
+        }
+    }
+
+    /// Detailed error pages with backtraces
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn load_shedding(mut self, load_shedding: Option<LoadShedding>) -> Self {
+        self.load_shedding = load_shedding;
+        self
+    }
+
+    pub fn compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// `ETag` hashed from the body of GET responses that have none
+    pub fn auto_etag(mut self, auto_etag: bool) -> Self {
+        self.auto_etag = auto_etag;
+        self
+    }
+
+    pub fn metrics(mut self, metrics: ServerMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn websocket(mut self, websocket: WebSocketConfig) -> Self {
+        self.websocket = Arc::new(websocket);
+        self
+    }
+
+    /// Ends streamed bodies of unknown size when the server drains
+    pub fn drain(mut self, drain: Drain) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
+    pub fn get_metrics(&self) -> &ServerMetrics {
+        &self.metrics
+    }
+
+    pub fn get_timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    pub fn get_limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Body limit of the route `req` is going to, known before its body is read
+    pub fn body_limit(&self, req: &Request) -> usize {
+        let router = self.hosts.select(req.get_host().as_deref());
+        router
+            .get((req.get_method(), req.get_path()))
+            .and_then(|route| route.get_max_body_size())
+            .unwrap_or(self.limits.get_max_body_size())
+    }
+
+    /// Largest decompressed body of the request, the route's body limit
+    /// unless the server's decompression limit is smaller
+    pub fn decompressed_limit(&self, req: &Request) -> usize {
+        self.body_limit(req).min(self.limits.get_max_decompressed_size())
+    }
+
+    /// Checks a request sent with `Expect: 100-continue` before its body is
+    /// read: it must have a route and pass the route middleware, which then
+    /// don't run again on dispatch. `Err` is the answer sent instead of `100 Continue`
+    pub async fn preflight(&self, req: Request) -> Result<Request, Response> {
+        panic_guard::with_backtraces(self.debug, self.run_preflight(req)).await
+    }
+
+    async fn run_preflight(&self, mut req: Request) -> Result<Request, Response> {
+        let (method, path) = (req.get_method(), req.get_path());
+        req.set_states(self.states.clone());
+        req.set_session(self.session.clone());
+        req.set_websocket_config(self.websocket.clone());
+        let router = self.hosts.select(req.get_host().as_deref());
+        let Some(route) = router.get((method.clone(), path.clone())) else {
+            // The fallback gets the whole request
+            return match self.fallback {
+                Some(_) => Ok(req),
+                None => Err(not_found!()),
+            };
+        };
+        if let Some(states) = route.get_states() {
+            req.set_states(states);
+        }
+        match route.run_middleware(req).await {
+            Ok(Ok(mut req)) => {
+                req.set_preflighted();
+                Ok(req)
+            }
+            Ok(Err(resp)) => Err(resp),
+            Err(e) => Err(self
+                .error(self.panic_error(e).with_request(method, path))
+                .await),
+        }
+    }
+
+    pub async fn dispatch(&self, req: Request) -> Response {
+        panic_guard::with_backtraces(self.debug, self.run_dispatch(req)).await
+    }
+
+    /// Routes the request and compresses the response. Reads are then checked
+    /// against their preconditions and get the ranges they ask for, both on
+    /// the representation that is sent. Writes are checked by the route
+    async fn run_dispatch(&self, req: Request) -> Response {
+        let accept_encoding = req.get_header("Accept-Encoding");
+        let read = (req.get_method() == Method::GET).then(|| {
+            (
+                Resources::key(&req),
+                Preconditions::from_request(&req),
+                RangeRequest::from_request(&req),
+            )
+        });
+        let mut resp = self.route(req).await;
+        if let Some((key, ..)) = &read {
+            resp = self.with_etag(resp);
+            // Writes without a route validator are checked against it
+            self.resources.remember(key, &resp);
+        }
+        // Before compressing, so the encoder still finishes a stream cut short
+        if let Some(drain) = &self.drain {
+            if resp.get_header("Content-Length").is_none() {
+                if let Some(body) = resp.take_stream() {
+                    resp.set_stream(shutdown::until_drained(body, drain.clone()));
+                }
+            }
+        }
+        if let Some(compression) = &self.compression {
+            resp = compression.apply(resp, accept_encoding.as_deref());
+        }
+        let Some((_, preconditions, range)) = read else {
+            return resp;
+        };
+        if let Some(preconditions) = preconditions {
+            let current = Validators::from_response(&resp);
+            if let Err(status) = preconditions.evaluate(current.as_ref(), true) {
+                return conditional::refuse(status, &resp);
+            }
+        }
+        match range {
+            Some(range) => range.apply(resp),
+            None => resp,
+        }
+    }
+
+    fn with_etag(&self, mut resp: Response) -> Response {
+        if self.auto_etag {
+            conditional::auto_etag(&mut resp);
+        }
+        resp
+    }
+
+    async fn route(&self, mut req: Request) -> Response {
+        let (method, path) = (req.get_method(), req.get_path());
+        req.set_states(self.states.clone());
+        req.set_session(self.session.clone());
+        req.set_websocket_config(self.websocket.clone());
+        let router = self.hosts.select(req.get_host().as_deref());
+        if let Some(route) = router.get((method.clone(), path.clone())) {
+            if let Some(states) = route.get_states() {
+                req.set_states(states);
+            }
+            let resources = self.resources.clone();
+            let _permit = match route.get_concurrency() {
+                Some(concurrency) => match self.acquire(concurrency).await {
+                    Ok(permit) => Some(permit),
+                    Err(err) => return self.shed(err.with_request(method, path)).await,
+                },
+                None => None,
+            };
+            let result = match self.timeouts.get_handler() {
+                Some(limit) => match tokio::time::timeout(limit, route.run_fabric(req, resources)).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        let err = HttpError::new(
+                            ErrorKind::Timeout,
+                            StatusCode::ServiceUnavailable,
+                            "Handler timed out",
+                        );
+                        return self.error(err.with_request(method, path)).await;
+                    }
+                },
+                None => route.run_fabric(req, resources).await,
+            };
+            match result {
+                Ok(resp) => resp,
+                Err(e) => {
+                    self.error(self.panic_error(e).with_request(method, path))
+                        .await
+                }
+            }
+        } else if let Some(fallback) = &self.fallback {
+            fallback(req).await
+        } else {
+            not_found!()
+        }
+    }
+
+    /// Waits for a concurrency slot of a route, at most the load shedding queue time
+    async fn acquire(&self, concurrency: Arc<Semaphore>) -> Result<OwnedSemaphorePermit, HttpError> {
+        let _queued = self.metrics.queued();
+        let acquire = concurrency.acquire_owned();
+        let permit = match &self.load_shedding {
+            Some(load_shedding) => {
+                tokio::time::timeout(load_shedding.get_max_queue_time(), acquire).await
+            }
+            None => Ok(acquire.await),
+        };
+        match permit {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(HttpError::new(
+                ErrorKind::Overload,
+                StatusCode::ServiceUnavailable,
+                "Too many requests queued for this route",
+            )),
+        }
+    }
+
+    async fn shed(&self, err: HttpError) -> Response {
+        self.metrics.record_shed();
+        let mut resp = self.error(err).await;
+        if let Some(load_shedding) = &self.load_shedding {
+            resp.set_header(
+                "Retry-After",
+                &load_shedding.get_retry_after_secs().to_string(),
+            );
+        }
+        resp
+    }
+
+    pub async fn error(&self, err: HttpError) -> Response {
+        log!(Level::Error, "{}", err);
+        if let Some(on_error) = &self.on_error {
+            on_error(err).await
+        } else if err.get_kind() == ErrorKind::Handler {
+            self.internal_error(&err)
+        } else {
+            ResponseBuilder::new(err.get_status(), "").build()
+        }
+    }
+
+    /// Only real panics are counted, a rejected request body is the client's mistake
+    fn panic_error(&self, e: CaughtPanic) -> HttpError {
+        let message = e.get_message();
+        if e.is_rejection() {
+            HttpError::new(
+                ErrorKind::Rejection,
+                StatusCode::BadRequest,
+                &format!("Invalid request body: {}", message),
+            )
+        } else {
+            self.metrics.record_panic();
+            HttpError::new(ErrorKind::Handler, StatusCode::InternalServerError, &message)
+                .with_backtrace(e.get_backtrace())
+        }
+    }
+
+    /// Backtrace page in debug builds, `application/problem+json` otherwise
+    fn internal_error(&self, err: &HttpError) -> Response {
+        let status = err.get_status();
+        if self.debug {
+            let page = format!(
+                "<!DOCTYPE html><html><head><title>{status}</title></head><body>\
+                 <h1>{status}</h1><p>{} {}</p><p>{}</p><pre>{}</pre></body></html>",
+                err.get_method().map(|m| m.to_string()).unwrap_or_default(),
+                escape_html(&err.get_path().unwrap_or_default()),
+                escape_html(&err.get_message()),
+                escape_html(&err.get_backtrace().unwrap_or_default()),
+            );
+            ResponseBuilder::raw(status, "text/html; charset=utf-8", &page).build()
+        } else {
+            let title = status.to_string();
+            ResponseBuilder::new(
+                status,
+                json!({
+                    "type": "about:blank",
+                    "title": title[4..],
+                    "status": status.as_u16(),
+                }),
+            )
+            .set_header("Content-Type", "application/problem+json")
+            .build()
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::method::Method;
+    use crate::http::request::RequestBuilder;
+    use crate::modules::router::route::Route;
+    use crate::modules::conditional::ETag;
+    use crate::modules::router::Router;
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use std::io::Read;
+
+    type BoxedHandler = Pin<Box<dyn Future<Output = Response> + Send + Sync>>;
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn typed(req: Request) -> BoxedHandler {
+        let count: u32 = req.body_json().unwrap();
+        Box::pin(async move { ResponseBuilder::new(StatusCode::Ok, count).build() })
+    }
+
+    fn broken(_: Request) -> BoxedHandler {
+        Box::pin(async { panic!("handler bug") })
+    }
+
+    fn dispatcher(routes: Vec<Route>) -> Dispatcher {
+        let states = Arc::new(RwLock::new(StateMap::new()));
+        let mut router = Router::new();
+        for mut route in routes {
+            route.resolve_states(&states);
+            router.insert(route.get(), route);
+        }
+        panic_guard::install_hook();
+        Dispatcher::new(VirtualHosts::new(router), states, None, None, None)
+    }
+
+    fn post(path: &str, body: &str) -> Request {
+        RequestBuilder::new(Method::POST, body).set_path(path).build()
+    }
+
+    #[tokio::test]
+    async fn counts_handler_panics_but_not_rejections() {
+        let dispatcher = dispatcher(vec![
+            Route::new(Method::POST, "/typed", typed),
+            Route::new(Method::POST, "/broken", broken),
+        ]);
+        let resp = dispatcher.dispatch(post("/typed", "7")).await;
+        assert_eq!(resp.get_status(), StatusCode::Ok);
+
+        let resp = dispatcher.dispatch(post("/typed", "not json")).await;
+        assert_eq!(resp.get_status(), StatusCode::BadRequest);
+        assert_eq!(dispatcher.get_metrics().get_panics(), 0);
+
+        let resp = dispatcher.dispatch(post("/broken", "")).await;
+        assert_eq!(resp.get_status(), StatusCode::InternalServerError);
+        assert_eq!(dispatcher.get_metrics().get_panics(), 1);
+    }
+
+    fn document(_: Request) -> BoxedHandler {
+        Box::pin(async { ResponseBuilder::new(StatusCode::Ok, "v1").etag("v1").build() })
+    }
+
+    fn update(_: Request) -> BoxedHandler {
+        Box::pin(async { ResponseBuilder::new(StatusCode::NoContent, "").build() })
+    }
+
+    fn request(method: Method, path: &str, headers: &[(&str, &str)]) -> Request {
+        headers
+            .iter()
+            .fold(
+                RequestBuilder::new(method, "").set_path(path),
+                |builder, (name, value)| builder.set_header(name, value),
+            )
+            .build()
+    }
+
+    #[tokio::test]
+    async fn checks_writes_against_the_last_read() {
+        let dispatcher = dispatcher(vec![
+            Route::new(Method::GET, "/doc", document),
+            Route::new(Method::PUT, "/doc", update),
+        ]);
+        let put = |headers: &[(&str, &str)]| request(Method::PUT, "/doc", headers);
+        let status = |resp: Response| resp.get_status();
+
+        // Nothing is known about the document before it is read
+        let resp = dispatcher.dispatch(put(&[("If-Match", "\"v1\"")])).await;
+        assert_eq!(status(resp), StatusCode::PreconditionFailed);
+        let resp = dispatcher.dispatch(put(&[("If-Unmodified-Since", DATE)])).await;
+        assert_eq!(status(resp), StatusCode::PreconditionFailed);
+
+        let resp = dispatcher.dispatch(request(Method::GET, "/doc", &[])).await;
+        assert_eq!(resp.get_header("ETag").as_deref(), Some("\"v1\""));
+        let resp = dispatcher.dispatch(put(&[("If-Match", "\"v2\"")])).await;
+        assert_eq!(status(resp), StatusCode::PreconditionFailed);
+        let resp = dispatcher.dispatch(put(&[("If-Match", "\"v1\"")])).await;
+        assert_eq!(status(resp), StatusCode::NoContent);
+
+        // The write changed the document, so the old tag is no longer trusted
+        let resp = dispatcher.dispatch(put(&[("If-Match", "\"v1\"")])).await;
+        assert_eq!(status(resp), StatusCode::PreconditionFailed);
+        let resp = dispatcher.dispatch(put(&[])).await;
+        assert_eq!(status(resp), StatusCode::NoContent);
+        // `If-Modified-Since` only applies to reads
+        let resp = dispatcher.dispatch(put(&[("If-Modified-Since", DATE)])).await;
+        assert_eq!(status(resp), StatusCode::NoContent);
+    }
+
+    #[tokio::test]
+    async fn prefers_the_route_validator() {
+        let dispatcher = dispatcher(vec![Route::new(Method::PUT, "/doc", update)
+            .validator(|_| async { Some(Validators::new().etag(ETag::strong("v9"))) })]);
+        let resp = dispatcher
+            .dispatch(request(Method::PUT, "/doc", &[("If-Match", "\"v9\"")]))
+            .await;
+        assert_eq!(resp.get_status(), StatusCode::NoContent);
+        let resp = dispatcher
+            .dispatch(request(Method::PUT, "/doc", &[("If-None-Match", "*")]))
+            .await;
+        assert_eq!(resp.get_status(), StatusCode::PreconditionFailed);
+    }
+
+    fn ticker(_: Request) -> BoxedHandler {
+        // Like an event stream, it never ends on its own
+        let ticks = futures_util::stream::iter([Ok(Bytes::from("tick\n"))])
+            .chain(futures_util::stream::pending());
+        Box::pin(async move { ResponseBuilder::stream(StatusCode::Ok, "text/plain", ticks).build() })
+    }
+
+    #[tokio::test]
+    async fn finishes_the_coding_of_a_stream_ended_by_draining() {
+        let (shutdown, drain) = Drain::new();
+        let dispatcher = dispatcher(vec![Route::new(Method::GET, "/ticks", ticker)])
+            .compression(Some(Compression::new()))
+            .drain(drain);
+        let req = request(Method::GET, "/ticks", &[("Accept-Encoding", "gzip")]);
+        let mut resp = dispatcher.dispatch(req).await;
+        assert_eq!(resp.get_header("Content-Encoding").as_deref(), Some("gzip"));
+        let mut body = resp.take_stream().unwrap();
+        let mut compressed = body.next().await.unwrap().unwrap().to_vec();
+
+        shutdown.send(true).unwrap();
+        while let Some(chunk) = body.next().await {
+            compressed.extend_from_slice(&chunk.unwrap());
+        }
+        // A gzip stream without its trailer fails to decode
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "tick\n");
+    }
+
+    #[test]
+    fn caps_decompression_at_the_route_limit() {
+        let dispatcher = dispatcher(vec![
+            Route::new(Method::POST, "/small", typed).max_body_size(100),
+            Route::new(Method::POST, "/large", typed).max_body_size(1 << 20),
+        ])
+        .limits(Limits::new().max_body_size(500).max_decompressed_size(1000));
+        assert_eq!(dispatcher.decompressed_limit(&post("/small", "")), 100);
+        assert_eq!(dispatcher.decompressed_limit(&post("/large", "")), 1000);
+        assert_eq!(dispatcher.decompressed_limit(&post("/other", "")), 500);
+    }
+}
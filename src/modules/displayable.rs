@@ -8,6 +8,9 @@ where
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut out = String::new();
+        if self.0.is_empty() {
+            return write!(f, "{out}");
+        }
         for i in 0..self.0.len() - 1 {
             out.push_str(
                 self.0[i]
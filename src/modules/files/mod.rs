@@ -0,0 +1,466 @@
+pub mod mime;
+
+use crate::http::request::Request;
+use crate::http::response::{BodyStream, Response, ResponseBuilder};
+use crate::http::status::StatusCode;
+use crate::modules::compression::{self, Compression, Encoding};
+use crate::modules::conditional::{self, ETag};
+use crate::modules::range::{self, RangeRequest, Ranges};
+use crate::not_found;
+use bytes::BytesMut;
+use std::fs::Metadata;
+use std::io::{self, SeekFrom};
+use std::time::UNIX_EPOCH;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+/// Size of the reads a file is streamed with
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serves the files of a directory under a URL prefix, mounted with `Route::serve_dir`
+#[derive(Debug, Clone)]
+pub struct ServeDir {
+    root: PathBuf,
+    index: Option<String>,
+    spa_fallback: Option<String>,
+    precompressed: bool,
+    follow_symlinks: bool,
+    hidden: bool,
+}
+
+impl ServeDir {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            index: Some("index.html".to_string()),
+            spa_fallback: None,
+            precompressed: false,
+            follow_symlinks: false,
+            hidden: false,
+        }
+    }
+
+    /// File served for a directory, `index.html` by default. `None` answers directories with 404
+    pub fn index(mut self, file: Option<&str>) -> Self {
+        self.index = file.map(str::to_string);
+        self
+    }
+
+    /// File under the root sent for missing pages, so a single-page app can
+    /// do its own routing. Only requests accepting HTML get it
+    pub fn spa_fallback(mut self, file: &str) -> Self {
+        self.spa_fallback = Some(file.trim_start_matches('/').to_string());
+        self
+    }
+
+    /// Sends `file.br` or `file.gz` next to a file instead of it when the client accepts the coding
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Serves files that symlinks under the root point to outside of it.
+    /// Off by default, such files are answered with 404
+    pub fn follow_symlinks(mut self, enabled: bool) -> Self {
+        self.follow_symlinks = enabled;
+        self
+    }
+
+    /// Serves files and directories whose name starts with a dot, such as
+    /// `.git` or `.env`. Off by default, they are answered with 404
+    pub fn hidden(mut self, enabled: bool) -> Self {
+        self.hidden = enabled;
+        self
+    }
+
+    /// Canonical root the served files must be under, `None` when symlinks may lead anywhere
+    async fn confinement(&self) -> io::Result<Option<PathBuf>> {
+        if self.follow_symlinks {
+            return Ok(None);
+        }
+        tokio::fs::canonicalize(&self.root).await.map(Some)
+    }
+
+    pub(crate) async fn serve(&self, req: &Request, mount: &str) -> Response {
+        let Ok(confinement) = self.confinement().await else {
+            return not_found!();
+        };
+        let root = confinement.as_deref();
+        let path = req.get_path();
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let relative = path.strip_prefix(mount).unwrap_or(path);
+        let Some(mut file) = resolve(&self.root, relative, self.hidden) else {
+            return not_found!();
+        };
+        match tokio::fs::metadata(&file).await {
+            Ok(metadata) if metadata.is_dir() => {
+                // Relative links in the index page need the trailing slash
+                if !path.ends_with('/') {
+                    let location = match req.get_path().split_once('?') {
+                        Some((_, query)) => format!("{}/?{}", path, query),
+                        None => format!("{}/", path),
+                    };
+                    return ResponseBuilder::raw(StatusCode::MovedPermanently, "text/plain", "")
+                        .set_header("Location", &location)
+                        .build();
+                }
+                match &self.index {
+                    Some(index) => file.push(index),
+                    None => return not_found!(),
+                }
+            }
+            Ok(_) => {}
+            Err(_) => return self.fallback(req, root).await,
+        }
+        match send_file(&file, req, self.precompressed, root).await {
+            Some(resp) => resp,
+            None => self.fallback(req, root).await,
+        }
+    }
+
+    async fn fallback(&self, req: &Request, root: Option<&Path>) -> Response {
+        let accepts_html = req
+            .get_header("Accept")
+            .is_some_and(|accept| accept.contains("text/html"));
+        match &self.spa_fallback {
+            Some(file) if accepts_html => {
+                send_file(&self.root.join(file), req, self.precompressed, root)
+                    .await
+                    .unwrap_or_else(|| not_found!())
+            }
+            _ => not_found!(),
+        }
+    }
+}
+
+/// Serves one file, mounted with `Route::serve_file`
+#[derive(Debug, Clone)]
+pub struct ServeFile {
+    path: PathBuf,
+    precompressed: bool,
+}
+
+impl ServeFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            precompressed: false,
+        }
+    }
+
+    /// Sends `file.br` or `file.gz` instead when the client accepts the coding
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    pub(crate) async fn serve(&self, req: &Request) -> Response {
+        send_file(&self.path, req, self.precompressed, None)
+            .await
+            .unwrap_or_else(|| not_found!())
+    }
+}
+
+/// Maps a URL path onto a file under `root`. `None` for paths that would
+/// leave it, go through a hidden name unless `hidden` or can't name a file
+fn resolve(root: &Path, path: &str, hidden: bool) -> Option<PathBuf> {
+    let decoded = percent_decode(path)?;
+    let mut file = root.to_path_buf();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.starts_with('.') && !hidden => return None,
+            segment if segment.contains(['\\', '\0']) => return None,
+            #[cfg(windows)]
+            segment if segment.contains(':') => return None,
+            segment => file.push(segment),
+        }
+    }
+    Some(file)
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Streams a regular file, `None` when there is none at `path` under `root`
+async fn send_file(
+    path: &Path,
+    req: &Request,
+    precompressed: bool,
+    root: Option<&Path>,
+) -> Option<Response> {
+    let content_type = mime::from_path(path);
+    let mut encoding = None;
+    let mut opened = None;
+    if precompressed {
+        if let Some(accept_encoding) = req.get_header("Accept-Encoding") {
+            opened = open_precompressed(path, &accept_encoding, root).await;
+            encoding = opened.as_ref().map(|(_, _, encoding)| *encoding);
+        }
+    }
+    let (mut file, metadata) = match opened {
+        Some((file, metadata, _)) => (file, metadata),
+        None => open(path, root).await?,
+    };
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    // Changes whenever the file is rewritten, without reading it
+    let etag = modified.map(|modified| {
+        let secs = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        format!("{:x}-{:x}", secs, len)
+    });
+
+    // A single range is read from where it starts, the dispatcher cuts out the others
+    let mut range = None;
+    if let Some(request) = RangeRequest::from_request(req) {
+        let etag = etag.as_ref().map(|etag| ETag::strong(etag).to_string());
+        let last_modified = modified.map(conditional::format_http_date);
+        match request.ranges(len, etag.as_deref(), last_modified.as_deref()) {
+            Ranges::Partial(ranges) if ranges.len() == 1 => range = Some(ranges[0]),
+            Ranges::Unsatisfiable => return Some(range::unsatisfiable(len)),
+            _ => {}
+        }
+    }
+    let mut builder = match range {
+        Some(range) => {
+            file.seek(SeekFrom::Start(range.get_start())).await.ok()?;
+            ResponseBuilder::stream(
+                StatusCode::PartialContent,
+                content_type,
+                file_stream(file.take(range.len())),
+            )
+            .set_header("Content-Length", &range.len().to_string())
+            .set_header("Content-Range", &range.content_range(len))
+        }
+        None => ResponseBuilder::stream(StatusCode::Ok, content_type, file_stream(file))
+            .set_header("Content-Length", &len.to_string()),
+    };
+    builder = builder.set_header("Accept-Ranges", "bytes");
+    if let (Some(etag), Some(modified)) = (&etag, modified) {
+        builder = builder.etag(etag).last_modified(modified);
+    }
+    // Whether or not this one is, other responses for the file may be compressed
+    if precompressed || compression::is_compressible_type(content_type) {
+        builder = builder.set_header("Vary", "Accept-Encoding");
+    }
+    if let Some(encoding) = encoding {
+        builder = builder.set_header("Content-Encoding", encoding.as_str());
+    }
+    Some(builder.build())
+}
+
+/// Sibling compressed with the best coding the client accepts
+async fn open_precompressed(
+    path: &Path,
+    accept_encoding: &str,
+    root: Option<&Path>,
+) -> Option<(File, Metadata, Encoding)> {
+    let mut candidates = vec![Encoding::Brotli, Encoding::Gzip];
+    while let Some(encoding) = Compression::new()
+        .encodings(&candidates)
+        .negotiate(accept_encoding)
+    {
+        let extension = match encoding {
+            Encoding::Brotli => "br",
+            _ => "gz",
+        };
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(extension);
+        if let Some((file, metadata)) = open(Path::new(&sibling), root).await {
+            return Some((file, metadata, encoding));
+        }
+        candidates.retain(|candidate| *candidate != encoding);
+    }
+    None
+}
+
+/// Opens a regular file. With a `root`, symlinks are only followed as long as they stay under it
+async fn open(path: &Path, root: Option<&Path>) -> Option<(File, Metadata)> {
+    let path = match root {
+        Some(root) => {
+            let canonical = tokio::fs::canonicalize(path).await.ok()?;
+            canonical.starts_with(root).then_some(canonical)?
+        }
+        None => path.to_path_buf(),
+    };
+    let file = File::open(path).await.ok()?;
+    let metadata = file.metadata().await.ok()?;
+    metadata.is_file().then_some((file, metadata))
+}
+
+fn file_stream<R: AsyncRead + Unpin + Send + 'static>(file: R) -> BodyStream {
+    Box::pin(futures_util::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+        match file.read_buf(&mut buf).await {
+            Ok(0) => None,
+            Ok(_) => Some((Ok(buf.freeze()), Some(file))),
+            Err(e) => Some((Err::<_, io::Error>(e), None)),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::method::Method;
+    use crate::http::request::RequestBuilder;
+
+    /// Fresh directory under the system temp dir, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("nutt-files-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn get(dir: &ServeDir, path: &str) -> StatusCode {
+        let req = RequestBuilder::new(Method::GET, "").set_path(path).build();
+        dir.serve(&req, "/static").await.get_status()
+    }
+
+    #[test]
+    fn resolves_paths_under_the_root() {
+        let resolve = |path| resolve(Path::new("/srv"), path, false);
+        assert_eq!(resolve("/css/site.css"), Some(PathBuf::from("/srv/css/site.css")));
+        assert_eq!(resolve("//a/./b/"), Some(PathBuf::from("/srv/a/b")));
+        assert_eq!(resolve("/my%20file.txt"), Some(PathBuf::from("/srv/my file.txt")));
+        assert_eq!(resolve(""), Some(PathBuf::from("/srv")));
+    }
+
+    #[test]
+    fn refuses_paths_leaving_the_root() {
+        let resolve = |path| resolve(Path::new("/srv"), path, false);
+        for path in [
+            "/../etc/passwd",
+            "/a/../../etc/passwd",
+            "/%2e%2e/etc/passwd",
+            "/%2E%2E%2Fetc%2Fpasswd",
+            "/a/..%2f..%2fetc",
+            "/..\\etc\\passwd",
+            "/a%5c..%5c..%5cetc",
+            "/file%00.txt",
+        ] {
+            assert_eq!(resolve(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn refuses_hidden_names_unless_enabled() {
+        let root = Path::new("/srv");
+        for path in ["/.env", "/.git/config", "/a/.secret/b", "/%2egit/HEAD"] {
+            assert_eq!(resolve(root, path, false), None, "{}", path);
+        }
+        assert_eq!(resolve(root, "/a.b/c.txt", false), Some(PathBuf::from("/srv/a.b/c.txt")));
+        let well_known = Some(PathBuf::from("/srv/.well-known/security.txt"));
+        assert_eq!(resolve(root, "/.well-known/security.txt", true), well_known);
+        assert_eq!(resolve(root, "/../etc/passwd", true), None);
+    }
+
+    #[test]
+    fn refuses_malformed_escapes() {
+        assert_eq!(percent_decode("/a%2"), None);
+        assert_eq!(percent_decode("/a%zz"), None);
+        assert_eq!(percent_decode("/%ff%fe"), None);
+        assert_eq!(percent_decode("/caf%C3%A9").as_deref(), Some("/café"));
+    }
+
+    #[tokio::test]
+    async fn serves_files_and_hides_the_rest() {
+        let tmp = TempDir::new("serve");
+        let root = tmp.0.join("public");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/index.html"), "<p>docs</p>").unwrap();
+        std::fs::write(tmp.0.join("secret.txt"), "secret").unwrap();
+        let dir = ServeDir::new(&root);
+
+        assert_eq!(get(&dir, "/static/docs/index.html").await, StatusCode::Ok);
+        assert_eq!(get(&dir, "/static/docs/").await, StatusCode::Ok);
+        assert_eq!(get(&dir, "/static/docs").await, StatusCode::MovedPermanently);
+        assert_eq!(get(&dir, "/static/../secret.txt").await, StatusCode::NotFound);
+        assert_eq!(get(&dir, "/static/%2e%2e/secret.txt").await, StatusCode::NotFound);
+        assert_eq!(get(&dir, "/static/missing.txt").await, StatusCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn hides_dot_files_by_default() {
+        let tmp = TempDir::new("hidden");
+        std::fs::create_dir_all(tmp.0.join(".git")).unwrap();
+        std::fs::write(tmp.0.join(".git/config"), "[core]").unwrap();
+        std::fs::write(tmp.0.join(".env"), "TOKEN=secret").unwrap();
+        let dir = ServeDir::new(&tmp.0);
+
+        assert_eq!(get(&dir, "/static/.env").await, StatusCode::NotFound);
+        assert_eq!(get(&dir, "/static/.git/config").await, StatusCode::NotFound);
+        let dir = dir.hidden(true);
+        assert_eq!(get(&dir, "/static/.env").await, StatusCode::Ok);
+        assert_eq!(get(&dir, "/static/.git/config").await, StatusCode::Ok);
+    }
+
+    #[tokio::test]
+    async fn varies_on_accept_encoding_when_compressible() {
+        let tmp = TempDir::new("vary");
+        std::fs::write(tmp.0.join("page.html"), "<p>page</p>").unwrap();
+        std::fs::write(tmp.0.join("photo.png"), [0x89, b'P', b'N', b'G']).unwrap();
+        let dir = ServeDir::new(&tmp.0);
+        let vary = |path: &str| {
+            let req = RequestBuilder::new(Method::GET, "").set_path(path).build();
+            let dir = dir.clone();
+            async move { dir.serve(&req, "/static").await.get_header("Vary") }
+        };
+        assert_eq!(vary("/static/page.html").await.as_deref(), Some("Accept-Encoding"));
+        assert_eq!(vary("/static/photo.png").await, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn keeps_symlinks_under_the_root() {
+        let tmp = TempDir::new("symlinks");
+        let root = tmp.0.join("public");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("inside.txt"), "inside").unwrap();
+        std::fs::write(tmp.0.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(root.join("inside.txt"), root.join("alias.txt")).unwrap();
+        std::os::unix::fs::symlink(tmp.0.join("secret.txt"), root.join("escape.txt")).unwrap();
+        std::os::unix::fs::symlink(&tmp.0, root.join("parent")).unwrap();
+
+        let dir = ServeDir::new(&root);
+        assert_eq!(get(&dir, "/static/alias.txt").await, StatusCode::Ok);
+        assert_eq!(get(&dir, "/static/escape.txt").await, StatusCode::NotFound);
+        assert_eq!(get(&dir, "/static/parent/secret.txt").await, StatusCode::NotFound);
+
+        let dir = dir.follow_symlinks(true);
+        assert_eq!(get(&dir, "/static/escape.txt").await, StatusCode::Ok);
+        assert_eq!(get(&dir, "/static/parent/secret.txt").await, StatusCode::Ok);
+    }
+}
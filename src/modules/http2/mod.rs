@@ -0,0 +1,449 @@
+use crate::http::error::{ErrorKind, HttpError};
+use crate::http::method::Method;
+use crate::http::request::RequestBuilder;
+use crate::http::response::Response;
+use crate::http::status::StatusCode;
+use crate::modules::compression;
+use crate::modules::dispatcher::Dispatcher;
+use crate::modules::shutdown::Drain;
+use crate::modules::tls::PeerCertificate;
+use anyhow::Result;
+use bytes::Bytes;
+use futures_util::future::poll_fn;
+use futures_util::StreamExt;
+use h2::server::SendResponse;
+use h2::SendStream;
+use h2::RecvStream;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing_log::log::{log, Level};
+
+/// Connection preface a client sends when it starts HTTP/2 without upgrading
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// HTTP/2 settings. Negotiated with ALPN over TLS, and with prior
+/// knowledge (h2c) over plain TCP when enabled
+#[derive(Debug, Clone)]
+pub struct Http2Config {
+    max_concurrent_streams: u32,
+    initial_window_size: u32,
+    initial_connection_window_size: u32,
+    max_frame_size: u32,
+    max_header_list_size: u32,
+    prior_knowledge: bool,
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Http2Config {
+    pub fn new() -> Self {
+        Self {
+            max_concurrent_streams: 100,
+            initial_window_size: 65_535,
+            initial_connection_window_size: 1024 * 1024,
+            max_frame_size: 16_384,
+            max_header_list_size: 16 * 1024,
+            prior_knowledge: true,
+        }
+    }
+
+    /// Streams a client may have open at once on one connection
+    pub fn max_concurrent_streams(mut self, max: u32) -> Self {
+        self.max_concurrent_streams = max;
+        self
+    }
+
+    /// Flow control window of every stream
+    pub fn initial_window_size(mut self, size: u32) -> Self {
+        self.initial_window_size = size;
+        self
+    }
+
+    /// Flow control window shared by all streams of a connection
+    pub fn initial_connection_window_size(mut self, size: u32) -> Self {
+        self.initial_connection_window_size = size;
+        self
+    }
+
+    pub fn max_frame_size(mut self, size: u32) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// Limit for the decoded (HPACK) header block of a request
+    pub fn max_header_list_size(mut self, size: u32) -> Self {
+        self.max_header_list_size = size;
+        self
+    }
+
+    /// Accept h2c over plain TCP from clients that skip the upgrade
+    pub fn prior_knowledge(mut self, enabled: bool) -> Self {
+        self.prior_knowledge = enabled;
+        self
+    }
+
+    pub(crate) fn is_prior_knowledge(&self) -> bool {
+        self.prior_knowledge
+    }
+}
+
+/// Reads until the bytes either complete the HTTP/2 connection preface or
+/// differ from it. `buf` keeps what was read, to replay with `Rewind`
+pub(crate) async fn read_preface<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    buf: &mut Vec<u8>,
+) -> io::Result<bool> {
+    let mut chunk = [0; PREFACE.len()];
+    while buf.len() < PREFACE.len() {
+        let n = stream.read(&mut chunk[..PREFACE.len() - buf.len()]).await?;
+        if n == 0 {
+            return Ok(false);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if !PREFACE.starts_with(buf) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Stream whose first bytes were already read, they are read again before the rest
+pub(crate) struct Rewind<T> {
+    prefix: Bytes,
+    inner: T,
+}
+
+impl<T> Rewind<T> {
+    pub fn new(prefix: Vec<u8>, inner: T) -> Self {
+        Self {
+            prefix: Bytes::from(prefix),
+            inner,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            let prefix = self.prefix.split_to(n);
+            buf.put_slice(&prefix);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Serves one HTTP/2 connection, every stream is dispatched on its own task.
+/// When the server drains, a GOAWAY is sent and open streams may finish
+pub(crate) async fn serve<T: AsyncRead + AsyncWrite + Unpin>(
+    io: T,
+    dispatcher: Arc<Dispatcher>,
+    config: &Http2Config,
+    peer_certificate: Option<PeerCertificate>,
+    mut drain: Drain,
+) -> Result<()> {
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(config.max_concurrent_streams)
+        .initial_window_size(config.initial_window_size)
+        .initial_connection_window_size(config.initial_connection_window_size)
+        .max_frame_size(config.max_frame_size)
+        .max_header_list_size(config.max_header_list_size)
+        .handshake(io);
+    let Ok(connection) = timeout(dispatcher.get_timeouts().get_handshake(), handshake).await
+    else {
+        return Ok(());
+    };
+    let mut connection = connection?;
+    let mut going_away = false;
+    let mut streams = JoinSet::new();
+    loop {
+        let next = tokio::select! {
+            next = connection.accept() => next,
+            Some(_) = streams.join_next(), if !streams.is_empty() => continue,
+            _ = drain.wait(), if !going_away => {
+                connection.graceful_shutdown();
+                going_away = true;
+                continue;
+            }
+        };
+        let Some(result) = next else {
+            break;
+        };
+        let (req, respond) = result?;
+        let dispatcher = dispatcher.clone();
+        let peer_certificate = peer_certificate.clone();
+        let in_flight = drain.request();
+        streams.spawn(async move {
+            if let Err(e) = handle_stream(req, respond, dispatcher, peer_certificate).await {
+                log!(Level::Error, "Error handling HTTP/2 stream: {}", e);
+            }
+            drop(in_flight);
+        });
+    }
+    while streams.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn handle_stream(
+    req: ::http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    dispatcher: Arc<Dispatcher>,
+    peer_certificate: Option<PeerCertificate>,
+) -> Result<()> {
+    let (parts, mut body) = req.into_parts();
+    let mut headers = vec![];
+    if let Some(authority) = parts.uri.authority() {
+        headers.push(("Host".to_string(), authority.to_string()));
+    }
+    for (name, value) in &parts.headers {
+        if let Ok(value) = value.to_str() {
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or("/".to_string(), |path| path.to_string());
+    log!(
+        Level::Info,
+        "HTTP/2 Request Method: {}, Path: {}",
+        parts.method,
+        path
+    );
+    if path.len() > dispatcher.get_limits().get_max_request_line() {
+        let err = HttpError::new(ErrorKind::Limit, StatusCode::UriTooLong, "Request path is too long");
+        return send_error(&mut respond, &dispatcher, err).await;
+    }
+
+    let request = parts
+        .method
+        .as_str()
+        .parse::<Method>()
+        .and_then(|method| RequestBuilder::from_parts(method, &path, &headers));
+    let mut req = match request {
+        Ok(builder) => builder.build(),
+        Err(e) => {
+            let err = HttpError::new(ErrorKind::Parse, StatusCode::BadRequest, &e.to_string());
+            return send_error(&mut respond, &dispatcher, err).await;
+        }
+    };
+
+    // Routing happens before the body is read, so the route's limit applies
+    let limit = dispatcher.body_limit(&req);
+    let too_large = HttpError::new(
+        ErrorKind::Limit,
+        StatusCode::PayloadTooLarge,
+        "Request body is too large",
+    );
+    let announced = req
+        .get_header("Content-Length")
+        .and_then(|value| value.parse::<usize>().ok());
+    if announced.is_some_and(|length| length > limit) {
+        return send_error(&mut respond, &dispatcher, too_large).await;
+    }
+    let encodings = match req.get_header("Content-Encoding") {
+        Some(header) => match compression::parse_content_encoding(&header) {
+            Ok(encodings) => encodings,
+            Err(err) => return send_error(&mut respond, &dispatcher, err).await,
+        },
+        None => Vec::new(),
+    };
+    match req.get_header("Expect") {
+        Some(expect) if expect.eq_ignore_ascii_case("100-continue") => {
+            req = match dispatcher.preflight(req).await {
+                Ok(req) => req,
+                Err(resp) => {
+                    let write = dispatcher.get_timeouts().get_write();
+                    return send_response(&mut respond, resp, write).await;
+                }
+            };
+            let continue_ = ::http::Response::builder().status(100).body(())?;
+            respond.send_informational(continue_)?;
+        }
+        Some(_) => {
+            let err = HttpError::new(
+                ErrorKind::Parse,
+                StatusCode::ExpectationFailed,
+                "Only the 100-continue expectation is supported",
+            );
+            return send_error(&mut respond, &dispatcher, err).await;
+        }
+        None => {}
+    }
+    let mut content = vec![];
+    let read = timeout(dispatcher.get_timeouts().get_body_read(), async {
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            // Hand the window back to the client once the data is consumed
+            body.flow_control().release_capacity(chunk.len())?;
+            if content.len() + chunk.len() > limit {
+                return Ok(Some(too_large));
+            }
+            content.extend_from_slice(&chunk);
+        }
+        anyhow::Ok(None)
+    })
+    .await;
+    let failed = match read {
+        Ok(read) => read?,
+        Err(_) => Some(HttpError::new(
+            ErrorKind::Timeout,
+            StatusCode::RequestTimeout,
+            "Timed out reading the request body",
+        )),
+    };
+    if let Some(err) = failed {
+        return send_error(&mut respond, &dispatcher, err).await;
+    }
+
+    let mut content = Bytes::from(content);
+    if !encodings.is_empty() && !content.is_empty() {
+        let max_size = dispatcher.decompressed_limit(&req);
+        let decoded = compression::decompress_request(&mut req, content, encodings, max_size).await;
+        content = match decoded {
+            Ok(content) => content,
+            Err(err) => return send_error(&mut respond, &dispatcher, err).await,
+        };
+    }
+    req.set_body(content);
+    req.set_peer_certificate(peer_certificate);
+    let mut resp = dispatcher.dispatch(req).await;
+    // A stream can't switch protocols, RFC 9113 section 8.6
+    if resp.take_upgrade().is_some() {
+        let err = HttpError::new(
+            ErrorKind::Handler,
+            StatusCode::UpgradeRequired,
+            "Protocol upgrades need HTTP/1.1",
+        );
+        return send_error(&mut respond, &dispatcher, err).await;
+    }
+    send_response(&mut respond, resp, dispatcher.get_timeouts().get_write()).await
+}
+
+async fn send_error(
+    respond: &mut SendResponse<Bytes>,
+    dispatcher: &Dispatcher,
+    err: HttpError,
+) -> Result<()> {
+    let resp = dispatcher.error(err).await;
+    send_response(respond, resp, dispatcher.get_timeouts().get_write()).await
+}
+
+async fn send_response(
+    respond: &mut SendResponse<Bytes>,
+    mut resp: Response,
+    write: Duration,
+) -> Result<()> {
+    let mut builder = ::http::Response::builder().status(resp.get_status().as_u16());
+    for (name, value) in resp.get_headers() {
+        // Connection specific headers are not allowed in HTTP/2
+        if name.eq_ignore_ascii_case("Connection") || name.eq_ignore_ascii_case("Keep-Alive") {
+            continue;
+        }
+        builder = builder.header(name.to_ascii_lowercase(), value);
+    }
+    let stream = resp.take_stream();
+    let body = resp.get_body_bytes();
+    let end = stream.is_none();
+    let mut send = respond.send_response(builder.body(())?, body.is_empty() && end)?;
+    if !body.is_empty() && !send_data(&mut send, body, end, write).await? {
+        return Ok(());
+    }
+    let Some(mut stream) = stream else {
+        return Ok(());
+    };
+    loop {
+        let chunk = match stream.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => {
+                send.send_reset(h2::Reason::INTERNAL_ERROR);
+                return Err(e.into());
+            }
+            None => {
+                send.send_data(Bytes::new(), true)?;
+                return Ok(());
+            }
+        };
+        if !chunk.is_empty() && !send_data(&mut send, chunk, false, write).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends as the client's flow control window allows. `false` when the client
+/// cancelled the stream or stopped reading
+async fn send_data(
+    send: &mut SendStream<Bytes>,
+    mut data: Bytes,
+    end: bool,
+    write: Duration,
+) -> Result<bool> {
+    let sent = timeout(write, async {
+        while !data.is_empty() {
+            // Only send what the client's flow control window allows
+            send.reserve_capacity(data.len());
+            let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                // The client cancelled the stream
+                None => return Ok(false),
+            };
+            let chunk = data.split_to(capacity.min(data.len()));
+            send.send_data(chunk, end && data.is_empty())?;
+        }
+        Ok(true)
+    })
+    .await;
+    match sent {
+        Ok(sent) => sent,
+        Err(_) => {
+            // The client stopped reading, give up on this stream
+            send.send_reset(h2::Reason::CANCEL);
+            Ok(false)
+        }
+    }
+}
@@ -0,0 +1,90 @@
+/// Size limits checked while a request is read, before anything is allocated for it
+#[derive(Debug, Clone)]
+pub struct Limits {
+    max_request_line: usize,
+    max_headers: usize,
+    max_header_size: usize,
+    max_body_size: usize,
+    max_decompressed_size: usize,
+    max_connections: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_size: 16 * 1024,
+            max_body_size: 2 * 1024 * 1024,
+            max_decompressed_size: 16 * 1024 * 1024,
+            max_connections: None,
+        }
+    }
+
+    /// Method, target and version together, answered with 414
+    pub fn max_request_line(mut self, bytes: usize) -> Self {
+        self.max_request_line = bytes;
+        self
+    }
+
+    /// Number of header fields, answered with 431
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.max_headers = count;
+        self
+    }
+
+    /// All header fields together, answered with 431
+    pub fn max_header_size(mut self, bytes: usize) -> Self {
+        self.max_header_size = bytes;
+        self
+    }
+
+    /// Default body limit, answered with 413. Routes and routers can override it
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.max_body_size = bytes;
+        self
+    }
+
+    /// Body sent with a `Content-Encoding` once decoded, answered with 413
+    pub fn max_decompressed_size(mut self, bytes: usize) -> Self {
+        self.max_decompressed_size = bytes;
+        self
+    }
+
+    /// Open connections across all listeners. At the limit the server stops
+    /// accepting until one closes, so new clients wait in the listen backlog
+    pub fn max_connections(mut self, count: usize) -> Self {
+        self.max_connections = Some(count);
+        self
+    }
+
+    pub(crate) fn get_max_request_line(&self) -> usize {
+        self.max_request_line
+    }
+
+    pub(crate) fn get_max_headers(&self) -> usize {
+        self.max_headers
+    }
+
+    pub(crate) fn get_max_header_size(&self) -> usize {
+        self.max_header_size
+    }
+
+    pub(crate) fn get_max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    pub(crate) fn get_max_decompressed_size(&self) -> usize {
+        self.max_decompressed_size
+    }
+
+    pub(crate) fn get_max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::Mutex;
+#[cfg(unix)]
+use tracing_log::log::{log, Level};
+
+/// First file descriptor passed by the service manager
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// A larger `LISTEN_FDS` is taken as garbage rather than a socket count
+#[cfg(unix)]
+const MAX_LISTEN_FDS: i32 = 64;
+
+/// Listening socket inherited from the process that started the server
+#[derive(Debug)]
+pub(crate) enum Inherited {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Inherited socket with its name from `LISTEN_FDNAMES`
+type NamedSocket = (Option<String>, Inherited);
+
+/// Sockets not claimed yet, read from the environment on first use
+static SOCKETS: Mutex<Option<Vec<NamedSocket>>> = Mutex::new(None);
+
+/// Takes the inherited socket named `name` through `LISTEN_FDNAMES`
+pub(crate) fn take_named(name: &str) -> Option<Inherited> {
+    let mut sockets = SOCKETS.lock().unwrap();
+    let sockets = sockets.get_or_insert_with(from_env);
+    let position = sockets
+        .iter()
+        .position(|(fd_name, _)| fd_name.as_deref() == Some(name))?;
+    Some(sockets.remove(position).1)
+}
+
+/// Takes every inherited socket not claimed by name
+pub(crate) fn take_all() -> Vec<Inherited> {
+    let mut sockets = SOCKETS.lock().unwrap();
+    let sockets = sockets.get_or_insert_with(from_env);
+    sockets.drain(..).map(|(_, socket)| socket).collect()
+}
+
+/// Follows the `sd_listen_fds` convention: `LISTEN_PID` must be this process,
+/// `LISTEN_FDS` sockets start at fd 3, `LISTEN_FDNAMES` is colon separated.
+/// The variables are left alone, child processes have another pid and ignore them
+#[cfg(unix)]
+fn from_env() -> Vec<NamedSocket> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let count = std::env::var("LISTEN_FDS").ok();
+    let names = std::env::var("LISTEN_FDNAMES").ok();
+
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return vec![];
+    }
+    let count = match count.map(|count| count.parse::<i32>()) {
+        Some(Ok(count)) if (0..=MAX_LISTEN_FDS).contains(&count) => count,
+        Some(_) => {
+            log!(Level::Warn, "Ignoring invalid LISTEN_FDS");
+            return vec![];
+        }
+        None => return vec![],
+    };
+    let names = names
+        .map(|names| names.split(':').map(str::to_string).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    (0..count)
+        .filter_map(|i| {
+            let fd = LISTEN_FDS_START + i;
+            if !is_listening_socket(fd) {
+                log!(Level::Warn, "Ignoring inherited fd {}, it is not a listening socket", fd);
+                return None;
+            }
+            // Inherited without FD_CLOEXEC, processes the server starts must not keep it open
+            if !set_cloexec(fd) {
+                log!(Level::Warn, "Could not set FD_CLOEXEC on inherited fd {}", fd);
+            }
+            // SAFETY: the service manager hands these descriptors over to this
+            // process, and `SOCKETS` is only filled once so they are adopted once
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            let socket = if listener.local_addr().is_ok() {
+                Inherited::Tcp(listener)
+            } else {
+                // Not an inet socket
+                let fd = listener.into_raw_fd();
+                Inherited::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
+            };
+            Some((names.get(i as usize).cloned(), socket))
+        })
+        .collect()
+}
+
+/// Whether `fd` is an open socket in the listening state
+#[cfg(unix)]
+fn is_listening_socket(fd: i32) -> bool {
+    // SAFETY: both calls only write to the buffers passed, and fail on a closed fd
+    unsafe {
+        let mut stat = std::mem::zeroed::<libc::stat>();
+        if libc::fstat(fd, &mut stat) != 0 || stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+            return false;
+        }
+        let mut accepting: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut accepting as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        ) == 0
+            && accepting != 0
+    }
+}
+
+#[cfg(unix)]
+fn set_cloexec(fd: i32) -> bool {
+    // SAFETY: reading and setting the descriptor flags has no other effect
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        flags >= 0 && libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) == 0
+    }
+}
+
+#[cfg(not(unix))]
+fn from_env() -> Vec<NamedSocket> {
+    vec![]
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn marks_adopted_sockets_close_on_exec() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = listener.as_raw_fd();
+        // As a service manager passes it
+        let flags = || unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        unsafe { libc::fcntl(fd, libc::F_SETFD, flags() & !libc::FD_CLOEXEC) };
+        assert_eq!(flags() & libc::FD_CLOEXEC, 0);
+
+        assert!(is_listening_socket(fd));
+        assert!(set_cloexec(fd));
+        assert_ne!(flags() & libc::FD_CLOEXEC, 0);
+    }
+}
@@ -0,0 +1,276 @@
+mod activation;
+
+use crate::modules::http2::Http2Config;
+use crate::modules::router::host::HostPattern;
+use crate::modules::tls::{CertResolver, ClientAuth};
+use anyhow::{Context, Result};
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Debug)]
+enum Address {
+    Tcp(String, u16),
+    Std(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Inherited(activation::Inherited),
+}
+
+/// Socket the server accepts connections on. Every listener has its own
+/// TLS and HTTP/2 settings, while all of them serve the same routes
+#[derive(Debug)]
+pub struct Listener {
+    address: Address,
+    fd_name: Option<String>,
+    tls_certs: CertResolver,
+    client_auth: Option<ClientAuth>,
+    http2: Option<Http2Config>,
+}
+
+impl Listener {
+    fn new(address: Address) -> Self {
+        Self {
+            address,
+            fd_name: None,
+            tls_certs: CertResolver::new(),
+            client_auth: None,
+            http2: None,
+        }
+    }
+
+    pub fn tcp(address: (&str, u16)) -> Self {
+        Self::new(Address::Tcp(address.0.to_string(), address.1))
+    }
+
+    /// Listener bound by the caller
+    pub fn from_std(listener: std::net::TcpListener) -> Self {
+        Self::new(Address::Std(listener))
+    }
+
+    /// Unix domain socket at `path`. A stale socket file left by a previous
+    /// run is replaced, and the file is removed when the server stops.
+    /// HTTP/2 is only available through ALPN here, h2c needs TCP
+    #[cfg(unix)]
+    pub fn unix(path: &str) -> Self {
+        Self::new(Address::Unix(PathBuf::from(path)))
+    }
+
+    /// Adopts the socket the service manager passed under this name
+    /// (`FileDescriptorName=` in systemd) instead of binding the address
+    pub fn fd_name(mut self, name: &str) -> Self {
+        self.fd_name = Some(name.to_string());
+        self
+    }
+
+    /// Sockets passed through `LISTEN_FDS` and not claimed by `fd_name`,
+    /// they replace the `bind_dev`/`bind_release` address
+    pub(crate) fn inherited() -> Vec<Self> {
+        activation::take_all()
+            .into_iter()
+            .map(|socket| Self::new(Address::Inherited(socket)))
+            .collect()
+    }
+
+    /// Default certificate, used when no certificate matches the SNI name
+    pub fn set_tls_certs(self, certs: (&str, &str)) -> Self {
+        self.tls_certs.add(None, certs.0, certs.1);
+        self
+    }
+
+    /// Certificate for the SNI names matching `pattern`
+    pub fn set_host_tls_certs(self, pattern: &str, certs: (&str, &str)) -> Self {
+        self.tls_certs
+            .add(Some(HostPattern::parse(pattern)), certs.0, certs.1);
+        self
+    }
+
+    /// Uses the certificates of another listener or of `NuttServer::cert_resolver`
+    pub fn with_cert_resolver(mut self, resolver: CertResolver) -> Self {
+        self.tls_certs = resolver;
+        self
+    }
+
+    /// Verifies client certificates against a CA bundle (mutual TLS)
+    pub fn client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = Some(client_auth);
+        self
+    }
+
+    /// Enables HTTP/2 next to HTTP/1.1
+    pub fn http2(mut self, config: Http2Config) -> Self {
+        self.http2 = Some(config);
+        self
+    }
+
+    /// Opens the socket and loads the certificates, so a bad address or
+    /// certificate fails before anything is served
+    pub(crate) async fn bind(self) -> Result<BoundListener> {
+        let inherited = self.fd_name.as_deref().and_then(activation::take_named);
+        let address = match inherited {
+            Some(socket) => Address::Inherited(socket),
+            None => self.address,
+        };
+        let socket = match address {
+            Address::Tcp(host, port) => Socket::Tcp(
+                TcpListener::bind(format!("{}:{}", host, port))
+                    .await
+                    .with_context(|| format!("cannot bind {}:{}", host, port))?,
+            ),
+            Address::Std(listener) => {
+                listener.set_nonblocking(true)?;
+                Socket::Tcp(TcpListener::from_std(listener)?)
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                remove_stale_socket(&path)?;
+                let listener = UnixListener::bind(&path)
+                    .with_context(|| format!("cannot bind {}", path.display()))?;
+                Socket::Unix(listener, Some(path))
+            }
+            Address::Inherited(activation::Inherited::Tcp(listener)) => {
+                listener.set_nonblocking(true)?;
+                Socket::Tcp(TcpListener::from_std(listener)?)
+            }
+            // The service manager owns the socket file
+            #[cfg(unix)]
+            Address::Inherited(activation::Inherited::Unix(listener)) => {
+                listener.set_nonblocking(true)?;
+                Socket::Unix(UnixListener::from_std(listener)?, None)
+            }
+        };
+
+        // Without certificates the listener speaks plain HTTP
+        let tls_certs = (!self.tls_certs.is_empty()).then(|| self.tls_certs.clone());
+        let acceptor = if tls_certs.is_none() {
+            None
+        } else {
+            self.tls_certs.reload_certs()?;
+
+            // Certificates are picked per connection by the SNI name
+            let builder = ServerConfig::builder();
+            let builder = match &self.client_auth {
+                Some(client_auth) => builder.with_client_cert_verifier(client_auth.verifier()?),
+                None => builder.with_no_client_auth(),
+            };
+            let mut config = builder.with_cert_resolver(Arc::new(self.tls_certs));
+            if self.http2.is_some() {
+                config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            }
+            Some(TlsAcceptor::from(Arc::new(config)))
+        };
+
+        Ok(BoundListener {
+            socket,
+            tls_certs,
+            protocol: Arc::new(Protocol {
+                acceptor,
+                http2: self.http2,
+            }),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("cannot remove stale socket {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// How connections of one listener are spoken to
+pub(crate) struct Protocol {
+    pub acceptor: Option<TlsAcceptor>,
+    pub http2: Option<Http2Config>,
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+pub(crate) enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+pub(crate) struct BoundListener {
+    socket: Socket,
+    tls_certs: Option<CertResolver>,
+    protocol: Arc<Protocol>,
+}
+
+impl BoundListener {
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.socket {
+            Socket::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Socket::Unix(..) => None,
+        }
+    }
+
+    /// Certificates of a TLS listener, to watch for changes
+    pub fn get_cert_resolver(&self) -> Option<CertResolver> {
+        self.tls_certs.clone()
+    }
+
+    pub fn get_protocol(&self) -> Arc<Protocol> {
+        self.protocol.clone()
+    }
+
+    pub async fn accept(&self) -> std::io::Result<Connection> {
+        match &self.socket {
+            Socket::Tcp(listener) => Ok(Connection::Tcp(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Socket::Unix(listener, _) => Ok(Connection::Unix(listener.accept().await?.0)),
+        }
+    }
+}
+
+impl Display for BoundListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.protocol.acceptor.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        match &self.socket {
+            Socket::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}://{}", scheme, addr),
+                Err(_) => write!(f, "{}://<unknown>", scheme),
+            },
+            #[cfg(unix)]
+            Socket::Unix(listener, _) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => write!(f, "{}+unix://{}", scheme, path.display()),
+                    None => write!(f, "{}+unix://<unnamed>", scheme),
+                },
+                Err(_) => write!(f, "{}+unix://<unknown>", scheme),
+            },
+        }
+    }
+}
+
+impl Drop for BoundListener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Socket::Unix(_, Some(path)) = &self.socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
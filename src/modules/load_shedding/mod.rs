@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+/// Answers with 503 and `Retry-After` instead of queueing a request longer
+/// than `max_queue_time` for a route that is at its concurrency limit
+#[derive(Debug, Clone)]
+pub struct LoadShedding {
+    max_queue_time: Duration,
+    retry_after: Duration,
+}
+
+impl LoadShedding {
+    pub fn new(max_queue_time: Duration) -> Self {
+        Self {
+            max_queue_time,
+            retry_after: Duration::from_secs(1),
+        }
+    }
+
+    /// Sent to the client in whole seconds, at least one
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    pub(crate) fn get_max_queue_time(&self) -> Duration {
+        self.max_queue_time
+    }
+
+    pub(crate) fn get_retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs().max(1)
+    }
+}
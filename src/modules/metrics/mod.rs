@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+struct Counters {
+    active_connections: AtomicUsize,
+    accept_waits: AtomicU64,
+    queued_requests: AtomicUsize,
+    shed_requests: AtomicU64,
+    panics: AtomicU64,
+}
+
+/// Counters on how the server copes with load. Clones share the same counters
+#[derive(Debug, Clone, Default)]
+pub struct ServerMetrics(Arc<Counters>);
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_active_connections(&self) -> usize {
+        self.0.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Times accepting stopped because the connection limit was reached
+    pub fn get_accept_waits(&self) -> u64 {
+        self.0.accept_waits.load(Ordering::Relaxed)
+    }
+
+    /// Requests currently waiting for a route concurrency slot
+    pub fn get_queued_requests(&self) -> usize {
+        self.0.queued_requests.load(Ordering::Relaxed)
+    }
+
+    /// Requests answered with 503 because they waited too long for a slot
+    pub fn get_shed_requests(&self) -> u64 {
+        self.0.shed_requests.load(Ordering::Relaxed)
+    }
+
+    /// Handler, middleware and shutdown hook panics caught by this server
+    pub fn get_panics(&self) -> u64 {
+        self.0.panics.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn connection(&self) -> Gauge {
+        Gauge::new(self.0.clone(), |counters| &counters.active_connections)
+    }
+
+    pub(crate) fn queued(&self) -> Gauge {
+        Gauge::new(self.0.clone(), |counters| &counters.queued_requests)
+    }
+
+    pub(crate) fn record_accept_wait(&self) {
+        self.0.accept_waits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_shed(&self) {
+        self.0.shed_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_panic(&self) {
+        self.0.panics.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts one towards a gauge until dropped
+pub(crate) struct Gauge {
+    counters: Arc<Counters>,
+    counter: fn(&Counters) -> &AtomicUsize,
+}
+
+impl Gauge {
+    fn new(counters: Arc<Counters>, counter: fn(&Counters) -> &AtomicUsize) -> Self {
+        counter(&counters).fetch_add(1, Ordering::Relaxed);
+        Self { counters, counter }
+    }
+}
+
+impl Drop for Gauge {
+    fn drop(&mut self) {
+        (self.counter)(&self.counters).fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,19 @@
+use crate::http::request::Request;
+use crate::http::response::Response;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type MiddlewareFuture = Pin<Box<dyn Future<Output = Result<Request, Response>> + Send>>;
+
+/// Runs before the handler of every route it is attached to. Returning
+/// `Err(response)` stops the chain and sends that response instead
+pub type Middleware = Arc<dyn Fn(Request) -> MiddlewareFuture + Send + Sync>;
+
+pub fn middleware<F, Fut>(f: F) -> Middleware
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Request, Response>> + Send + 'static,
+{
+    Arc::new(move |req| Box::pin(f(req)))
+}
@@ -1,7 +1,27 @@
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod dispatcher;
 pub mod displayable;
+pub mod files;
+pub mod http2;
+pub mod limits;
+pub mod listener;
+pub mod load_shedding;
+pub mod metrics;
+pub mod middleware;
+pub mod panic_guard;
+pub mod parser;
+pub mod profile;
+pub mod range;
 pub mod router;
+pub mod server_handle;
 pub mod session;
+pub mod shutdown;
 pub mod state;
-pub mod stream_reader;
+pub mod timeouts;
+pub mod tls;
+pub mod upgrade;
+pub mod websocket;
 
 pub use nutt_web_macro::{delete, get, include_addr, post, put};
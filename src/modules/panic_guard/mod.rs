@@ -0,0 +1,93 @@
+use futures_util::FutureExt;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Once;
+
+static HOOK: Once = Once::new();
+
+tokio::task_local! {
+    /// Whether a panic in the current request keeps its backtrace, set by its server
+    static CAPTURE_BACKTRACE: bool;
+}
+
+thread_local! {
+    static LAST_BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Panic payload of a handler whose arguments couldn't be taken from the
+/// request, raised by `Route::new` in place of the extractor's own panic
+#[derive(Debug)]
+pub(crate) struct Rejection(pub String);
+
+/// Panic caught at the handler boundary
+#[derive(Debug, Clone)]
+pub struct CaughtPanic {
+    message: String,
+    backtrace: Option<String>,
+    rejection: bool,
+}
+
+impl CaughtPanic {
+    pub fn get_message(&self) -> String {
+        self.message.clone()
+    }
+
+    /// The request was refused by an extractor rather than the handler failing
+    pub fn is_rejection(&self) -> bool {
+        self.rejection
+    }
+
+    pub fn get_backtrace(&self) -> Option<String> {
+        self.backtrace.clone()
+    }
+}
+
+/// Chains a hook in front of the current panic hook that keeps the backtrace
+/// of the last panic on this thread, so it can be attached to the error page.
+/// The hook is shared by the process, each server decides with `with_backtraces`
+pub(crate) fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if CAPTURE_BACKTRACE.try_with(|capture| *capture).unwrap_or(false) {
+                let backtrace = Backtrace::force_capture().to_string();
+                LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+            }
+            previous(info)
+        }));
+    });
+}
+
+/// Runs `fut` keeping the backtraces of panics in it when `capture` is set
+pub(crate) async fn with_backtraces<F: Future>(capture: bool, fut: F) -> F::Output {
+    CAPTURE_BACKTRACE.scope(capture, fut).await
+}
+
+/// Polls the future and turns a panic inside it into an error
+pub(crate) async fn catch<F: Future>(fut: F) -> Result<F::Output, CaughtPanic> {
+    match AssertUnwindSafe(fut).catch_unwind().await {
+        Ok(output) => Ok(output),
+        Err(payload) => {
+            Err(CaughtPanic {
+                message: panic_message(payload.as_ref()),
+                backtrace: LAST_BACKTRACE.with(|last| last.borrow_mut().take()),
+                rejection: payload.is::<Rejection>(),
+            })
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(Rejection(message)) = payload.downcast_ref::<Rejection>() {
+        message.clone()
+    } else if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "handler panicked".to_string()
+    }
+}
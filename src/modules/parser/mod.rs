@@ -0,0 +1,378 @@
+pub(crate) mod reader;
+
+use crate::http::error::{ErrorKind, HttpError};
+use crate::http::status::StatusCode;
+use crate::modules::limits::Limits;
+
+/// Incremental parser for the request line and headers of an HTTP/1 request.
+/// Bytes are scanned once: each call only looks at what was added since the
+/// last one and remembers where the lines are, so a slow client sending its
+/// headers in many small reads costs no more than one sending them at once
+#[derive(Debug, Clone)]
+pub struct HeadParser {
+    max_request_line: usize,
+    max_headers: usize,
+    max_header_size: usize,
+    scanned: usize,
+    line_start: usize,
+    request_line: Option<(usize, usize)>,
+    headers: Vec<(usize, usize)>,
+    header_size: usize,
+}
+
+/// Request line and headers borrowed from the buffer they were parsed from
+#[derive(Debug, Clone)]
+pub struct RequestHead<'a> {
+    method: &'a str,
+    target: &'a str,
+    version: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+impl HeadParser {
+    pub fn new(limits: &Limits) -> Self {
+        Self {
+            max_request_line: limits.get_max_request_line(),
+            max_headers: limits.get_max_headers(),
+            max_header_size: limits.get_max_header_size(),
+            scanned: 0,
+            line_start: 0,
+            request_line: None,
+            headers: Vec::new(),
+            header_size: 0,
+        }
+    }
+
+    /// Scans the bytes appended to `buf` since the last call. Returns the
+    /// length of the head once the blank line ending it has been read
+    pub fn advance(&mut self, buf: &[u8]) -> Result<Option<usize>, HttpError> {
+        while let Some(end) = buf[self.scanned..].iter().position(|&b| b == b'\n') {
+            let line_end = self.scanned + end + 1;
+            self.scanned = line_end;
+            let start = self.line_start;
+            self.line_start = line_end;
+            let content_end = trim_line_end(buf, start, line_end);
+            let size = line_end - start;
+            match self.request_line {
+                // Blank lines in front of the request line are ignored
+                None if content_end == start => {}
+                None => {
+                    if size > self.max_request_line + 2 {
+                        return Err(request_line_too_long());
+                    }
+                    self.request_line = Some((start, content_end));
+                }
+                Some(_) if content_end == start => return Ok(Some(line_end)),
+                Some(_) => {
+                    self.header_size += size;
+                    if self.headers.len() == self.max_headers {
+                        return Err(headers_too_large("Too many request headers"));
+                    }
+                    if self.header_size > self.max_header_size {
+                        return Err(headers_too_large("Request headers are too large"));
+                    }
+                    self.headers.push((start, content_end));
+                }
+            }
+        }
+        self.scanned = buf.len();
+        // An unfinished line is held to the same limits, so it can't grow the buffer
+        let pending = buf.len() - self.line_start;
+        match self.request_line {
+            None if pending > self.max_request_line + 2 => Err(request_line_too_long()),
+            Some(_) if self.header_size + pending > self.max_header_size + 2 => {
+                Err(headers_too_large("Request headers are too large"))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether the scanned bytes hold anything besides blank lines
+    pub fn is_started(&self, buf: &[u8]) -> bool {
+        // Complete lines before the request line are all blank
+        self.request_line.is_some()
+            || buf[self.line_start..].iter().any(|&b| b != b'\r' && b != b'\n')
+    }
+
+    /// Splits the lines found by `advance` into borrowed fields
+    pub fn head<'a>(&self, buf: &'a [u8]) -> Result<RequestHead<'a>, HttpError> {
+        let (start, end) = self
+            .request_line
+            .ok_or_else(|| parse_error("Empty HTTP request"))?;
+        let line = as_str(&buf[start..end])?;
+        let mut tokens = line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (tokens.next(), tokens.next(), tokens.next(), tokens.next())
+        else {
+            return Err(parse_error("Invalid HTTP request line"));
+        };
+        if method.is_empty() || target.is_empty() || !matches!(version, "HTTP/1.0" | "HTTP/1.1") {
+            return Err(parse_error("Invalid HTTP request line"));
+        }
+        let mut headers = Vec::with_capacity(self.headers.len());
+        for &(start, end) in &self.headers {
+            let line = as_str(&buf[start..end])?;
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(parse_error("Invalid header line"));
+            };
+            // Whitespace before the colon or a folded line is rejected, RFC 9112 section 5
+            if name.is_empty() || name.bytes().any(|b| b.is_ascii_whitespace()) {
+                return Err(parse_error("Invalid header name"));
+            }
+            headers.push((name, value.trim_matches([' ', '\t'])));
+        }
+        Ok(RequestHead {
+            method,
+            target,
+            version,
+            headers,
+        })
+    }
+
+    /// Forgets the parsed head, ready for the next request on the connection
+    pub fn reset(&mut self) {
+        self.scanned = 0;
+        self.line_start = 0;
+        self.request_line = None;
+        self.headers.clear();
+        self.header_size = 0;
+    }
+}
+
+impl<'a> RequestHead<'a> {
+    pub fn get_method(&self) -> &'a str {
+        self.method
+    }
+
+    pub fn get_target(&self) -> &'a str {
+        self.target
+    }
+
+    pub fn get_version(&self) -> &'a str {
+        self.version
+    }
+
+    pub fn get_headers(&self) -> &[(&'a str, &'a str)] {
+        &self.headers
+    }
+
+    /// First header with this name, compared case-insensitively
+    pub fn get_header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    /// Length of the body, zero when the header is missing. Bodies are only
+    /// framed by a single `Content-Length`: transfer codings are refused and a
+    /// repeated length is rejected, so no two readers can disagree on where the
+    /// request ends, RFC 9112 section 6
+    pub fn get_content_length(&self) -> Result<usize, HttpError> {
+        if self.get_header("Transfer-Encoding").is_some() {
+            return Err(HttpError::new(
+                ErrorKind::Parse,
+                StatusCode::NotImplemented,
+                "Transfer-Encoding is not supported",
+            ));
+        }
+        let mut lengths = self
+            .headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("Content-Length"));
+        let Some((_, value)) = lengths.next() else {
+            return Ok(0);
+        };
+        if lengths.next().is_some() {
+            return Err(parse_error("Repeated Content-Length header"));
+        }
+        // `parse` would also take a sign, and a list such as `5, 5` is refused too
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(parse_error("Invalid Content-Length header"));
+        }
+        value
+            .parse::<usize>()
+            .map_err(|_| parse_error("Invalid Content-Length header"))
+    }
+}
+
+/// End of a line without its `\n` or `\r\n`
+fn trim_line_end(buf: &[u8], start: usize, line_end: usize) -> usize {
+    let mut end = line_end - 1;
+    if end > start && buf[end - 1] == b'\r' {
+        end -= 1;
+    }
+    end
+}
+
+fn as_str(bytes: &[u8]) -> Result<&str, HttpError> {
+    std::str::from_utf8(bytes).map_err(|_| parse_error("Request head is not valid UTF-8"))
+}
+
+fn parse_error(message: &str) -> HttpError {
+    HttpError::new(ErrorKind::Parse, StatusCode::BadRequest, message)
+}
+
+fn request_line_too_long() -> HttpError {
+    HttpError::new(ErrorKind::Limit, StatusCode::UriTooLong, "Request line is too long")
+}
+
+fn headers_too_large(message: &str) -> HttpError {
+    HttpError::new(ErrorKind::Limit, StatusCode::RequestHeaderFieldsTooLarge, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Option<(usize, RequestHead<'_>)>, HttpError> {
+        let mut parser = HeadParser::new(&Limits::new());
+        match parser.advance(raw.as_bytes())? {
+            Some(len) => Ok(Some((len, parser.head(&raw.as_bytes()[..len])?))),
+            None => Ok(None),
+        }
+    }
+
+    fn content_length(raw: &str) -> Result<usize, HttpError> {
+        parse(raw).unwrap().unwrap().1.get_content_length()
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let raw = "GET /path?q=1 HTTP/1.1\r\nHost: example.com\r\nX-Empty:\r\nAccept:  text/html \r\n\r\n";
+        let (len, head) = parse(raw).unwrap().unwrap();
+        assert_eq!(len, raw.len());
+        assert_eq!(head.get_method(), "GET");
+        assert_eq!(head.get_target(), "/path?q=1");
+        assert_eq!(head.get_version(), "HTTP/1.1");
+        assert_eq!(head.get_header("host"), Some("example.com"));
+        assert_eq!(head.get_header("X-Empty"), Some(""));
+        assert_eq!(head.get_header("Accept"), Some("text/html"));
+    }
+
+    #[test]
+    fn accepts_bare_line_feeds_and_leading_blank_lines() {
+        let (_, head) = parse("\r\n\nPOST / HTTP/1.0\nA: b\n\n").unwrap().unwrap();
+        assert_eq!(head.get_method(), "POST");
+        assert_eq!(head.get_header("A"), Some("b"));
+    }
+
+    #[test]
+    fn head_ends_at_the_blank_line() {
+        let raw = "GET / HTTP/1.1\r\n\r\nGET /next HTTP/1.1\r\n\r\n";
+        let (len, _) = parse(raw).unwrap().unwrap();
+        assert_eq!(&raw[len..], "GET /next HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn incomplete_head_waits_for_more() {
+        assert!(parse("GET / HTTP/1.1\r\nHost: x\r\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn resumes_across_reads() {
+        let raw = b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n";
+        let mut parser = HeadParser::new(&Limits::new());
+        let mut end = None;
+        for split in 1..=raw.len() {
+            end = parser.advance(&raw[..split]).unwrap();
+            if end.is_some() {
+                assert_eq!(split, raw.len());
+            }
+        }
+        let head = parser.head(&raw[..end.unwrap()]).unwrap();
+        assert_eq!(head.get_target(), "/a");
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        for raw in [
+            "GET /\r\n\r\n",
+            "GET  / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/2.0\r\n\r\n",
+            "GET / HTTP/1.1 extra\r\n\r\n",
+        ] {
+            let err = parse(raw).unwrap_err();
+            assert_eq!(err.get_status(), StatusCode::BadRequest, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_header_lines() {
+        for raw in [
+            "GET / HTTP/1.1\r\nNoColon\r\n\r\n",
+            "GET / HTTP/1.1\r\nName : value\r\n\r\n",
+            "GET / HTTP/1.1\r\nA: b\r\n folded\r\n\r\n",
+        ] {
+            assert!(parse(raw).is_err(), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = Limits::new().max_request_line(16).max_headers(1).max_header_size(32);
+        let status = |raw: &str| {
+            HeadParser::new(&limits)
+                .advance(raw.as_bytes())
+                .unwrap_err()
+                .get_status()
+        };
+        assert_eq!(status("GET /a-very-long-path HTTP/1.1\r\n"), StatusCode::UriTooLong);
+        // An unfinished line counts too
+        assert_eq!(status("GET /a-very-long-path"), StatusCode::UriTooLong);
+        assert_eq!(
+            status("GET / HTTP/1.1\r\nA: b\r\nC: d\r\n"),
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+        assert_eq!(
+            status(&format!("GET / HTTP/1.1\r\nA: {}\r\n", "b".repeat(40))),
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+    }
+
+    #[test]
+    fn reset_parses_the_next_head() {
+        let raw = b"GET /a HTTP/1.1\r\n\r\n";
+        let mut parser = HeadParser::new(&Limits::new());
+        assert!(parser.advance(raw).unwrap().is_some());
+        parser.reset();
+        assert!(!parser.is_started(b"\r\n"));
+        let next = b"GET /b HTTP/1.1\r\n\r\n";
+        let len = parser.advance(next).unwrap().unwrap();
+        assert_eq!(parser.head(&next[..len]).unwrap().get_target(), "/b");
+    }
+
+    #[test]
+    fn content_length_defaults_to_zero() {
+        assert_eq!(content_length("GET / HTTP/1.1\r\n\r\n").unwrap(), 0);
+        assert_eq!(content_length("POST / HTTP/1.1\r\ncontent-length: 12\r\n\r\n").unwrap(), 12);
+    }
+
+    #[test]
+    fn rejects_ambiguous_content_length() {
+        for raw in [
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length:\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+        ] {
+            let err = content_length(raw).unwrap_err();
+            assert_eq!(err.get_status(), StatusCode::BadRequest, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn refuses_transfer_codings() {
+        for raw in [
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\ntransfer-encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.0\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+        ] {
+            let err = content_length(raw).unwrap_err();
+            assert_eq!(err.get_status(), StatusCode::NotImplemented, "{:?}", raw);
+        }
+    }
+}
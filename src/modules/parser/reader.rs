@@ -0,0 +1,236 @@
+use super::{HeadParser, RequestHead};
+use crate::http::error::{ErrorKind, HttpError};
+use crate::http::status::StatusCode;
+use crate::modules::limits::Limits;
+use crate::modules::timeouts::Timeouts;
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{timeout, timeout_at, Instant};
+
+/// Room made in the buffer before each read
+const READ_SIZE: usize = 8 * 1024;
+
+/// How often a body being received is checked against the minimum rate
+const RATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads requests from one connection into a buffer kept between requests,
+/// so bytes of a pipelined request read together with the previous one are
+/// parsed next instead of being lost
+pub(crate) struct RequestReader<'a> {
+    buf: BytesMut,
+    parser: HeadParser,
+    head_len: usize,
+    deadline: Option<Instant>,
+    timeouts: &'a Timeouts,
+}
+
+impl<'a> RequestReader<'a> {
+    pub fn new(timeouts: &'a Timeouts, limits: &Limits) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(READ_SIZE),
+            parser: HeadParser::new(limits),
+            head_len: 0,
+            deadline: None,
+            timeouts,
+        }
+    }
+
+    /// Waits for the first bytes of the next request, the header deadline
+    /// starts here. `false` means the client closed the connection or sent
+    /// nothing before the deadline
+    pub async fn wait_request<T: AsyncRead + Unpin>(&mut self, stream: &mut T) -> bool {
+        self.consume_head();
+        let deadline = Instant::now() + self.timeouts.get_header_read();
+        self.deadline = Some(deadline);
+        while !self.parser.is_started(&self.buf) {
+            self.buf.reserve(READ_SIZE);
+            // `read_buf` is cancel safe, nothing read is lost when the wait is dropped
+            match timeout_at(deadline, stream.read_buf(&mut self.buf)).await {
+                Ok(Ok(0)) | Ok(Err(_)) | Err(_) => return false,
+                Ok(Ok(_)) => {}
+            }
+        }
+        true
+    }
+
+    /// Reads the request line and headers. `None` means the client closed the
+    /// connection or sent nothing before the header deadline
+    pub async fn read_head<T: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut T,
+    ) -> Result<Option<RequestHead<'_>>> {
+        self.consume_head();
+        let deadline = self
+            .deadline
+            .take()
+            .unwrap_or_else(|| Instant::now() + self.timeouts.get_header_read());
+        let head_len = loop {
+            if let Some(len) = self.parser.advance(&self.buf)? {
+                break len;
+            }
+            self.buf.reserve(READ_SIZE);
+            // `read_buf` is cancel safe, whatever arrived before the deadline stays buffered
+            let read = timeout_at(deadline, stream.read_buf(&mut self.buf)).await;
+            let started = self.parser.is_started(&self.buf);
+            match read {
+                Ok(Ok(0)) | Ok(Err(_)) if !started => return Ok(None),
+                Ok(Ok(0)) => anyhow::bail!("Connection closed before the request headers were complete"),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) if !started => return Ok(None),
+                Err(_) => return Err(timeout_error("Timed out reading the request headers")),
+            }
+        };
+        self.head_len = head_len;
+        Ok(Some(self.parser.head(&self.buf[..head_len])?))
+    }
+
+    /// Reads a body of `content_length` bytes, checked against the body limit by the caller
+    pub async fn read_body<T: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut T,
+        content_length: usize,
+    ) -> Result<Bytes> {
+        self.consume_head();
+        let started = Instant::now();
+        let deadline = started + self.timeouts.get_body_read();
+        while self.buf.len() < content_length {
+            self.buf.reserve(content_length - self.buf.len());
+            // Wake up regularly so a client sending nothing is caught by the rate check
+            let wait = deadline
+                .saturating_duration_since(Instant::now())
+                .min(RATE_CHECK_INTERVAL);
+            if let Ok(read) = timeout(wait, stream.read_buf(&mut self.buf)).await {
+                if read? == 0 {
+                    anyhow::bail!("Connection closed before the request body was complete");
+                }
+            }
+            if self.buf.len() >= content_length {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(timeout_error("Timed out reading the request body"));
+            }
+            if let Some(min_rate) = self.timeouts.get_min_body_rate() {
+                let elapsed = started.elapsed();
+                if elapsed >= RATE_CHECK_INTERVAL
+                    && (self.buf.len() as f64) < min_rate as f64 * elapsed.as_secs_f64()
+                {
+                    return Err(timeout_error("Request body is sent too slowly"));
+                }
+            }
+        }
+        Ok(self.buf.split_to(content_length).freeze())
+    }
+
+    /// Bytes received after the last request, handed over on a protocol upgrade
+    pub fn take_buffered(&mut self) -> Bytes {
+        self.consume_head();
+        self.buf.split().freeze()
+    }
+
+    /// Drops the head of the previous request from the buffer
+    fn consume_head(&mut self) {
+        if self.head_len > 0 {
+            self.buf.advance(self.head_len);
+            self.head_len = 0;
+            self.parser.reset();
+        }
+    }
+}
+
+fn timeout_error(message: &str) -> anyhow::Error {
+    HttpError::new(ErrorKind::Timeout, StatusCode::RequestTimeout, message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn timeouts() -> Timeouts {
+        Timeouts::new()
+            .header_read(Duration::from_millis(200))
+            .body_read(Duration::from_millis(200))
+    }
+
+    /// Head and body of the next request, as the connection loop reads them
+    async fn next_request<T: AsyncRead + Unpin>(
+        reader: &mut RequestReader<'_>,
+        stream: &mut T,
+    ) -> Option<(String, Bytes)> {
+        let head = reader.read_head(stream).await.unwrap()?;
+        let target = head.get_target().to_string();
+        let length = head.get_content_length().unwrap();
+        let body = reader.read_body(stream, length).await.unwrap();
+        Some((target, body))
+    }
+
+    #[tokio::test]
+    async fn reads_pipelined_requests_in_order() {
+        let timeouts = timeouts();
+        let mut reader = RequestReader::new(&timeouts, &Limits::new());
+        let mut stream: &[u8] = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\n\r\nPOST /c HTTP/1.1\r\nContent-Length: 3\r\n\r\nbye";
+        let (target, body) = next_request(&mut reader, &mut stream).await.unwrap();
+        assert_eq!((target.as_str(), &body[..]), ("/a", &b"hello"[..]));
+        let (target, body) = next_request(&mut reader, &mut stream).await.unwrap();
+        assert_eq!((target.as_str(), &body[..]), ("/b", &b""[..]));
+        let (target, body) = next_request(&mut reader, &mut stream).await.unwrap();
+        assert_eq!((target.as_str(), &body[..]), ("/c", &b"bye"[..]));
+        assert!(next_request(&mut reader, &mut stream).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_requests_split_across_writes() {
+        let timeouts = timeouts();
+        let mut reader = RequestReader::new(&timeouts, &Limits::new());
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let raw = b"POST /a HTTP/1.1\r\nContent-Length: 4\r\n\r\nbodyGET /b HTTP/1.1\r\n\r\n";
+        tokio::spawn(async move {
+            for chunk in raw.chunks(3) {
+                client.write_all(chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        });
+        assert!(reader.wait_request(&mut server).await);
+        let (target, body) = next_request(&mut reader, &mut server).await.unwrap();
+        assert_eq!((target.as_str(), &body[..]), ("/a", &b"body"[..]));
+        let (target, _) = next_request(&mut reader, &mut server).await.unwrap();
+        assert_eq!(target, "/b");
+        assert!(!reader.wait_request(&mut server).await);
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_not_an_error() {
+        let timeouts = timeouts();
+        let mut reader = RequestReader::new(&timeouts, &Limits::new());
+        let (_client, mut server) = tokio::io::duplex(64);
+        assert!(reader.read_head(&mut server).await.unwrap().is_none());
+        let mut closed: &[u8] = b"\r\n";
+        assert!(!reader.wait_request(&mut closed).await);
+    }
+
+    #[tokio::test]
+    async fn incomplete_requests_fail() {
+        let timeouts = timeouts();
+        let mut reader = RequestReader::new(&timeouts, &Limits::new());
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost";
+        assert!(reader.read_head(&mut stream).await.is_err());
+
+        let mut reader = RequestReader::new(&timeouts, &Limits::new());
+        let mut stream: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort";
+        reader.read_head(&mut stream).await.unwrap().unwrap();
+        assert!(reader.read_body(&mut stream, 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn upgrade_takes_the_bytes_after_the_head() {
+        let timeouts = timeouts();
+        let mut reader = RequestReader::new(&timeouts, &Limits::new());
+        let mut stream: &[u8] = b"GET /ws HTTP/1.1\r\n\r\n\x81\x00";
+        reader.read_head(&mut stream).await.unwrap().unwrap();
+        assert_eq!(&reader.take_buffered()[..], b"\x81\x00");
+    }
+}
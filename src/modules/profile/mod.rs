@@ -0,0 +1,177 @@
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+pub use tracing::Level;
+
+/// Environment variable selecting the profile
+pub const PROFILE_ENV: &str = "NUTT_PROFILE";
+
+/// Command line flag selecting the profile, `--profile staging` or `--profile=staging`
+pub const PROFILE_FLAG: &str = "--profile";
+
+/// Environment the server runs in, chosen at startup instead of at compile time
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Profile {
+    Dev,
+    Staging,
+    Release,
+    Custom(String),
+}
+
+impl Profile {
+    pub fn parse(name: &str) -> Self {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "dev" | "development" => Profile::Dev,
+            "staging" => Profile::Staging,
+            "release" | "prod" | "production" => Profile::Release,
+            other => Profile::Custom(other.to_string()),
+        }
+    }
+
+    /// The `--profile` flag, then `NUTT_PROFILE`, then dev for debug builds
+    /// and release otherwise
+    pub fn from_env() -> Self {
+        if let Some(profile) = Self::from_flag() {
+            return profile;
+        }
+        match std::env::var(PROFILE_ENV) {
+            Ok(name) if !name.trim().is_empty() => Self::parse(&name),
+            _ if cfg!(debug_assertions) => Profile::Dev,
+            _ => Profile::Release,
+        }
+    }
+}
+
+impl Profile {
+    pub(crate) fn from_flag() -> Option<Self> {
+        Self::from_args(std::env::args_os().skip(1))
+    }
+
+    /// Arguments that aren't valid Unicode are skipped, they can't name a profile
+    fn from_args(mut args: impl Iterator<Item = OsString>) -> Option<Self> {
+        while let Some(arg) = args.next() {
+            let Some(arg) = arg.to_str() else {
+                continue;
+            };
+            if arg == PROFILE_FLAG {
+                return args
+                    .next()
+                    .and_then(|name| name.to_str().map(Self::parse));
+            } else if let Some(name) = arg
+                .strip_prefix(PROFILE_FLAG)
+                .and_then(|rest| rest.strip_prefix('='))
+            {
+                return Some(Self::parse(name));
+            }
+        }
+        None
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Profile::Dev => write!(f, "dev"),
+            Profile::Staging => write!(f, "staging"),
+            Profile::Release => write!(f, "release"),
+            Profile::Custom(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Settings that differ between profiles. Unset values fall back to the
+/// profile defaults: info logging everywhere, detailed errors only in dev
+#[derive(Debug, Clone, Default)]
+pub struct ProfileConfig {
+    address: Option<(String, u16)>,
+    tls_certs: Option<(String, String)>,
+    log_level: Option<Level>,
+    verbose_errors: Option<bool>,
+}
+
+impl ProfileConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(mut self, address: (&str, u16)) -> Self {
+        self.address = Some((address.0.to_string(), address.1));
+        self
+    }
+
+    /// Default certificate of the profile, replaces the one set with `set_tls_certs`
+    pub fn tls_certs(mut self, certs: (&str, &str)) -> Self {
+        self.tls_certs = Some((certs.0.to_string(), certs.1.to_string()));
+        self
+    }
+
+    pub fn log_level(mut self, level: Level) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Backtraces on panics and a detailed error page instead of a bare problem response
+    pub fn verbose_errors(mut self, verbose: bool) -> Self {
+        self.verbose_errors = Some(verbose);
+        self
+    }
+
+    /// Fills the values this config leaves unset from `defaults`
+    pub(crate) fn or(self, defaults: &ProfileConfig) -> Self {
+        Self {
+            address: self.address.or_else(|| defaults.address.clone()),
+            tls_certs: self.tls_certs.or_else(|| defaults.tls_certs.clone()),
+            log_level: self.log_level.or(defaults.log_level),
+            verbose_errors: self.verbose_errors.or(defaults.verbose_errors),
+        }
+    }
+
+    pub(crate) fn get_address(&self) -> Option<(String, u16)> {
+        self.address.clone()
+    }
+
+    pub(crate) fn get_tls_certs(&self) -> Option<(String, String)> {
+        self.tls_certs.clone()
+    }
+
+    pub(crate) fn get_log_level(&self) -> Level {
+        self.log_level.unwrap_or(Level::INFO)
+    }
+
+    pub(crate) fn is_verbose_errors(&self, profile: &Profile) -> bool {
+        self.verbose_errors.unwrap_or(*profile == Profile::Dev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &[&str]) -> Option<Profile> {
+        Profile::from_args(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn reads_the_profile_flag() {
+        assert_eq!(from_args(&["--profile", "staging"]), Some(Profile::Staging));
+        assert_eq!(from_args(&["-v", "--profile=prod"]), Some(Profile::Release));
+        assert_eq!(from_args(&["--profile=qa"]), Some(Profile::Custom("qa".to_string())));
+        assert_eq!(from_args(&["--profile"]), None);
+        assert_eq!(from_args(&["--profiles", "dev"]), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_arguments_that_are_not_unicode() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let args = vec![
+            OsString::from_vec(b"--input=\xff".to_vec()),
+            OsString::from("--profile"),
+            OsString::from("staging"),
+        ];
+        assert_eq!(Profile::from_args(args.into_iter()), Some(Profile::Staging));
+
+        let args = vec![OsString::from("--profile"), OsString::from_vec(b"\xff".to_vec())];
+        assert_eq!(Profile::from_args(args.into_iter()), None);
+    }
+}
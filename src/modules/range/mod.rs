@@ -0,0 +1,487 @@
+use crate::http::method::Method;
+use crate::http::request::Request;
+use crate::http::response::{BodyStream, Response, ResponseBuilder};
+use crate::http::status::StatusCode;
+use crate::modules::conditional::{self, ETag};
+use bytes::{Bytes, BytesMut};
+use futures_util::StreamExt;
+use std::collections::VecDeque;
+use std::io;
+
+/// More ranges than this are answered with the whole body, so a request can't
+/// make a small body expand into many parts
+const MAX_RANGES: usize = 32;
+
+/// Inclusive byte range of a body, `bytes=0-99` is the first hundred bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+impl ByteRange {
+    pub fn get_start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn get_end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// A range has at least one byte
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// `Content-Range` value of the range in a body of `len` bytes
+    pub fn content_range(&self, len: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, len)
+    }
+}
+
+/// What a `Range` header asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ranges {
+    /// No usable range, the whole body is sent
+    Full,
+    /// Sorted ranges that don't overlap
+    Partial(Vec<ByteRange>),
+    /// None of the ranges is in the body, answered with 416
+    Unsatisfiable,
+}
+
+impl Ranges {
+    /// Ranges of a `Range` header for a body of `len` bytes. Headers that are
+    /// malformed or use another unit are ignored, RFC 9110 section 14.2
+    pub fn parse(header: &str, len: u64) -> Self {
+        let Some((unit, specs)) = header.split_once('=') else {
+            return Ranges::Full;
+        };
+        if !unit.trim().eq_ignore_ascii_case("bytes") {
+            return Ranges::Full;
+        }
+        let specs = specs
+            .split(',')
+            .map(str::trim)
+            .filter(|spec| !spec.is_empty())
+            .collect::<Vec<_>>();
+        if specs.is_empty() || specs.len() > MAX_RANGES {
+            return Ranges::Full;
+        }
+        let mut ranges = Vec::new();
+        for spec in specs {
+            let Some((first, last)) = spec.split_once('-') else {
+                return Ranges::Full;
+            };
+            let (first, last) = (first.trim(), last.trim());
+            let range = if first.is_empty() {
+                // Suffix, the last bytes of the body
+                let Ok(suffix) = last.parse::<u64>() else {
+                    return Ranges::Full;
+                };
+                (suffix > 0 && len > 0).then(|| ByteRange {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                })
+            } else {
+                let Ok(start) = first.parse::<u64>() else {
+                    return Ranges::Full;
+                };
+                let end = match last {
+                    "" => u64::MAX,
+                    last => match last.parse::<u64>() {
+                        Ok(end) if end >= start => end,
+                        _ => return Ranges::Full,
+                    },
+                };
+                (start < len).then(|| ByteRange {
+                    start,
+                    end: end.min(len - 1),
+                })
+            };
+            // Ranges past the end are left out, the others are still served
+            ranges.extend(range);
+        }
+        if ranges.is_empty() {
+            return Ranges::Unsatisfiable;
+        }
+        ranges.sort_by_key(|range| range.start);
+        let mut coalesced: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match coalesced.last_mut() {
+                Some(last) if range.start <= last.end.saturating_add(1) => {
+                    last.end = last.end.max(range.end);
+                }
+                _ => coalesced.push(range),
+            }
+        }
+        Ranges::Partial(coalesced)
+    }
+}
+
+/// `Range` and `If-Range` headers of a GET request
+#[derive(Debug)]
+pub(crate) struct RangeRequest {
+    range: Option<String>,
+    if_range: Option<String>,
+}
+
+impl RangeRequest {
+    /// `None` for requests other than GET, their responses never have ranges
+    pub fn from_request(req: &Request) -> Option<Self> {
+        (req.get_method() == Method::GET).then(|| Self {
+            range: req.get_header("Range"),
+            if_range: req.get_header("If-Range"),
+        })
+    }
+
+    /// Ranges asked for of a body of `len` bytes with the given validators.
+    /// `If-Range` asks for the whole body unless it still has this version
+    pub fn ranges(&self, len: u64, etag: Option<&str>, last_modified: Option<&str>) -> Ranges {
+        let Some(range) = &self.range else {
+            return Ranges::Full;
+        };
+        if let Some(if_range) = &self.if_range {
+            let if_range = if_range.trim();
+            let current = if if_range.starts_with('"') || if_range.starts_with("W/") {
+                // Only a strong tag is sure to name the same bytes
+                let etag = etag.and_then(ETag::parse);
+                ETag::parse(if_range)
+                    .zip(etag)
+                    .is_some_and(|(if_range, etag)| if_range.strong_eq(&etag))
+            } else {
+                let date = conditional::parse_http_date(if_range);
+                date.is_some() && date == last_modified.and_then(conditional::parse_http_date)
+            };
+            if !current {
+                return Ranges::Full;
+            }
+        }
+        Ranges::parse(range, len)
+    }
+
+    /// Advertises ranges on a successful response of known length and answers
+    /// with the parts asked for, `206 Partial Content` or `416 Range Not Satisfiable`
+    pub fn apply(&self, mut resp: Response) -> Response {
+        if resp.get_status() != StatusCode::Ok {
+            return resp;
+        }
+        let len = if resp.is_streaming() {
+            match resp
+                .get_header("Content-Length")
+                .and_then(|value| value.trim().parse::<u64>().ok())
+            {
+                Some(len) => len,
+                None => return resp,
+            }
+        } else {
+            resp.get_body_bytes().len() as u64
+        };
+        resp.set_header("Accept-Ranges", "bytes");
+        let etag = resp.get_header("ETag");
+        let last_modified = resp.get_header("Last-Modified");
+        match self.ranges(len, etag.as_deref(), last_modified.as_deref()) {
+            Ranges::Full => resp,
+            Ranges::Unsatisfiable => unsatisfiable(len),
+            Ranges::Partial(ranges) => partial(resp, &ranges, len),
+        }
+    }
+}
+
+/// `416 Range Not Satisfiable` for a body of `len` bytes
+pub fn unsatisfiable(len: u64) -> Response {
+    ResponseBuilder::new(StatusCode::RangeNotSatisfiable, "")
+        .set_header("Content-Range", &format!("bytes */{}", len))
+        .build()
+}
+
+/// Turns a full `200 OK` response of `len` bytes into `206 Partial Content`
+/// with the given ranges, several are sent as `multipart/byteranges`
+fn partial(mut resp: Response, ranges: &[ByteRange], len: u64) -> Response {
+    let content_type = resp.get_header("Content-Type");
+    let mut parts = Vec::with_capacity(ranges.len());
+    let mut trailer = Bytes::new();
+    if let [range] = ranges {
+        resp.set_header("Content-Range", &range.content_range(len));
+        parts.push((Bytes::new(), *range));
+    } else {
+        let boundary = format!("{:016x}", rand::random::<u64>());
+        for range in ranges {
+            let mut head = format!("\r\n--{}\r\n", boundary);
+            if let Some(content_type) = &content_type {
+                head.push_str(&format!("Content-Type: {}\r\n", content_type));
+            }
+            head.push_str(&format!("Content-Range: {}\r\n\r\n", range.content_range(len)));
+            parts.push((Bytes::from(head), *range));
+        }
+        trailer = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+        resp.remove_header("Content-Type");
+        resp.set_header(
+            "Content-Type",
+            &format!("multipart/byteranges; boundary={}", boundary),
+        );
+    }
+    let total = parts
+        .iter()
+        .map(|(head, range)| head.len() as u64 + range.len())
+        .sum::<u64>()
+        + trailer.len() as u64;
+    resp.set_status(StatusCode::PartialContent);
+    match resp.take_stream() {
+        Some(body) => {
+            resp.set_stream(slice_stream(body, parts, trailer));
+            resp.remove_header("Content-Length");
+            resp.set_header("Content-Length", &total.to_string());
+        }
+        None => {
+            let body = resp.get_body_bytes();
+            let mut sliced = BytesMut::with_capacity(total as usize);
+            for (head, range) in parts {
+                sliced.extend_from_slice(&head);
+                sliced.extend_from_slice(&body[range.start as usize..=range.end as usize]);
+            }
+            sliced.extend_from_slice(&trailer);
+            resp.set_body(sliced.freeze());
+        }
+    }
+    resp
+}
+
+/// Passes on the parts of a streamed body in one pass, each after its head.
+/// Reading stops once the last range is sent
+fn slice_stream(body: BodyStream, parts: Vec<(Bytes, ByteRange)>, trailer: Bytes) -> BodyStream {
+    let state = (body, 0u64, VecDeque::from(parts), Some(trailer));
+    let stream = futures_util::stream::unfold(Some(state), |state| async move {
+        let (mut body, mut offset, mut parts, mut trailer) = state?;
+        loop {
+            if parts.is_empty() {
+                return trailer.take().map(|trailer| (Ok(trailer), None));
+            }
+            let chunk = match body.next().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => {
+                    let e = io::Error::new(io::ErrorKind::UnexpectedEof, "body ended before its ranges");
+                    return Some((Err(e), None));
+                }
+            };
+            let chunk_end = offset + chunk.len() as u64;
+            let mut out = BytesMut::new();
+            while let Some((head, range)) = parts.front() {
+                if range.start >= chunk_end {
+                    break;
+                }
+                if range.start >= offset {
+                    out.extend_from_slice(head);
+                }
+                let from = range.start.max(offset) - offset;
+                let to = range.end.min(chunk_end - 1) - offset;
+                out.extend_from_slice(&chunk[from as usize..=to as usize]);
+                if range.end >= chunk_end {
+                    break;
+                }
+                parts.pop_front();
+            }
+            offset = chunk_end;
+            if !out.is_empty() {
+                return Some((Ok(out.freeze()), Some((body, offset, parts, trailer))));
+            }
+        }
+    });
+    Box::pin(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    fn request(range: &str, if_range: Option<&str>) -> RangeRequest {
+        RangeRequest {
+            range: Some(range.to_string()),
+            if_range: if_range.map(str::to_string),
+        }
+    }
+
+    fn chunked(body: &'static [u8], size: usize) -> BodyStream {
+        let chunks = body
+            .chunks(size)
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect::<Vec<_>>();
+        Box::pin(futures_util::stream::iter(chunks))
+    }
+
+    async fn collect(mut body: BodyStream) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        while let Some(chunk) = body.next().await {
+            out.extend_from_slice(&chunk?);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(Ranges::parse("bytes=0-99", 1000), Ranges::Partial(vec![range(0, 99)]));
+        assert_eq!(Ranges::parse("bytes=900-", 1000), Ranges::Partial(vec![range(900, 999)]));
+        assert_eq!(Ranges::parse("bytes=-100", 1000), Ranges::Partial(vec![range(900, 999)]));
+        assert_eq!(Ranges::parse("BYTES = 5-5", 1000), Ranges::Partial(vec![range(5, 5)]));
+    }
+
+    #[test]
+    fn clamps_to_the_body() {
+        assert_eq!(Ranges::parse("bytes=900-5000", 1000), Ranges::Partial(vec![range(900, 999)]));
+        assert_eq!(Ranges::parse("bytes=-5000", 1000), Ranges::Partial(vec![range(0, 999)]));
+    }
+
+    #[test]
+    fn sorts_and_coalesces() {
+        assert_eq!(
+            Ranges::parse("bytes=50-59, 0-9, 5-19, 20-29", 100),
+            Ranges::Partial(vec![range(0, 29), range(50, 59)])
+        );
+        // Ranges past the end are left out, the others are kept
+        assert_eq!(
+            Ranges::parse("bytes=0-1,500-600", 100),
+            Ranges::Partial(vec![range(0, 1)])
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_headers() {
+        for header in [
+            "items=0-1",
+            "bytes",
+            "bytes=",
+            "bytes=a-b",
+            "bytes=5-1",
+            "bytes=1",
+            "bytes=--1",
+        ] {
+            assert_eq!(Ranges::parse(header, 100), Ranges::Full, "{}", header);
+        }
+        let many = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2)).collect::<Vec<_>>();
+        assert_eq!(Ranges::parse(&format!("bytes={}", many.join(",")), 1000), Ranges::Full);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(Ranges::parse("bytes=100-", 100), Ranges::Unsatisfiable);
+        assert_eq!(Ranges::parse("bytes=-0", 100), Ranges::Unsatisfiable);
+        assert_eq!(Ranges::parse("bytes=0-", 0), Ranges::Unsatisfiable);
+        assert_eq!(Ranges::parse("bytes=-5", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn content_range() {
+        assert_eq!(range(0, 99).content_range(1000), "bytes 0-99/1000");
+        assert_eq!(range(0, 99).len(), 100);
+    }
+
+    #[test]
+    fn if_range_needs_a_matching_strong_tag() {
+        let etag = Some("\"v1\"");
+        assert_eq!(
+            request("bytes=0-9", Some("\"v1\"")).ranges(100, etag, None),
+            Ranges::Partial(vec![range(0, 9)])
+        );
+        assert_eq!(request("bytes=0-9", Some("\"v2\"")).ranges(100, etag, None), Ranges::Full);
+        assert_eq!(request("bytes=0-9", Some("W/\"v1\"")).ranges(100, etag, None), Ranges::Full);
+        assert_eq!(
+            request("bytes=0-9", Some("\"v1\"")).ranges(100, Some("W/\"v1\""), None),
+            Ranges::Full
+        );
+        assert_eq!(request("bytes=0-9", Some("\"v1\"")).ranges(100, None, None), Ranges::Full);
+    }
+
+    #[test]
+    fn if_range_date_must_match_exactly() {
+        let modified = Some("Tue, 15 Nov 1994 08:12:31 GMT");
+        assert_eq!(
+            request("bytes=0-9", modified).ranges(100, None, modified),
+            Ranges::Partial(vec![range(0, 9)])
+        );
+        assert_eq!(
+            request("bytes=0-9", Some("Tue, 15 Nov 1994 08:12:32 GMT")).ranges(100, None, modified),
+            Ranges::Full
+        );
+        assert_eq!(request("bytes=0-9", Some("not a date")).ranges(100, None, modified), Ranges::Full);
+        assert_eq!(request("bytes=0-9", modified).ranges(100, None, None), Ranges::Full);
+    }
+
+    #[test]
+    fn apply_cuts_a_buffered_body() {
+        let resp = ResponseBuilder::raw(StatusCode::Ok, "text/plain", "0123456789").build();
+        let resp = request("bytes=2-4", None).apply(resp);
+        assert_eq!(resp.get_status(), StatusCode::PartialContent);
+        assert_eq!(resp.get_header("Content-Range").as_deref(), Some("bytes 2-4/10"));
+        assert_eq!(&resp.get_body_bytes()[..], b"234");
+
+        let resp = ResponseBuilder::raw(StatusCode::Ok, "text/plain", "0123456789").build();
+        let resp = request("bytes=20-", None).apply(resp);
+        assert_eq!(resp.get_status(), StatusCode::RangeNotSatisfiable);
+        assert_eq!(resp.get_header("Content-Range").as_deref(), Some("bytes */10"));
+    }
+
+    #[test]
+    fn apply_sends_several_ranges_as_multipart() {
+        let resp = ResponseBuilder::raw(StatusCode::Ok, "text/plain", "0123456789").build();
+        let resp = request("bytes=0-1,8-9", None).apply(resp);
+        let content_type = resp.get_header("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8_lossy(&resp.get_body_bytes()), expected);
+    }
+
+    #[test]
+    fn apply_leaves_other_responses_alone() {
+        let resp = ResponseBuilder::raw(StatusCode::NotFound, "text/plain", "missing").build();
+        let resp = request("bytes=0-1", None).apply(resp);
+        assert_eq!(resp.get_status(), StatusCode::NotFound);
+        assert!(resp.get_header("Accept-Ranges").is_none());
+    }
+
+    #[tokio::test]
+    async fn slices_a_stream_across_chunks() {
+        let body = b"abcdefghijklmnopqrstuvwxyz";
+        let parts = vec![
+            (Bytes::from_static(b"["), range(2, 4)),
+            (Bytes::from_static(b"|"), range(6, 13)),
+            (Bytes::from_static(b"|"), range(25, 25)),
+        ];
+        for size in [1, 3, 5, 26] {
+            let sliced = slice_stream(chunked(body, size), parts.clone(), Bytes::from_static(b"]"));
+            assert_eq!(collect(sliced).await.unwrap(), b"[cde|ghijklmn|z]", "chunks of {}", size);
+        }
+    }
+
+    #[tokio::test]
+    async fn slicing_stops_after_the_last_range() {
+        let body: BodyStream = Box::pin(
+            futures_util::stream::iter(vec![Ok(Bytes::from_static(b"0123"))])
+                .chain(futures_util::stream::once(async { panic!("read past the last range") })),
+        );
+        let sliced = slice_stream(body, vec![(Bytes::new(), range(1, 2))], Bytes::new());
+        assert_eq!(collect(sliced).await.unwrap(), b"12");
+    }
+
+    #[tokio::test]
+    async fn short_stream_is_an_error() {
+        let sliced = slice_stream(chunked(b"0123", 2), vec![(Bytes::new(), range(2, 9))], Bytes::new());
+        let err = collect(sliced).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
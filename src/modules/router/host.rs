@@ -0,0 +1,157 @@
+use crate::modules::router::Router;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HostPattern {
+    /// `api.example.com`
+    Exact(String),
+    /// `*.example.com`, matches any subdomain of `example.com`
+    Wildcard(String),
+    /// `*`, matches every host
+    Any,
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Self {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        if pattern == "*" {
+            HostPattern::Any
+        } else if let Some(domain) = pattern.strip_prefix("*.") {
+            HostPattern::Wildcard(domain.to_string())
+        } else {
+            HostPattern::Exact(pattern)
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        match self {
+            HostPattern::Exact(name) => host.eq_ignore_ascii_case(name),
+            HostPattern::Wildcard(domain) => host
+                .to_ascii_lowercase()
+                .strip_suffix(domain.as_str())
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            HostPattern::Any => true,
+        }
+    }
+
+    /// Exact names win over wildcards, longer wildcards over shorter ones
+    pub(crate) fn priority(&self) -> usize {
+        match self {
+            HostPattern::Exact(_) => usize::MAX,
+            HostPattern::Wildcard(domain) => domain.len(),
+            HostPattern::Any => 0,
+        }
+    }
+}
+
+impl Display for HostPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostPattern::Exact(name) => write!(f, "{}", name),
+            HostPattern::Wildcard(domain) => write!(f, "*.{}", domain),
+            HostPattern::Any => write!(f, "*"),
+        }
+    }
+}
+
+/// Routers selected by the `Host` header before method/path matching
+pub(crate) struct VirtualHosts {
+    hosts: Vec<(HostPattern, Router)>,
+    default: Router,
+}
+
+impl VirtualHosts {
+    pub fn new(default: Router) -> Self {
+        Self {
+            hosts: Vec::new(),
+            default,
+        }
+    }
+
+    pub fn insert(&mut self, pattern: HostPattern, router: Router) {
+        self.hosts.push((pattern, router));
+        self.hosts
+            .sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.priority()));
+    }
+
+    pub fn select(&self, host: Option<&str>) -> &Router {
+        if let Some(host) = host {
+            for (pattern, router) in &self.hosts {
+                if pattern.matches(host) {
+                    return router;
+                }
+            }
+        }
+        &self.default
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::method::Method;
+    use crate::http::request::Request;
+    use crate::http::response::{Response, ResponseBuilder};
+    use crate::http::status::StatusCode;
+    use crate::modules::router::route::Route;
+    use std::future::Future;
+    use std::pin::Pin;
+
+    fn ok(_: Request) -> Pin<Box<dyn Future<Output = Response> + Send + Sync>> {
+        Box::pin(async { ResponseBuilder::new(StatusCode::Ok, "ok").build() })
+    }
+
+    /// Router with a single route named after it
+    fn router(name: &str) -> Router {
+        Router::new().routes(vec![Route::new(Method::GET, &format!("/{}", name), ok)])
+    }
+
+    fn selected<'a>(hosts: &VirtualHosts, host: Option<&str>, names: &[&'a str]) -> &'a str {
+        let router = hosts.select(host);
+        names
+            .iter()
+            .find(|name| router.get((Method::GET, format!("/{}", name))).is_some())
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_patterns() {
+        let exact = HostPattern::Exact("api.example.com".into());
+        assert_eq!(HostPattern::parse(" API.Example.com "), exact);
+        let wildcard = HostPattern::Wildcard("example.com".into());
+        assert_eq!(HostPattern::parse("*.example.com"), wildcard);
+        assert_eq!(HostPattern::parse("*"), HostPattern::Any);
+    }
+
+    #[test]
+    fn matches_subdomains_only_with_wildcards() {
+        let wildcard = HostPattern::parse("*.example.com");
+        assert!(wildcard.matches("api.example.com"));
+        assert!(wildcard.matches("a.b.Example.com."));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+        assert!(HostPattern::parse("example.com").matches("EXAMPLE.com."));
+    }
+
+    #[test]
+    fn prefers_the_most_specific_pattern() {
+        let names = ["default", "any", "wildcard", "api_wildcard", "exact"];
+        // Inserted from the least specific, the order must not matter
+        let mut hosts = VirtualHosts::new(router("default"));
+        hosts.insert(HostPattern::parse("*"), router("any"));
+        hosts.insert(HostPattern::parse("*.example.com"), router("wildcard"));
+        hosts.insert(HostPattern::parse("*.api.example.com"), router("api_wildcard"));
+        hosts.insert(HostPattern::parse("api.example.com"), router("exact"));
+
+        assert_eq!(selected(&hosts, Some("api.example.com"), &names), "exact");
+        assert_eq!(selected(&hosts, Some("v1.api.example.com"), &names), "api_wildcard");
+        assert_eq!(selected(&hosts, Some("www.example.com"), &names), "wildcard");
+        assert_eq!(selected(&hosts, Some("example.org"), &names), "any");
+        assert_eq!(selected(&hosts, None, &names), "default");
+
+        let mut hosts = VirtualHosts::new(router("default"));
+        hosts.insert(HostPattern::parse("*.example.com"), router("wildcard"));
+        assert_eq!(selected(&hosts, Some("example.org"), &names), "default");
+    }
+}
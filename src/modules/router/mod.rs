@@ -1,10 +1,23 @@
 use crate::http::method::Method;
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::modules::middleware::{self, Middleware};
 use crate::modules::router::route::Route;
+use crate::modules::state::{State, StateMap};
+use serde::Deserialize;
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+pub mod host;
 pub mod route;
 
 pub struct Router {
     routes: HashMap<(Method, String), Route>,
+    /// Routes matching a whole subtree, longest prefix first
+    prefixes: Vec<(Method, String)>,
+    middleware: Vec<Middleware>,
+    states: StateMap,
+    max_body_size: Option<usize>,
 }
 
 impl Default for Router {
@@ -17,18 +30,118 @@ impl Router {
     pub fn new() -> Self {
         Self {
             routes: HashMap::new(),
+            prefixes: Vec::new(),
+            middleware: Vec::new(),
+            states: StateMap::new(),
+            max_body_size: None,
         }
     }
 
     pub fn insert(&mut self, key: (Method, String), route: Route) {
+        self.prefixes.retain(|prefix| *prefix != key);
+        if route.is_prefix() {
+            self.prefixes.push(key.clone());
+            self.prefixes
+                .sort_by_key(|(_, prefix)| std::cmp::Reverse(prefix.len()));
+        }
         self.routes.insert(key, route);
     }
 
+    /// Route for exactly this path, or else the one mounted on the longest prefix of it
     pub fn get(&self, key: (Method, String)) -> Option<&Route> {
-        self.routes.get(&key)
+        if let Some(route) = self.routes.get(&key) {
+            return Some(route);
+        }
+        let (method, path) = key;
+        let path = path.split('?').next().unwrap_or_default();
+        self.prefixes
+            .iter()
+            .find(|(prefix_method, prefix)| {
+                *prefix_method == method && is_under(path, prefix.trim_end_matches('/'))
+            })
+            .and_then(|key| self.routes.get(key))
+    }
+}
+
+impl Router {
+    pub fn routes(mut self, routes: Vec<Route>) -> Self {
+        for route in routes {
+            self.insert(route.get(), route)
+        }
+        self
+    }
+
+    /// Middleware for every route of this router, including nested ones
+    pub fn middleware<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Request, Response>> + Send + 'static,
+    {
+        self.middleware.push(middleware::middleware(f));
+        self
+    }
+
+    /// State visible only to the routes of this router. It shadows a server
+    /// state with the same name
+    pub fn state<T: Sync + Send + 'static + for<'a> Deserialize<'a>>(
+        mut self,
+        state: (String, State<T>),
+    ) -> Self {
+        self.states.insert(state.0, Arc::new(state.1));
+        self
+    }
+
+    /// Largest body accepted by the routes of this router, including nested
+    /// ones, unless they set their own
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.max_body_size = Some(bytes);
+        self
+    }
+
+    /// Mounts all routes of `router` under `prefix`
+    pub fn nest(mut self, prefix: &str, router: Router) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        for mut route in router.into_routes() {
+            let (_, path) = route.get();
+            if path == "/" && !prefix.is_empty() {
+                route.set_path(prefix.to_string());
+            } else {
+                route.set_path(format!("{}{}", prefix, path));
+            }
+            self.insert(route.get(), route)
+        }
+        self
+    }
+
+    /// Builds a nested router in place, e.g.
+    /// `router.group("/admin", |group| group.middleware(auth).routes(routes!(stats)))`
+    pub fn group<F: FnOnce(Router) -> Router>(self, prefix: &str, f: F) -> Self {
+        self.nest(prefix, f(Router::new()))
+    }
+
+    /// Adds all routes of `router` as they are
+    pub fn merge(self, router: Router) -> Self {
+        self.nest("", router)
+    }
+
+    /// Hands out the routes with this router's middleware and states applied
+    pub(crate) fn into_routes(self) -> Vec<Route> {
+        let mut routes = Vec::with_capacity(self.routes.len());
+        for (_, mut route) in self.routes {
+            route.wrap_middleware(&self.middleware);
+            route.wrap_states(&self.states);
+            route.wrap_max_body_size(self.max_body_size);
+            routes.push(route);
+        }
+        routes
     }
 }
 
+fn is_under(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
 #[macro_export]
 macro_rules! routes {
     ($elem:expr; $n:expr) => (
@@ -41,3 +154,89 @@ macro_rules! routes {
         Vec::new()
     )
  }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request::RequestBuilder;
+    use crate::http::response::ResponseBuilder;
+    use crate::http::status::StatusCode;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+
+    type BoxedHandler = Pin<Box<dyn Future<Output = Response> + Send + Sync>>;
+    type Log = Arc<Mutex<Vec<&'static str>>>;
+
+    fn ok(_: Request) -> BoxedHandler {
+        Box::pin(async { ResponseBuilder::new(StatusCode::Ok, "ok").build() })
+    }
+
+    fn get(path: &str) -> Route {
+        Route::new(Method::GET, path, ok)
+    }
+
+    /// Middleware noting `name` in `log` when it runs
+    fn logged(router: Router, log: &Log, name: &'static str) -> Router {
+        let log = log.clone();
+        router.middleware(move |req| {
+            log.lock().unwrap().push(name);
+            async move { Ok(req) }
+        })
+    }
+
+    /// Names of the middleware run for `path`, and whether they let the request through
+    async fn run(
+        routes: &[Route],
+        log: &Log,
+        path: &str,
+        req: Request,
+    ) -> (Vec<&'static str>, bool) {
+        log.lock().unwrap().clear();
+        let route = routes.iter().find(|route| route.get().1 == path).unwrap();
+        let passed = matches!(route.run_middleware(req).await, Ok(Ok(_)));
+        (log.lock().unwrap().clone(), passed)
+    }
+
+    #[test]
+    fn prefixes_the_paths_of_nested_groups() {
+        let router = Router::new().routes(vec![get("/")]).group("/api/", |api| {
+            api.routes(vec![get("/"), get("/users")])
+                .group("/admin", |admin| admin.routes(vec![get("/stats")]))
+        });
+        let mut paths: Vec<_> = router.into_routes().iter().map(|route| route.get().1).collect();
+        paths.sort();
+        assert_eq!(paths, ["/", "/api", "/api/admin/stats", "/api/users"]);
+    }
+
+    #[tokio::test]
+    async fn runs_outer_group_middleware_first() {
+        let log = Log::default();
+        let router = logged(Router::new(), &log, "server").group("/api", |api| {
+            logged(api, &log, "api")
+                .middleware(|req: Request| async move {
+                    match req.get_header("Authorization") {
+                        Some(_) => Ok(req),
+                        None => Err(ResponseBuilder::new(StatusCode::Forbidden, "").build()),
+                    }
+                })
+                .routes(vec![get("/health")])
+                .group("/admin", |admin| logged(admin, &log, "admin").routes(vec![get("/stats")]))
+        });
+        let routes = router.into_routes();
+        let authorized = || {
+            RequestBuilder::new(Method::GET, "")
+                .set_header("Authorization", "Bearer token")
+                .build()
+        };
+
+        let stats = run(&routes, &log, "/api/admin/stats", authorized()).await;
+        assert_eq!(stats, (vec!["server", "api", "admin"], true));
+        let health = run(&routes, &log, "/api/health", authorized()).await;
+        assert_eq!(health, (vec!["server", "api"], true));
+
+        // A rejection stops the middleware of inner groups from running
+        let anonymous = RequestBuilder::new(Method::GET, "").build();
+        let stats = run(&routes, &log, "/api/admin/stats", anonymous).await;
+        assert_eq!(stats, (vec!["server", "api"], false));
+    }
+}
@@ -1,43 +1,304 @@
 use crate::http::method::Method;
 use crate::http::request::Request;
 use crate::http::response::responder::Responder;
-use crate::http::response::Response;
+use crate::http::response::sse::Sse;
+use crate::http::response::{Response, ResponseBuilder};
 use std::future::Future;
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::Stream;
+use crate::http::status::StatusCode;
+use crate::modules::conditional::{Preconditions, Resources, Validators};
+use crate::modules::files::{ServeDir, ServeFile};
+use crate::modules::middleware::Middleware;
+use crate::modules::panic_guard::{self, CaughtPanic, Rejection};
+use crate::modules::state::StateMap;
+use crate::modules::websocket::{WebSocket, WebSocketUpgrade};
+use std::sync::{Arc, RwLock};
+use tokio::sync::Semaphore;
 
 type FuncPointer = fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send + Sync>>;
+type Fabric = Arc<dyn Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync>;
+type Validator =
+    Arc<dyn Fn(&Request) -> Pin<Box<dyn Future<Output = Option<Validators>> + Send>> + Send + Sync>;
+
 pub struct Route {
     method: Method,
     path: String,
-    fabric: Arc<FuncPointer>,
+    fabric: Fabric,
+    middleware: Vec<Middleware>,
+    states: StateMap,
+    resolved_states: Option<Arc<RwLock<StateMap>>>,
+    max_body_size: Option<usize>,
+    concurrency: Option<Arc<Semaphore>>,
+    prefix: bool,
+    validator: Option<Validator>,
 }
 
 impl Route {
-    pub fn run_fabric<T: Stream + AsyncWriteExt + AsyncReadExt + Unpin + Send + 'static>(&self, mut stream: T, req: Request) {
+    /// Runs the middleware and the handler. Writes with preconditions are checked
+    /// first, holding the lock of their resource until the handler is done
+    pub(crate) async fn run_fabric(
+        &self,
+        req: Request,
+        resources: Arc<Resources>,
+    ) -> Result<Response, CaughtPanic> {
         let fabric = self.fabric.clone();
-        tokio::spawn(async move {
+        // Middleware already ran when the request was checked before its body was read
+        let middleware = if req.is_preflighted() {
+            Vec::new()
+        } else {
+            self.middleware.clone()
+        };
+        let mount_path = self.prefix.then(|| self.path.clone());
+        // Reads are checked against the response they get
+        let write = req.get_method() != Method::GET;
+        let validator = self.validator.clone();
+        panic_guard::catch(async move {
+            let mut req = req;
+            if let Some(path) = mount_path {
+                req.set_mount_path(&path);
+            }
+            for middleware in middleware {
+                req = match middleware(req).await {
+                    Ok(req) => req,
+                    Err(resp) => return resp,
+                };
+            }
+            if !write {
+                return fabric(req).await;
+            }
+            let key = Resources::key(&req);
+            let preconditions = Preconditions::from_request(&req).filter(Preconditions::for_writes);
+            let _guard = match preconditions {
+                Some(preconditions) => {
+                    let guard = resources.lock(&key).await;
+                    let current = match &validator {
+                        Some(validator) => Some(validator(&req).await),
+                        None => resources.get_sent(&key),
+                    };
+                    let checked = match current {
+                        Some(current) => preconditions.evaluate(current.as_ref(), false),
+                        // Nothing is known to check the write against
+                        None => Err(StatusCode::PreconditionFailed),
+                    };
+                    if let Err(status) = checked {
+                        return ResponseBuilder::new(status, "").build();
+                    }
+                    Some(guard)
+                }
+                None => None,
+            };
             let resp = fabric(req).await;
-            stream
-                .write(resp.into_response().to_string().as_bytes()).await
-                .unwrap()
-        });
+            resources.written(&key, &resp);
+            resp
+        })
+        .await
+    }
+
+    /// Runs only the middleware, `Err` holds the response of the one that refused the request
+    pub(crate) async fn run_middleware(
+        &self,
+        req: Request,
+    ) -> Result<Result<Request, Response>, CaughtPanic> {
+        let middleware = self.middleware.clone();
+        panic_guard::catch(async move {
+            let mut req = req;
+            for middleware in middleware {
+                req = middleware(req).await?;
+            }
+            Ok(req)
+        })
+        .await
     }
 }
 
 impl Route {
     pub fn new(method: Method, path: &str, fabric: FuncPointer) -> Self {
+        Self::with_fabric(
+            method,
+            path,
+            Arc::new(move |req: Request| {
+                let body_error = req.body_error();
+                // The route macros take the handler arguments out of the request before
+                // returning its future, so a panic here comes from an extractor
+                match std::panic::catch_unwind(AssertUnwindSafe(|| fabric(req))) {
+                    Ok(fut) => fut,
+                    Err(payload) => match body_error.lock().unwrap().take() {
+                        Some(message) => std::panic::resume_unwind(Box::new(Rejection(message))),
+                        None => std::panic::resume_unwind(payload),
+                    },
+                }
+            }),
+        )
+    }
+
+    /// WebSocket endpoint on `GET path`. The handshake is answered with
+    /// `101 Switching Protocols`, then `handler` gets the request and the socket
+    pub fn ws<F, Fut>(path: &str, handler: F) -> Self
+    where
+        F: Fn(Request, WebSocket) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        Self::with_fabric(
+            Method::GET,
+            path,
+            Arc::new(move |req| {
+                let handler = handler.clone();
+                let resp = match WebSocketUpgrade::from_request(&req) {
+                    Ok(upgrade) => upgrade.on_upgrade(move |socket| handler(req, socket)),
+                    Err(resp) => resp,
+                };
+                Box::pin(std::future::ready(resp))
+            }),
+        )
+    }
+
+    /// Server-sent events on `GET path`. `handler` gets the request, e.g. to
+    /// resume after `Last-Event-ID`
+    pub fn sse<F, Fut>(path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Sse> + Send + 'static,
+    {
+        Self::with_fabric(
+            Method::GET,
+            path,
+            Arc::new(move |req| {
+                let events = handler(req);
+                Box::pin(async move { events.await.into_response() })
+            }),
+        )
+    }
+
+    /// Files of a directory on `GET prefix/...`, e.g.
+    /// `Route::serve_dir("/assets", ServeDir::new("public"))`
+    pub fn serve_dir(prefix: &str, dir: ServeDir) -> Self {
+        let dir = Arc::new(dir);
+        let mut route = Self::with_fabric(
+            Method::GET,
+            prefix,
+            Arc::new(move |req| {
+                let dir = dir.clone();
+                Box::pin(async move {
+                    let mount = req.get_mount_path().unwrap_or_default();
+                    dir.serve(&req, &mount).await
+                })
+            }),
+        );
+        route.prefix = true;
+        route
+    }
+
+    /// A single file on `GET path`
+    pub fn serve_file(path: &str, file: ServeFile) -> Self {
+        let file = Arc::new(file);
+        Self::with_fabric(
+            Method::GET,
+            path,
+            Arc::new(move |req| {
+                let file = file.clone();
+                Box::pin(async move { file.serve(&req).await })
+            }),
+        )
+    }
+
+    fn with_fabric(method: Method, path: &str, fabric: Fabric) -> Self {
         Self {
             method,
             path: path.to_string(),
-            fabric: Arc::new(fabric),
+            fabric,
+            middleware: Vec::new(),
+            states: StateMap::new(),
+            resolved_states: None,
+            max_body_size: None,
+            concurrency: None,
+            prefix: false,
+            validator: None,
         }
     }
 
+    /// Looks up the current version of the resource so `If-Match`,
+    /// `If-None-Match` and `If-Unmodified-Since` on writes are checked before
+    /// the handler runs, `None` when it doesn't exist. Without a validator
+    /// writes are checked against the last GET response for the same path, and
+    /// refused with 412 when the resource hasn't been read since it last changed.
+    /// Conditional writes to one resource run one at a time, so two of them
+    /// can't both pass against the same version
+    pub fn validator<F, Fut>(mut self, validator: F) -> Self
+    where
+        F: Fn(&Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<Validators>> + Send + 'static,
+    {
+        self.validator = Some(Arc::new(move |req| Box::pin(validator(req))));
+        self
+    }
+
+    /// Requests handled at the same time, others wait for a free slot
+    pub fn max_concurrency(mut self, limit: usize) -> Self {
+        self.concurrency = Some(Arc::new(Semaphore::new(limit)));
+        self
+    }
+
+    /// Largest body this route accepts, instead of the server or router limit
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.max_body_size = Some(bytes);
+        self
+    }
+
     #[inline]
     pub fn get(&self) -> (Method, String) {
         (self.method.clone(), self.path.clone())
     }
+
+    pub(crate) fn set_path(&mut self, path: String) {
+        self.path = path;
+    }
+
+    /// Puts group middleware in front of the ones already attached,
+    /// so outer groups run first
+    pub(crate) fn wrap_middleware(&mut self, middleware: &[Middleware]) {
+        self.middleware.splice(0..0, middleware.iter().cloned());
+    }
+
+    /// Adds group states, keeping the ones set by inner groups
+    pub(crate) fn wrap_states(&mut self, states: &StateMap) {
+        for (key, state) in states {
+            self.states
+                .entry(key.clone())
+                .or_insert_with(|| state.clone());
+        }
+    }
+
+    /// Layers the group states over the server-wide ones
+    pub(crate) fn resolve_states(&mut self, global: &Arc<RwLock<StateMap>>) {
+        if self.states.is_empty() {
+            return;
+        }
+        let mut states = global.read().unwrap().clone();
+        states.extend(self.states.drain());
+        self.resolved_states = Some(Arc::new(RwLock::new(states)));
+    }
+
+    pub(crate) fn get_states(&self) -> Option<Arc<RwLock<StateMap>>> {
+        self.resolved_states.clone()
+    }
+
+    /// Takes the group limit unless the route or an inner group set one
+    pub(crate) fn wrap_max_body_size(&mut self, bytes: Option<usize>) {
+        self.max_body_size = self.max_body_size.or(bytes);
+    }
+
+    pub(crate) fn get_max_body_size(&self) -> Option<usize> {
+        self.max_body_size
+    }
+
+    /// Whether the route also matches every path below its own
+    pub(crate) fn is_prefix(&self) -> bool {
+        self.prefix
+    }
+
+    pub(crate) fn get_concurrency(&self) -> Option<Arc<Semaphore>> {
+        self.concurrency.clone()
+    }
 }
@@ -0,0 +1,57 @@
+use crate::modules::metrics::ServerMetrics;
+use crate::modules::shutdown::ShutdownSummary;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// Server running on a background task, returned by `NuttServer::spawn`
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    metrics: ServerMetrics,
+    shutdown: Arc<Notify>,
+    join: JoinHandle<Result<ShutdownSummary>>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        local_addrs: Vec<SocketAddr>,
+        metrics: ServerMetrics,
+        shutdown: Arc<Notify>,
+        join: JoinHandle<Result<ShutdownSummary>>,
+    ) -> Self {
+        Self {
+            local_addrs,
+            metrics,
+            shutdown,
+            join,
+        }
+    }
+
+    /// Address of the first TCP listener, with the real port when bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// Addresses of all TCP listeners, in the order they were added
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Connection and load shedding counters of the running server
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.metrics
+    }
+
+    /// Starts a graceful shutdown, `join` waits for it to finish
+    pub fn shutdown(&self) {
+        self.shutdown.notify_one();
+    }
+
+    /// Waits until the server has stopped
+    pub async fn join(self) -> Result<ShutdownSummary> {
+        self.join.await?
+    }
+}
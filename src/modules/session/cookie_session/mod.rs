@@ -22,6 +22,12 @@ impl CookieSession {
     }
 }
 
+impl Default for CookieSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CookieSession {
     pub fn new() -> Self {
         Self {
@@ -39,11 +45,7 @@ impl CookieSession {
 
     pub fn get_session_data(&self, id: SessionId) -> Option<Data> {
         if let Ok(session) = self.sessions.try_read() {
-            return if let Some(data) = session.get(&id) {
-                Some(data.clone())
-            } else {
-                None
-            };
+            return session.get(&id).cloned();
         }
         None
     }
@@ -52,6 +54,8 @@ impl CookieSession {
 #[derive(Hash, Clone, Eq, PartialEq, Debug)]
 pub struct SessionId(String);
 
+// A default ID would be a random one, so only `new` makes them
+#[allow(clippy::new_without_default)]
 impl SessionId {
     pub fn new() -> Self {
         let mut hasher = Whirlpool::new();
@@ -0,0 +1,166 @@
+use crate::http::response::BodyStream;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+pub type ShutdownHook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+/// What happened to the open connections when the server stopped
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownSummary {
+    drained_connections: usize,
+    aborted_connections: usize,
+    aborted_requests: usize,
+    elapsed: Duration,
+}
+
+impl ShutdownSummary {
+    /// Connections that were closed after finishing their requests
+    pub fn get_drained_connections(&self) -> usize {
+        self.drained_connections
+    }
+
+    /// Connections still open when the deadline passed
+    pub fn get_aborted_connections(&self) -> usize {
+        self.aborted_connections
+    }
+
+    /// Requests that were still being handled when the deadline passed
+    pub fn get_aborted_requests(&self) -> usize {
+        self.aborted_requests
+    }
+
+    /// Time between the shutdown signal and the last connection closing
+    pub fn get_elapsed(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+impl Display for ShutdownSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} connections drained, {} connections and {} requests aborted in {:?}",
+            self.drained_connections, self.aborted_connections, self.aborted_requests, self.elapsed
+        )
+    }
+}
+
+impl ShutdownSummary {
+    pub(crate) fn new(
+        drained_connections: usize,
+        aborted_connections: usize,
+        aborted_requests: usize,
+        elapsed: Duration,
+    ) -> Self {
+        Self {
+            drained_connections,
+            aborted_connections,
+            aborted_requests,
+            elapsed,
+        }
+    }
+}
+
+/// Shared by all connections, tells them when to stop taking new requests
+/// and counts the connections and requests still open
+#[derive(Debug, Clone)]
+pub(crate) struct Drain {
+    signal: watch::Receiver<bool>,
+    connections: Arc<AtomicUsize>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drain {
+    pub fn new() -> (watch::Sender<bool>, Self) {
+        let (sender, signal) = watch::channel(false);
+        (
+            sender,
+            Self {
+                signal,
+                connections: Arc::new(AtomicUsize::new(0)),
+                in_flight: Arc::new(AtomicUsize::new(0)),
+            },
+        )
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Resolves once the server starts shutting down
+    pub async fn wait(&mut self) {
+        let _ = self.signal.wait_for(|draining| *draining).await;
+    }
+
+    /// Marks a request as in flight until the guard is dropped
+    pub fn request(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.in_flight.clone())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Marks a connection as open until the guard is dropped
+    pub fn connection(&self) -> InFlight {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.connections.clone())
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Ends `body` when the server starts draining, for streams such as
+/// server-sent events that may never end on their own
+pub(crate) fn until_drained(body: BodyStream, drain: Drain) -> BodyStream {
+    let stream = futures_util::stream::unfold(Some((body, drain)), |state| async move {
+        let (mut body, mut drain) = state?;
+        tokio::select! {
+            biased;
+            _ = drain.wait() => None,
+            chunk = futures_util::StreamExt::next(&mut body) => {
+                chunk.map(|chunk| (chunk, Some((body, drain))))
+            }
+        }
+    });
+    Box::pin(stream)
+}
+
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Resolves on SIGINT (Ctrl+C) or SIGTERM
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
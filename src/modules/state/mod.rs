@@ -1,5 +1,9 @@
+use std::any::Any;
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+pub type StateMap = HashMap<String, Arc<dyn Any + Send + Sync>>;
+
 #[derive(Debug, Clone)]
 pub struct State<T>(Arc<RwLock<T>>);
 
@@ -7,12 +11,12 @@ impl<T> State<T> {
     pub fn new(value: T) -> Self {
         Self(Arc::new(RwLock::new(value)))
     }
-    pub fn read(&self) -> RwLockReadGuard<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
         while self.0.try_read().is_err() {}
         self.0.read().unwrap()
     }
 
-    pub fn write(&self) -> RwLockWriteGuard<T> {
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
         while self.0.try_write().is_err() {}
         self.0.write().unwrap()
     }
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+/// Deadlines that keep slow or stalled clients from holding a connection open
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    handshake: Duration,
+    header_read: Duration,
+    body_read: Duration,
+    handler: Option<Duration>,
+    write: Duration,
+    min_body_rate: Option<u64>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Self {
+            handshake: Duration::from_secs(10),
+            header_read: Duration::from_secs(30),
+            body_read: Duration::from_secs(60),
+            handler: None,
+            write: Duration::from_secs(30),
+            min_body_rate: None,
+        }
+    }
+
+    /// TLS handshake and HTTP/2 connection setup, the connection is closed when it passes
+    pub fn handshake(mut self, timeout: Duration) -> Self {
+        self.handshake = timeout;
+        self
+    }
+
+    /// Request line and headers, answered with 408. Also how long an idle
+    /// keep-alive connection stays open, which is closed without a response
+    pub fn header_read(mut self, timeout: Duration) -> Self {
+        self.header_read = timeout;
+        self
+    }
+
+    /// Whole request body, answered with 408
+    pub fn body_read(mut self, timeout: Duration) -> Self {
+        self.body_read = timeout;
+        self
+    }
+
+    /// Handler and its middleware, answered with 503. Unlimited by default
+    pub fn handler(mut self, timeout: Duration) -> Self {
+        self.handler = Some(timeout);
+        self
+    }
+
+    /// Sending a response, the connection is closed when it passes
+    pub fn write(mut self, timeout: Duration) -> Self {
+        self.write = timeout;
+        self
+    }
+
+    /// Answers with 408 when a body arrives slower than `bytes_per_second`
+    /// on average, measured after the first second
+    pub fn min_body_rate(mut self, bytes_per_second: u64) -> Self {
+        self.min_body_rate = Some(bytes_per_second);
+        self
+    }
+
+    pub(crate) fn get_handshake(&self) -> Duration {
+        self.handshake
+    }
+
+    pub(crate) fn get_header_read(&self) -> Duration {
+        self.header_read
+    }
+
+    pub(crate) fn get_body_read(&self) -> Duration {
+        self.body_read
+    }
+
+    pub(crate) fn get_handler(&self) -> Option<Duration> {
+        self.handler
+    }
+
+    pub(crate) fn get_write(&self) -> Duration {
+        self.write
+    }
+
+    pub(crate) fn get_min_body_rate(&self) -> Option<u64> {
+        self.min_body_rate
+    }
+}
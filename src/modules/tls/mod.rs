@@ -0,0 +1,218 @@
+mod peer_certificate;
+
+pub use peer_certificate::PeerCertificate;
+
+use crate::modules::router::host::HostPattern;
+use crate::modules::shutdown::Drain;
+use anyhow::{Context, Result};
+use rustls::crypto::aws_lc_rs::sign::any_supported_type;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::RootCertStore;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing_log::log::{log, Level};
+
+#[derive(Debug, Clone)]
+struct CertSource {
+    /// `None` for the default certificate
+    pattern: Option<HostPattern>,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    pattern: Option<HostPattern>,
+    key: Arc<CertifiedKey>,
+    modified: Option<SystemTime>,
+}
+
+/// Picks the server certificate by the SNI name of the client hello.
+/// Clones share the same certificates, so a clone kept by the application
+/// can call `reload_certs` while the server is running
+#[derive(Debug, Clone, Default)]
+pub struct CertResolver {
+    sources: Arc<RwLock<Vec<CertSource>>>,
+    loaded: Arc<RwLock<Vec<LoadedCert>>>,
+}
+
+impl CertResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn add(&self, pattern: Option<HostPattern>, cert: &str, key: &str) {
+        let mut sources = self.sources.write().unwrap();
+        sources.retain(|source| source.pattern != pattern);
+        sources.push(CertSource {
+            pattern,
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.read().unwrap().is_empty()
+    }
+
+    /// Reads every certificate again. Nothing is replaced if one of them fails
+    /// to load; connections already established keep their certificate
+    pub fn reload_certs(&self) -> Result<()> {
+        let sources = self.sources.read().unwrap().clone();
+        let mut loaded = Vec::with_capacity(sources.len());
+        for source in sources {
+            let certs = load_certs(&source.cert)?;
+            let key = load_private_key(&source.key)?;
+            let signing_key = any_supported_type(&key)
+                .with_context(|| format!("unsupported private key {}", source.key.display()))?;
+            loaded.push(LoadedCert {
+                pattern: source.pattern,
+                key: Arc::new(CertifiedKey::new(certs, signing_key)),
+                modified: modified(&source.cert, &source.key),
+            });
+        }
+        loaded.sort_by_key(|cert| {
+            std::cmp::Reverse(cert.pattern.as_ref().map_or(0, |pattern| pattern.priority()))
+        });
+        *self.loaded.write().unwrap() = loaded;
+        log!(Level::Info, "TLS certificates loaded");
+        Ok(())
+    }
+
+    /// Reloads the certificates whenever one of their files changes on disk,
+    /// until the server starts shutting down
+    pub(crate) async fn watch(self, interval: Duration, mut drain: Drain) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = drain.wait() => return,
+            }
+            if self.is_changed() {
+                if let Err(e) = self.reload_certs() {
+                    log!(Level::Error, "Failed to reload TLS certificates: {:#}", e);
+                }
+            }
+        }
+    }
+
+    /// Whether both are clones of the same resolver
+    pub(crate) fn shares_certs(&self, other: &CertResolver) -> bool {
+        Arc::ptr_eq(&self.loaded, &other.loaded)
+    }
+
+    fn is_changed(&self) -> bool {
+        let sources = self.sources.read().unwrap();
+        let loaded = self.loaded.read().unwrap();
+        sources.iter().any(|source| {
+            let current = modified(&source.cert, &source.key);
+            !loaded
+                .iter()
+                .any(|cert| cert.pattern == source.pattern && cert.modified == current)
+        })
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.read().unwrap();
+        if let Some(name) = client_hello.server_name() {
+            let matched = loaded.iter().find(|cert| {
+                cert.pattern
+                    .as_ref()
+                    .is_some_and(|pattern| pattern.matches(name))
+            });
+            if let Some(cert) = matched {
+                return Some(cert.key.clone());
+            }
+        }
+        loaded
+            .iter()
+            .find(|cert| cert.pattern.is_none())
+            .map(|cert| cert.key.clone())
+    }
+}
+
+/// Client certificate verification for mutual TLS
+#[derive(Debug, Clone)]
+pub struct ClientAuth {
+    ca: PathBuf,
+    crls: Vec<PathBuf>,
+    required: bool,
+}
+
+impl ClientAuth {
+    /// Rejects the handshake unless the client presents a certificate
+    /// issued by one of the CAs in the `ca` bundle
+    pub fn required(ca: &str) -> Self {
+        Self {
+            ca: PathBuf::from(ca),
+            crls: vec![],
+            required: true,
+        }
+    }
+
+    /// Verifies a client certificate if one is presented, but also
+    /// accepts clients without one
+    pub fn optional(ca: &str) -> Self {
+        Self {
+            required: false,
+            ..Self::required(ca)
+        }
+    }
+
+    /// Certificate revocation list in PEM format
+    pub fn crl(mut self, path: &str) -> Self {
+        self.crls.push(PathBuf::from(path));
+        self
+    }
+
+    pub(crate) fn verifier(&self) -> Result<Arc<dyn ClientCertVerifier>> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&self.ca)? {
+            roots
+                .add(cert)
+                .with_context(|| format!("invalid CA certificate in {}", self.ca.display()))?;
+        }
+        let mut crls = vec![];
+        for path in &self.crls {
+            let list = CertificateRevocationListDer::pem_file_iter(path)
+                .with_context(|| format!("cannot open CRL file {}", path.display()))?
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("cannot parse CRL file {}", path.display()))?;
+            crls.extend(list);
+        }
+        let mut builder = WebPkiClientVerifier::builder(Arc::new(roots)).with_crls(crls);
+        if !self.required {
+            builder = builder.allow_unauthenticated();
+        }
+        Ok(builder.build()?)
+    }
+}
+
+pub fn load_certs(filename: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(filename)
+        .with_context(|| format!("cannot open certificate file {}", filename.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("cannot parse certificate file {}", filename.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates in {}", filename.display());
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(filename: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(filename)
+        .with_context(|| format!("cannot read private key file {}", filename.display()))
+}
+
+/// Latest modification time of the certificate and key files
+fn modified(cert: &Path, key: &Path) -> Option<SystemTime> {
+    let cert = std::fs::metadata(cert).and_then(|meta| meta.modified()).ok()?;
+    let key = std::fs::metadata(key).and_then(|meta| meta.modified()).ok()?;
+    Some(cert.max(key))
+}
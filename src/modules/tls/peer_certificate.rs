@@ -0,0 +1,81 @@
+use rustls::pki_types::CertificateDer;
+use sha2::{Digest, Sha256};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+/// Certificate the client authenticated with over mutual TLS
+#[derive(Debug, Clone)]
+pub struct PeerCertificate {
+    subject: String,
+    issuer: String,
+    subject_alt_names: Vec<String>,
+    fingerprint: String,
+    der: Vec<u8>,
+}
+
+impl PeerCertificate {
+    pub(crate) fn from_der(der: &CertificateDer<'_>) -> Option<Self> {
+        let (_, cert) = parse_x509_certificate(der.as_ref()).ok()?;
+        let mut subject_alt_names = vec![];
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(dns) => subject_alt_names.push(format!("DNS:{}", dns)),
+                    GeneralName::RFC822Name(email) => {
+                        subject_alt_names.push(format!("email:{}", email))
+                    }
+                    GeneralName::URI(uri) => subject_alt_names.push(format!("URI:{}", uri)),
+                    GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => subject_alt_names.push(format!(
+                            "IP:{}",
+                            std::net::Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])
+                        )),
+                        16 => subject_alt_names.push(format!(
+                            "IP:{}",
+                            std::net::Ipv6Addr::from(<[u8; 16]>::try_from(*ip).ok()?)
+                        )),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+        let fingerprint = Sha256::digest(der.as_ref())
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":");
+        Some(Self {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            subject_alt_names,
+            fingerprint,
+            der: der.to_vec(),
+        })
+    }
+}
+
+impl PeerCertificate {
+    /// Distinguished name, e.g. `CN=billing, O=Example`
+    pub fn get_subject(&self) -> String {
+        self.subject.clone()
+    }
+
+    pub fn get_issuer(&self) -> String {
+        self.issuer.clone()
+    }
+
+    /// Subject alternative names prefixed by their kind, e.g. `DNS:billing.internal`
+    pub fn get_subject_alt_names(&self) -> Vec<String> {
+        self.subject_alt_names.clone()
+    }
+
+    /// SHA-256 of the DER encoding, as colon separated hex
+    pub fn get_fingerprint(&self) -> String {
+        self.fingerprint.clone()
+    }
+
+    pub fn get_der(&self) -> &[u8] {
+        &self.der
+    }
+}
@@ -0,0 +1,80 @@
+use crate::modules::shutdown::Drain;
+use bytes::{Buf, Bytes};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Takes over the connection once a `101 Switching Protocols` response is written
+pub type OnUpgrade = Box<dyn FnOnce(Upgraded) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+pub(crate) trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// Connection handed over after a protocol upgrade, plain or TLS. Bytes the
+/// client sent right after the handshake are read first
+pub struct Upgraded {
+    io: Box<dyn Io>,
+    buffered: Bytes,
+    drain: Drain,
+}
+
+impl Upgraded {
+    pub(crate) fn new<T: Io + 'static>(io: T, buffered: Bytes, drain: Drain) -> Self {
+        Self {
+            io: Box::new(io),
+            buffered,
+            drain,
+        }
+    }
+
+    /// Whether the server is shutting down
+    pub fn is_draining(&self) -> bool {
+        self.drain.is_draining()
+    }
+
+    /// Resolves once the server starts shutting down
+    pub async fn draining(&mut self) {
+        self.drain.wait().await
+    }
+
+    pub(crate) fn get_drain(&self) -> Drain {
+        self.drain.clone()
+    }
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buffered.is_empty() {
+            let len = self.buffered.len().min(buf.remaining());
+            buf.put_slice(&self.buffered[..len]);
+            self.buffered.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
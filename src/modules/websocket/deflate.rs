@@ -0,0 +1,268 @@
+use super::{close_code, Violation};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+/// Ends every compressed message and is left out on the wire, RFC 7692 section 7.2.1
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+/// Accepted `permessage-deflate` offer
+#[derive(Debug, Clone, Copy)]
+pub(super) struct DeflateParams {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    /// Picks the first offer in `Sec-WebSocket-Extensions` this server can
+    /// honour. Only 15 bit windows are supported for the server side
+    pub(super) fn negotiate(header: &str) -> Option<Self> {
+        header.split(',').find_map(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            if params.next()? != "permessage-deflate" {
+                return None;
+            }
+            let mut accepted = DeflateParams {
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+            };
+            for param in params {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (param, None),
+                };
+                match (name, value) {
+                    ("server_no_context_takeover", None) => {
+                        accepted.server_no_context_takeover = true
+                    }
+                    ("client_no_context_takeover", None) => {
+                        accepted.client_no_context_takeover = true
+                    }
+                    ("server_max_window_bits", Some("15")) => {}
+                    // The client's window can always be decoded with a 15 bit window
+                    ("client_max_window_bits", None) => {}
+                    ("client_max_window_bits", Some(bits))
+                        if bits
+                            .parse::<u8>()
+                            .is_ok_and(|bits| (8..=15).contains(&bits)) => {}
+                    _ => return None,
+                }
+            }
+            Some(accepted)
+        })
+    }
+
+    /// Value of the `Sec-WebSocket-Extensions` response header
+    pub(super) fn response_header(&self) -> String {
+        let mut header = "permessage-deflate".to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        header
+    }
+}
+
+pub(super) struct Deflate {
+    params: DeflateParams,
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl Deflate {
+    pub(super) fn new(params: DeflateParams) -> Self {
+        Self {
+            params,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        }
+    }
+
+    pub(super) fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, Violation> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity().max(64));
+            }
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|_| Violation(close_code::INTERNAL, "Compression failed"))?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            // The sync flush is complete once the output stops filling up
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&TRAILER) {
+            out.truncate(out.len() - TRAILER.len());
+        }
+        if self.params.server_no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+
+    /// Inflates a whole message, stopping as soon as it grows past `max_size`
+    pub(super) fn decompress(
+        &mut self,
+        data: &[u8],
+        max_size: usize,
+    ) -> Result<Vec<u8>, Violation> {
+        let input = [data, &TRAILER].concat();
+        let mut out = Vec::with_capacity((data.len() * 2).min(max_size) + 64);
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity().max(64));
+            }
+            let written = out.len();
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|_| Violation(close_code::INVALID_DATA, "Invalid compressed data"))?;
+            if out.len() > max_size {
+                return Err(Violation(close_code::TOO_BIG, "Message is too large"));
+            }
+            let before = consumed;
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let done = consumed == input.len() && out.len() < out.capacity();
+            if done || status == Status::StreamEnd {
+                break;
+            }
+            if consumed == before && out.len() == written {
+                return Err(Violation(
+                    close_code::INVALID_DATA,
+                    "Invalid compressed data",
+                ));
+            }
+        }
+        if self.params.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted(offer: &str) -> Option<String> {
+        DeflateParams::negotiate(offer).map(|params| params.response_header())
+    }
+
+    fn params(server_no_context_takeover: bool, client_no_context_takeover: bool) -> DeflateParams {
+        DeflateParams {
+            server_no_context_takeover,
+            client_no_context_takeover,
+        }
+    }
+
+    #[test]
+    fn accepts_supported_offers() {
+        assert_eq!(accepted("permessage-deflate").as_deref(), Some("permessage-deflate"));
+        assert_eq!(
+            accepted("permessage-deflate; client_max_window_bits").as_deref(),
+            Some("permessage-deflate")
+        );
+        assert_eq!(
+            accepted(
+                "permessage-deflate; server_no_context_takeover; client_no_context_takeover; \
+                 server_max_window_bits=15; client_max_window_bits=\"10\""
+            )
+            .as_deref(),
+            Some("permessage-deflate; server_no_context_takeover; client_no_context_takeover")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_next_offer() {
+        assert_eq!(
+            accepted(
+                "x-webkit-deflate-frame, permessage-deflate; server_max_window_bits=10, \
+                 permessage-deflate; client_no_context_takeover"
+            )
+            .as_deref(),
+            Some("permessage-deflate; client_no_context_takeover")
+        );
+    }
+
+    #[test]
+    fn declines_what_it_cant_honour() {
+        assert_eq!(accepted(""), None);
+        assert_eq!(accepted("x-webkit-deflate-frame"), None);
+        assert_eq!(accepted("permessage-deflate; server_max_window_bits=10"), None);
+        assert_eq!(accepted("permessage-deflate; client_max_window_bits=16"), None);
+        assert_eq!(accepted("permessage-deflate; client_max_window_bits=7"), None);
+        assert_eq!(accepted("permessage-deflate; server_no_context_takeover=1"), None);
+        assert_eq!(accepted("permessage-deflate; unknown"), None);
+    }
+
+    #[test]
+    fn inflates_messages_sharing_a_window() {
+        // RFC 7692 section 7.2.3.2, the second message refers back to the first
+        let mut deflate = Deflate::new(params(false, false));
+        let first = deflate.decompress(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 1024).unwrap();
+        assert_eq!(first, b"Hello");
+        let second = deflate.decompress(&[0xf2, 0x00, 0x11, 0x00, 0x00], 1024).unwrap();
+        assert_eq!(second, b"Hello");
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        for takeover in [false, true] {
+            let mut server = Deflate::new(params(takeover, takeover));
+            let mut client = Deflate::new(params(takeover, takeover));
+            for message in ["first message ".repeat(20), "second".to_string(), String::new()] {
+                let compressed = server.compress(message.as_bytes()).unwrap();
+                assert!(!compressed.ends_with(&TRAILER));
+                let inflated = client.decompress(&compressed, 1 << 20).unwrap();
+                assert_eq!(inflated, message.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn resets_the_window_without_context_takeover() {
+        let message = "repeated message ".repeat(10);
+        let mut shared = Deflate::new(params(false, false));
+        let first = shared.compress(message.as_bytes()).unwrap();
+        let second = shared.compress(message.as_bytes()).unwrap();
+        assert!(second.len() < first.len());
+
+        let mut reset = Deflate::new(params(true, false));
+        let first = reset.compress(message.as_bytes()).unwrap();
+        let second = reset.compress(message.as_bytes()).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn compresses_large_messages() {
+        let message: Vec<u8> = (0..1_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let compressed = Deflate::new(params(false, false)).compress(&message).unwrap();
+        let inflated = Deflate::new(params(false, false))
+            .decompress(&compressed, message.len())
+            .unwrap();
+        assert_eq!(inflated, message);
+    }
+
+    #[test]
+    fn stops_inflating_past_the_limit() {
+        let compressed = Deflate::new(params(false, false)).compress(&[0; 1 << 20]).unwrap();
+        let err = Deflate::new(params(false, false))
+            .decompress(&compressed, 1000)
+            .unwrap_err();
+        assert_eq!(err.0, close_code::TOO_BIG);
+    }
+
+    #[test]
+    fn rejects_invalid_data() {
+        let err = Deflate::new(params(false, false))
+            .decompress(&[0xff; 16], 1024)
+            .unwrap_err();
+        assert_eq!(err.0, close_code::INVALID_DATA);
+    }
+}
@@ -0,0 +1,259 @@
+use super::{close_code, Violation};
+use bytes::{Buf, BufMut, BytesMut};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            0x0 => Some(OpCode::Continuation),
+            0x1 => Some(OpCode::Text),
+            0x2 => Some(OpCode::Binary),
+            0x8 => Some(OpCode::Close),
+            0x9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    pub(super) fn is_control(self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Frame {
+    pub fin: bool,
+    /// Set on the first frame of a compressed message
+    pub rsv1: bool,
+    pub opcode: OpCode,
+    pub payload: BytesMut,
+}
+
+/// Takes the next whole frame from the front of `buf`, RFC 6455 section 5.2.
+/// `None` until enough bytes have been received
+pub(super) fn parse(buf: &mut BytesMut, max_payload: usize) -> Result<Option<Frame>, Violation> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let (first, second) = (buf[0], buf[1]);
+    if first & 0x30 != 0 {
+        return Err(Violation(close_code::PROTOCOL, "Reserved bits are set"));
+    }
+    let opcode =
+        OpCode::from_u8(first & 0x0F).ok_or(Violation(close_code::PROTOCOL, "Unknown opcode"))?;
+    if second & 0x80 == 0 {
+        return Err(Violation(
+            close_code::PROTOCOL,
+            "Client frames must be masked",
+        ));
+    }
+    let (length_size, length) = match second & 0x7F {
+        126 if buf.len() >= 4 => (2, u16::from_be_bytes([buf[2], buf[3]]) as u64),
+        127 if buf.len() >= 10 => (8, u64::from_be_bytes(buf[2..10].try_into().unwrap())),
+        126 | 127 => return Ok(None),
+        length => (0, length as u64),
+    };
+    if length >> 63 != 0 {
+        return Err(Violation(close_code::PROTOCOL, "Invalid frame length"));
+    }
+    if length > max_payload as u64 {
+        return Err(Violation(close_code::TOO_BIG, "Frame is too large"));
+    }
+    let header = 2 + length_size + 4;
+    let total = header + length as usize;
+    if buf.len() < total {
+        buf.reserve(total - buf.len());
+        return Ok(None);
+    }
+    let mut frame = buf.split_to(total);
+    let mask: [u8; 4] = frame[header - 4..header].try_into().unwrap();
+    frame.advance(header);
+    for (i, byte) in frame.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some(Frame {
+        fin: first & 0x80 != 0,
+        rsv1: first & 0x40 != 0,
+        opcode,
+        payload: frame,
+    }))
+}
+
+/// Appends an unmasked server frame to `buf`
+pub(super) fn encode(buf: &mut BytesMut, fin: bool, rsv1: bool, opcode: OpCode, payload: &[u8]) {
+    let mut first = opcode.as_u8();
+    if fin {
+        first |= 0x80;
+    }
+    if rsv1 {
+        first |= 0x40;
+    }
+    buf.reserve(payload.len() + 10);
+    buf.put_u8(first);
+    match payload.len() {
+        len if len < 126 => buf.put_u8(len as u8),
+        len if len <= u16::MAX as usize => {
+            buf.put_u8(126);
+            buf.put_u16(len as u16);
+        }
+        len => {
+            buf.put_u8(127);
+            buf.put_u64(len as u64);
+        }
+    }
+    buf.put_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// Client frame with a 7 bit, 16 bit or 64 bit length as its size needs
+    fn masked(first: u8, payload: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(first);
+        match payload.len() {
+            len if len < 126 => buf.put_u8(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                buf.put_u8(0x80 | 126);
+                buf.put_u16(len as u16);
+            }
+            len => {
+                buf.put_u8(0x80 | 127);
+                buf.put_u64(len as u64);
+            }
+        }
+        buf.put_slice(&MASK);
+        buf.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        buf
+    }
+
+    fn violation(buf: &[u8]) -> u16 {
+        parse(&mut BytesMut::from(buf), 1 << 20).unwrap_err().0
+    }
+
+    #[test]
+    fn unmasks_a_text_frame() {
+        // RFC 6455 section 5.7
+        let mut buf = BytesMut::from(
+            &[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58][..],
+        );
+        let frame = parse(&mut buf, 125).unwrap().unwrap();
+        assert!(frame.fin && !frame.rsv1);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(&frame.payload[..], b"Hello");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        let wire = masked(0x82, &[9; 300]);
+        let mut buf = BytesMut::new();
+        for &byte in &wire[..wire.len() - 1] {
+            buf.put_u8(byte);
+            assert!(parse(&mut buf, 1024).unwrap().is_none());
+        }
+        buf.put_u8(wire[wire.len() - 1]);
+        let frame = parse(&mut buf, 1024).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Binary);
+        assert_eq!(&frame.payload[..], &[9; 300][..]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn takes_frames_one_at_a_time() {
+        let mut buf = masked(0x01, b"frag");
+        buf.extend_from_slice(&masked(0x89, b"ping"));
+        buf.extend_from_slice(&masked(0x80, b"ment"));
+        let first = parse(&mut buf, 125).unwrap().unwrap();
+        assert!(!first.fin);
+        assert_eq!(first.opcode, OpCode::Text);
+        let ping = parse(&mut buf, 125).unwrap().unwrap();
+        assert!(ping.opcode.is_control());
+        assert_eq!(&ping.payload[..], b"ping");
+        let last = parse(&mut buf, 125).unwrap().unwrap();
+        assert!(last.fin);
+        assert_eq!(last.opcode, OpCode::Continuation);
+        assert_eq!(&last.payload[..], b"ment");
+        assert!(parse(&mut buf, 125).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_extended_lengths() {
+        for len in [125, 126, u16::MAX as usize, u16::MAX as usize + 1] {
+            let payload = vec![b'x'; len];
+            let frame = parse(&mut masked(0x82, &payload), 1 << 20).unwrap().unwrap();
+            assert_eq!(frame.payload.len(), len);
+        }
+    }
+
+    #[test]
+    fn keeps_the_compressed_bit() {
+        let frame = parse(&mut masked(0xC1, b"x"), 125).unwrap().unwrap();
+        assert!(frame.rsv1);
+    }
+
+    #[test]
+    fn rejects_protocol_violations() {
+        // Unmasked
+        assert_eq!(violation(&[0x81, 0x00]), close_code::PROTOCOL);
+        // RSV2 and RSV3
+        assert_eq!(violation(&masked(0xA1, b"")), close_code::PROTOCOL);
+        assert_eq!(violation(&masked(0x91, b"")), close_code::PROTOCOL);
+        // Reserved opcodes
+        assert_eq!(violation(&masked(0x83, b"")), close_code::PROTOCOL);
+        assert_eq!(violation(&masked(0x8B, b"")), close_code::PROTOCOL);
+        // Most significant bit of a 64 bit length
+        let mut huge = BytesMut::from(&[0x82, 0xFF][..]);
+        huge.put_u64(1 << 63);
+        assert_eq!(violation(&huge), close_code::PROTOCOL);
+    }
+
+    #[test]
+    fn refuses_large_frames_before_they_arrive() {
+        let mut header = BytesMut::from(&[0x82, 0xFF][..]);
+        header.put_u64(1 << 40);
+        assert_eq!(violation(&header), close_code::TOO_BIG);
+        let mut buf = masked(0x82, &[0; 200]);
+        assert_eq!(parse(&mut buf, 199).unwrap_err().0, close_code::TOO_BIG);
+    }
+
+    #[test]
+    fn encodes_unmasked_server_frames() {
+        let mut buf = BytesMut::new();
+        encode(&mut buf, true, false, OpCode::Text, b"Hello");
+        assert_eq!(&buf[..], &[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        let mut buf = BytesMut::new();
+        encode(&mut buf, false, true, OpCode::Binary, &[0; 126]);
+        assert_eq!(&buf[..4], &[0x42, 126, 0x00, 126]);
+        assert_eq!(buf.len(), 4 + 126);
+
+        let mut buf = BytesMut::new();
+        encode(&mut buf, true, false, OpCode::Binary, &[0; 65536]);
+        assert_eq!(&buf[..10], &[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(buf.len(), 10 + 65536);
+    }
+}
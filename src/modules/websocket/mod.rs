@@ -0,0 +1,772 @@
+mod deflate;
+mod frame;
+
+use crate::http::request::Request;
+use crate::http::response::{Response, ResponseBuilder};
+use crate::http::status::StatusCode;
+use crate::modules::shutdown::Drain;
+use crate::modules::upgrade::Upgraded;
+use anyhow::Result;
+use base64ct::{Base64, Encoding};
+use bytes::{Buf, BytesMut};
+use deflate::{Deflate, DeflateParams};
+use frame::{Frame, OpCode};
+use sha1::{Digest, Sha1};
+use std::borrow::Cow;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{interval_at, sleep_until, timeout, Instant, Interval, MissedTickBehavior};
+
+/// Appended to the client key to prove the server speaks WebSocket, RFC 6455 section 1.3
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Room made in the read buffer before each read
+const READ_SIZE: usize = 8 * 1024;
+
+/// Shorter messages are sent uncompressed, deflate would only grow them
+const DEFLATE_THRESHOLD: usize = 64;
+
+/// Status codes of close frames, RFC 6455 section 7.4.1
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL: u16 = 1002;
+    pub const UNSUPPORTED: u16 = 1003;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const POLICY: u16 = 1008;
+    pub const TOO_BIG: u16 = 1009;
+    pub const INTERNAL: u16 = 1011;
+}
+
+/// Broken protocol rule, ends the connection with a close frame carrying the code
+#[derive(Debug)]
+struct Violation(u16, &'static str);
+
+/// Limits and keepalive of WebSocket connections, set with `NuttServer::websocket`
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    max_message_size: usize,
+    max_frame_size: usize,
+    fragment_size: Option<usize>,
+    ping_interval: Option<Duration>,
+    close_timeout: Duration,
+    deflate: bool,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebSocketConfig {
+    pub fn new() -> Self {
+        Self {
+            max_message_size: 16 * 1024 * 1024,
+            max_frame_size: 16 * 1024 * 1024,
+            fragment_size: None,
+            ping_interval: Some(Duration::from_secs(30)),
+            close_timeout: Duration::from_secs(5),
+            deflate: false,
+        }
+    }
+
+    /// Largest received message after reassembly and decompression, closed with 1009
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// Largest received frame, closed with 1009
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Splits sent messages into frames of at most this many bytes
+    pub fn fragment_size(mut self, bytes: usize) -> Self {
+        self.fragment_size = Some(bytes.max(1));
+        self
+    }
+
+    /// Pings an idle client this often and drops it when it doesn't answer
+    /// before the next ping. `None` turns keepalive off
+    pub fn ping_interval(mut self, interval: Option<Duration>) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// How long to wait for the client to answer a close frame
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// Accepts `permessage-deflate` when the client offers it, RFC 7692
+    pub fn deflate(mut self, enabled: bool) -> Self {
+        self.deflate = enabled;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    code: u16,
+    reason: String,
+}
+
+impl CloseFrame {
+    pub fn new(code: u16, reason: &str) -> Self {
+        Self {
+            code,
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn get_code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn get_reason(&self) -> String {
+        self.reason.clone()
+    }
+
+    fn parse(payload: &[u8]) -> Result<Option<Self>, Violation> {
+        match payload.len() {
+            0 => return Ok(None),
+            1 => return Err(Violation(close_code::PROTOCOL, "Invalid close frame")),
+            _ => {}
+        }
+        let code = u16::from_be_bytes([payload[0], payload[1]]);
+        // Codes that may not be sent on the wire or are unassigned
+        if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+            return Err(Violation(close_code::PROTOCOL, "Invalid close code"));
+        }
+        let reason = std::str::from_utf8(&payload[2..])
+            .map_err(|_| Violation(close_code::INVALID_DATA, "Close reason is not valid UTF-8"))?;
+        Ok(Some(CloseFrame::new(code, reason)))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        // Control frames carry at most 125 bytes
+        let mut end = self.reason.len().min(123);
+        while !self.reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        [&self.code.to_be_bytes()[..], &self.reason.as_bytes()[..end]].concat()
+    }
+}
+
+/// Checked WebSocket handshake, answered by `on_upgrade`. Routes made with
+/// `Route::ws` use it; fallback handlers can upgrade requests themselves
+pub struct WebSocketUpgrade {
+    key: String,
+    deflate: Option<DeflateParams>,
+    config: Arc<WebSocketConfig>,
+}
+
+impl WebSocketUpgrade {
+    /// Checks the handshake headers, RFC 6455 section 4.2.1. `Err` is the
+    /// response to send instead: 426 for plain HTTP or another version, 400 for a bad key
+    pub fn from_request(req: &Request) -> Result<Self, Response> {
+        let has_token = |name: &str, token: &str| {
+            req.get_header(name).is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|item| item.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        if !has_token("Upgrade", "websocket")
+            || !has_token("Connection", "upgrade")
+            || req.get_header("Sec-WebSocket-Version").as_deref() != Some("13")
+        {
+            return Err(ResponseBuilder::new(
+                StatusCode::UpgradeRequired,
+                "Expected a WebSocket handshake",
+            )
+            .set_header("Upgrade", "websocket")
+            .set_header("Sec-WebSocket-Version", "13")
+            .build());
+        }
+        let key = req.get_header("Sec-WebSocket-Key").unwrap_or_default();
+        if Base64::decode_vec(key.trim()).map_or(true, |nonce| nonce.len() != 16) {
+            return Err(
+                ResponseBuilder::new(StatusCode::BadRequest, "Invalid Sec-WebSocket-Key").build(),
+            );
+        }
+        let config = req.get_websocket_config().unwrap_or_default();
+        let deflate = config
+            .deflate
+            .then(|| req.get_header("Sec-WebSocket-Extensions"))
+            .flatten()
+            .and_then(|offers| DeflateParams::negotiate(&offers));
+        Ok(Self {
+            key: key.trim().to_string(),
+            deflate,
+            config,
+        })
+    }
+
+    /// `101 Switching Protocols` response, `callback` gets the socket once it is written
+    pub fn on_upgrade<F, Fut>(self, callback: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut hasher = Sha1::new();
+        hasher.update(self.key.as_bytes());
+        hasher.update(GUID.as_bytes());
+        let accept = Base64::encode_string(&hasher.finalize());
+        let extension = self.deflate.map(|params| params.response_header());
+        let mut headers = vec![
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Accept", accept.as_str()),
+        ];
+        if let Some(extension) = &extension {
+            headers.push(("Sec-WebSocket-Extensions", extension));
+        }
+        let (config, deflate) = (self.config, self.deflate);
+        Response::switching_protocols(
+            &headers,
+            Box::new(move |upgraded| {
+                Box::pin(async move { callback(WebSocket::new(upgraded, config, deflate)).await })
+            }),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SocketState {
+    Open,
+    CloseSent,
+    Closed,
+}
+
+/// Message being reassembled from fragments
+struct Partial {
+    opcode: OpCode,
+    compressed: bool,
+    data: Vec<u8>,
+}
+
+/// Open WebSocket connection. `recv` and `send` answer pings and close frames
+/// by themselves and send the keepalive pings, so a handler that only sends
+/// still notices when the client is gone
+pub struct WebSocket {
+    io: Upgraded,
+    drain: Drain,
+    config: Arc<WebSocketConfig>,
+    deflate: Option<Deflate>,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    partial: Option<Partial>,
+    /// Message read by `send`, returned by the next `recv`
+    pending: Option<Message>,
+    state: SocketState,
+    ping: Option<Interval>,
+    awaiting_pong: bool,
+    close_deadline: Option<Instant>,
+}
+
+impl WebSocket {
+    fn new(io: Upgraded, config: Arc<WebSocketConfig>, deflate: Option<DeflateParams>) -> Self {
+        let ping = config.ping_interval.map(|period| {
+            let mut ping = interval_at(Instant::now() + period, period);
+            ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ping
+        });
+        Self {
+            drain: io.get_drain(),
+            io,
+            config,
+            deflate: deflate.map(Deflate::new),
+            read_buf: BytesMut::with_capacity(READ_SIZE),
+            write_buf: BytesMut::new(),
+            partial: None,
+            pending: None,
+            state: SocketState::Open,
+            ping,
+            awaiting_pong: false,
+            close_deadline: None,
+        }
+    }
+
+    /// Next message, `None` once the connection is closed. Pings are answered
+    /// and still returned, except the ones `send` came across. Safe to use in
+    /// `select!`, nothing is lost when cancelled
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        if let Some(message) = self.pending.take() {
+            return Ok(Some(message));
+        }
+        Ok(self.advance(true).await?.flatten())
+    }
+
+    /// Reads frames and runs the timers until a message arrives, `Some(None)`
+    /// once the connection is closed. Without `wait` it returns `None` instead
+    /// of waiting for the client
+    async fn advance(&mut self, wait: bool) -> Result<Option<Option<Message>>> {
+        loop {
+            if self.state == SocketState::Closed {
+                return Ok(Some(None));
+            }
+            self.flush().await?;
+            let frame = match frame::parse(&mut self.read_buf, self.config.max_frame_size) {
+                Ok(frame) => frame,
+                Err(violation) => return Err(self.fail(violation).await),
+            };
+            if let Some(frame) = frame {
+                match self.handle_frame(frame) {
+                    Ok(Some(message)) => {
+                        if self.state == SocketState::Closed {
+                            self.finish().await;
+                        }
+                        return Ok(Some(Some(message)));
+                    }
+                    Ok(None) => continue,
+                    Err(violation) => return Err(self.fail(violation).await),
+                }
+            }
+            self.read_buf.reserve(READ_SIZE);
+            let draining = self.state == SocketState::Open;
+            tokio::select! {
+                biased;
+                read = self.io.read_buf(&mut self.read_buf) => {
+                    if read? == 0 {
+                        // Closed without a close frame, 1006 in RFC terms
+                        self.state = SocketState::Closed;
+                        return Ok(Some(None));
+                    }
+                    self.awaiting_pong = false;
+                }
+                _ = tick(&mut self.ping) => {
+                    if self.awaiting_pong {
+                        self.state = SocketState::Closed;
+                        self.finish().await;
+                        anyhow::bail!("WebSocket client stopped answering pings");
+                    }
+                    self.awaiting_pong = true;
+                    self.queue(true, false, OpCode::Ping, &[]);
+                }
+                _ = self.drain.wait(), if draining => {
+                    let frame = CloseFrame::new(close_code::GOING_AWAY, "Server is shutting down");
+                    self.queue_close(Some(frame));
+                }
+                _ = expire(self.close_deadline) => {
+                    self.state = SocketState::Closed;
+                    self.finish().await;
+                    return Ok(Some(None));
+                }
+                _ = std::future::ready(()), if !wait => return Ok(None),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        // Catch up on what the client sent. A data message stops the reading
+        // until `recv` takes it, answered pings aren't kept
+        while self.pending.is_none() {
+            match self.advance(false).await? {
+                None => break,
+                Some(Some(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(message) => {
+                    self.pending = message;
+                    break;
+                }
+            }
+        }
+        if self.state != SocketState::Open {
+            anyhow::bail!("WebSocket is closing");
+        }
+        match message {
+            Message::Text(text) => self.queue_message(OpCode::Text, text.as_bytes())?,
+            Message::Binary(data) => self.queue_message(OpCode::Binary, &data)?,
+            Message::Ping(data) | Message::Pong(data) if data.len() > 125 => {
+                anyhow::bail!("Ping and pong payloads are limited to 125 bytes")
+            }
+            Message::Ping(data) => self.queue(true, false, OpCode::Ping, &data),
+            Message::Pong(data) => self.queue(true, false, OpCode::Pong, &data),
+            Message::Close(frame) => self.queue_close(frame),
+        }
+        self.flush().await
+    }
+
+    /// Sends a close frame and waits for the client's answer, at most the close timeout
+    pub async fn close(mut self, code: u16, reason: &str) -> Result<()> {
+        if self.state == SocketState::Open {
+            self.queue_close(Some(CloseFrame::new(code, reason)));
+        }
+        while let Some(message) = self.recv().await? {
+            if let Message::Close(_) = message {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<Option<Message>, Violation> {
+        let Frame {
+            fin,
+            rsv1,
+            opcode,
+            payload,
+        } = frame;
+        if opcode.is_control() {
+            if !fin || payload.len() > 125 || rsv1 {
+                return Err(Violation(close_code::PROTOCOL, "Invalid control frame"));
+            }
+            return Ok(Some(match opcode {
+                OpCode::Ping => {
+                    if self.state == SocketState::Open {
+                        self.queue(true, false, OpCode::Pong, &payload);
+                    }
+                    Message::Ping(payload.to_vec())
+                }
+                OpCode::Pong => Message::Pong(payload.to_vec()),
+                _ => {
+                    let frame = CloseFrame::parse(&payload)?;
+                    if self.state == SocketState::Open {
+                        // Echo the code back, RFC 6455 section 5.5.1
+                        let echo = frame.as_ref().map(CloseFrame::encode).unwrap_or_default();
+                        self.queue(true, false, OpCode::Close, &echo);
+                    }
+                    self.state = SocketState::Closed;
+                    Message::Close(frame)
+                }
+            }));
+        }
+        match (opcode, &mut self.partial) {
+            (OpCode::Continuation, None) => {
+                return Err(Violation(
+                    close_code::PROTOCOL,
+                    "Continuation frame without a message",
+                ))
+            }
+            (OpCode::Continuation, Some(_)) if rsv1 => {
+                return Err(Violation(
+                    close_code::PROTOCOL,
+                    "Compression flag on a continuation frame",
+                ))
+            }
+            (OpCode::Continuation, Some(partial)) => partial.data.extend_from_slice(&payload),
+            (_, Some(_)) => {
+                return Err(Violation(
+                    close_code::PROTOCOL,
+                    "Expected a continuation frame",
+                ))
+            }
+            (_, None) => {
+                if rsv1 && self.deflate.is_none() {
+                    return Err(Violation(
+                        close_code::PROTOCOL,
+                        "Compression was not negotiated",
+                    ));
+                }
+                self.partial = Some(Partial {
+                    opcode,
+                    compressed: rsv1,
+                    data: payload.to_vec(),
+                });
+            }
+        }
+        let max_size = self.config.max_message_size;
+        if self
+            .partial
+            .as_ref()
+            .is_some_and(|partial| partial.data.len() > max_size)
+        {
+            return Err(Violation(close_code::TOO_BIG, "Message is too large"));
+        }
+        if !fin {
+            return Ok(None);
+        }
+        let Some(partial) = self.partial.take() else {
+            return Ok(None);
+        };
+        let data = match (&mut self.deflate, partial.compressed) {
+            (Some(deflate), true) => deflate.decompress(&partial.data, max_size)?,
+            _ => partial.data,
+        };
+        Ok(Some(match partial.opcode {
+            OpCode::Text => Message::Text(String::from_utf8(data).map_err(|_| {
+                Violation(close_code::INVALID_DATA, "Text message is not valid UTF-8")
+            })?),
+            _ => Message::Binary(data),
+        }))
+    }
+
+    fn queue_message(&mut self, opcode: OpCode, data: &[u8]) -> Result<()> {
+        let (payload, compressed) = match &mut self.deflate {
+            Some(deflate) if data.len() >= DEFLATE_THRESHOLD => {
+                let compressed = deflate
+                    .compress(data)
+                    .map_err(|violation| anyhow::Error::msg(violation.1))?;
+                (Cow::Owned(compressed), true)
+            }
+            _ => (Cow::Borrowed(data), false),
+        };
+        let size = self.config.fragment_size.unwrap_or(usize::MAX);
+        if payload.is_empty() {
+            self.queue(true, compressed, opcode, &[]);
+            return Ok(());
+        }
+        let count = payload.len().div_ceil(size);
+        for (i, chunk) in payload.chunks(size).enumerate() {
+            let opcode = if i == 0 { opcode } else { OpCode::Continuation };
+            self.queue(i + 1 == count, compressed && i == 0, opcode, chunk);
+        }
+        Ok(())
+    }
+
+    fn queue_close(&mut self, frame: Option<CloseFrame>) {
+        let payload = frame.as_ref().map(CloseFrame::encode).unwrap_or_default();
+        self.queue(true, false, OpCode::Close, &payload);
+        self.state = SocketState::CloseSent;
+        self.close_deadline = Some(Instant::now() + self.config.close_timeout);
+    }
+
+    fn queue(&mut self, fin: bool, rsv1: bool, opcode: OpCode, payload: &[u8]) {
+        frame::encode(&mut self.write_buf, fin, rsv1, opcode, payload);
+    }
+
+    /// Writes the queued frames. What was written stays written when cancelled
+    async fn flush(&mut self) -> Result<()> {
+        while !self.write_buf.is_empty() {
+            let written = self.io.write(&self.write_buf).await?;
+            if written == 0 {
+                anyhow::bail!("WebSocket connection closed");
+            }
+            self.write_buf.advance(written);
+        }
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    /// Closes the connection with the close code of `violation`
+    async fn fail(&mut self, violation: Violation) -> anyhow::Error {
+        if self.state == SocketState::Open {
+            self.queue_close(Some(CloseFrame::new(violation.0, violation.1)));
+        }
+        self.state = SocketState::Closed;
+        self.finish().await;
+        anyhow::Error::msg(format!(
+            "WebSocket closed with {}: {}",
+            violation.0, violation.1
+        ))
+    }
+
+    /// Flushes what is left and shuts the connection down, bounded by the close timeout
+    async fn finish(&mut self) {
+        let close_timeout = self.config.close_timeout;
+        let _ = timeout(close_timeout, async {
+            let _ = self.flush().await;
+            let _ = self.io.shutdown().await;
+        })
+        .await;
+    }
+}
+
+async fn tick(ping: &mut Option<Interval>) {
+    match ping {
+        Some(ping) => {
+            ping.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn expire(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::method::Method;
+    use crate::http::request::RequestBuilder;
+    use crate::modules::shutdown::Drain;
+    use bytes::Bytes;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+    use tokio::sync::watch;
+
+    fn handshake(headers: &[(&str, &str)]) -> RequestBuilder {
+        let builder = RequestBuilder::new(Method::GET, "")
+            .set_header("Upgrade", "websocket")
+            .set_header("Connection", "keep-alive, Upgrade")
+            .set_header("Sec-WebSocket-Version", "13")
+            .set_header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+        headers
+            .iter()
+            .fold(builder, |builder, (name, value)| builder.set_header(name, value))
+    }
+
+    fn upgrade(req: Request) -> Response {
+        match WebSocketUpgrade::from_request(&req) {
+            Ok(upgrade) => upgrade.on_upgrade(|_| async {}),
+            Err(resp) => resp,
+        }
+    }
+
+    /// Server side socket and the client end, the drain sender keeps it from closing
+    fn socket(config: WebSocketConfig) -> (WebSocket, DuplexStream, watch::Sender<bool>) {
+        let (server, client) = duplex(1 << 16);
+        let (shutdown, drain) = Drain::new();
+        let io = Upgraded::new(server, Bytes::new(), drain);
+        (WebSocket::new(io, Arc::new(config), None), client, shutdown)
+    }
+
+    /// Client frame masked with a zero key, so the payload goes as is
+    async fn write_frame(client: &mut DuplexStream, opcode: u8, payload: &[u8]) {
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(payload);
+        client.write_all(&frame).await.unwrap();
+    }
+
+    /// Opcode and payload of the next server frame
+    async fn read_frame(client: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).await.unwrap();
+        let mut payload = vec![0; (head[1] & 0x7F) as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    #[tokio::test]
+    async fn answers_control_frames_while_only_sending() {
+        let (mut ws, mut client, _shutdown) = socket(WebSocketConfig::new());
+        write_frame(&mut client, 0x9, b"are you there").await;
+        ws.send(Message::Text("tick".into())).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, (0xA, b"are you there".to_vec()));
+        assert_eq!(read_frame(&mut client).await, (0x1, b"tick".to_vec()));
+
+        // A data message waits for `recv`, the close frame is echoed on the next send
+        write_frame(&mut client, 0x1, b"hello").await;
+        write_frame(&mut client, 0x8, &[0x03, 0xE8]).await;
+        ws.send(Message::Text("tock".into())).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, (0x1, b"tock".to_vec()));
+        assert_eq!(ws.recv().await.unwrap(), Some(Message::Text("hello".into())));
+        assert!(ws.send(Message::Text("late".into())).await.is_err());
+        assert_eq!(read_frame(&mut client).await, (0x8, vec![0x03, 0xE8]));
+    }
+
+    #[tokio::test]
+    async fn pings_while_only_sending() {
+        let interval = Duration::from_millis(50);
+        let config = WebSocketConfig::new().ping_interval(Some(interval));
+        let (mut ws, mut client, _shutdown) = socket(config);
+        tokio::time::sleep(interval * 2).await;
+        ws.send(Message::Text("tick".into())).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, (0x9, vec![]));
+        assert_eq!(read_frame(&mut client).await, (0x1, b"tick".to_vec()));
+
+        // The client never answers, so a later send gives up on it
+        tokio::time::sleep(interval * 2).await;
+        assert!(ws.send(Message::Text("tock".into())).await.is_err());
+    }
+
+    #[test]
+    fn answers_the_handshake() {
+        let resp = upgrade(handshake(&[]).build());
+        assert_eq!(resp.get_status(), StatusCode::SwitchingProtocols);
+        // RFC 6455 section 1.3
+        assert_eq!(
+            resp.get_header("Sec-WebSocket-Accept").as_deref(),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert!(resp.get_header("Sec-WebSocket-Extensions").is_none());
+    }
+
+    #[test]
+    fn refuses_bad_handshakes() {
+        for (name, value) in [
+            ("Upgrade", "h2c"),
+            ("Connection", "keep-alive"),
+            ("Sec-WebSocket-Version", "8"),
+        ] {
+            let resp = upgrade(handshake(&[(name, value)]).build());
+            assert_eq!(resp.get_status(), StatusCode::UpgradeRequired, "{}", name);
+            assert_eq!(resp.get_header("Sec-WebSocket-Version").as_deref(), Some("13"));
+        }
+        for key in ["", "not base64!", "c2hvcnQ="] {
+            let resp = upgrade(handshake(&[("Sec-WebSocket-Key", key)]).build());
+            assert_eq!(resp.get_status(), StatusCode::BadRequest, "{}", key);
+        }
+    }
+
+    #[test]
+    fn negotiates_deflate_when_enabled() {
+        let offer = [("Sec-WebSocket-Extensions", "permessage-deflate; client_max_window_bits")];
+        let mut req = handshake(&offer).build();
+        req.set_websocket_config(Arc::new(WebSocketConfig::new().deflate(true)));
+        let resp = upgrade(req);
+        assert_eq!(
+            resp.get_header("Sec-WebSocket-Extensions").as_deref(),
+            Some("permessage-deflate")
+        );
+
+        // Off unless configured
+        let resp = upgrade(handshake(&offer).build());
+        assert!(resp.get_header("Sec-WebSocket-Extensions").is_none());
+    }
+
+    #[test]
+    fn parses_close_frames() {
+        assert!(CloseFrame::parse(&[]).unwrap().is_none());
+        let frame = CloseFrame::parse(&[0x03, 0xE8, b'b', b'y', b'e']).unwrap().unwrap();
+        assert_eq!(frame, CloseFrame::new(close_code::NORMAL, "bye"));
+        assert!(CloseFrame::parse(&4999u16.to_be_bytes()).is_ok());
+
+        assert_eq!(CloseFrame::parse(&[0x03]).unwrap_err().0, close_code::PROTOCOL);
+        // Reserved for local use or unassigned
+        for code in [999u16, 1004, 1005, 1006, 1015, 2999, 5000] {
+            let err = CloseFrame::parse(&code.to_be_bytes()).unwrap_err();
+            assert_eq!(err.0, close_code::PROTOCOL, "{}", code);
+        }
+        let err = CloseFrame::parse(&[0x03, 0xE8, 0xFF]).unwrap_err();
+        assert_eq!(err.0, close_code::INVALID_DATA);
+    }
+
+    #[test]
+    fn fits_close_frames_in_a_control_frame() {
+        let frame = CloseFrame::new(close_code::GOING_AWAY, "restart");
+        assert_eq!(frame.encode(), [&[0x03, 0xE9][..], b"restart"].concat());
+
+        // Cut at 123 bytes of reason without splitting a character
+        let encoded = CloseFrame::new(close_code::NORMAL, &"é".repeat(100)).encode();
+        assert_eq!(encoded.len(), 2 + 122);
+        let parsed = CloseFrame::parse(&encoded).unwrap().unwrap();
+        assert_eq!(parsed.get_reason(), "é".repeat(61));
+    }
+}
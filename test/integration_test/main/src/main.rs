@@ -1,7 +1,11 @@
+use nutt_web::http::error::HttpError;
+use nutt_web::http::request::Request;
 use nutt_web::http::response::responder::Responder;
 use nutt_web::http::response::{Response, ResponseBuilder};
 use nutt_web::http::status::StatusCode;
+use nutt_web::modules::http2::Http2Config;
 use nutt_web::modules::router::route::Route;
+use nutt_web::modules::router::Router;
 use nutt_web::modules::session::cookie_session::{CookieSession, SessionId};
 use nutt_web::modules::session::SessionType;
 use nutt_web::modules::state::State;
@@ -9,8 +13,6 @@ use nutt_web::modules::{delete, get, include_addr, post, put};
 use nutt_web::{routes, state, NuttServer};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::hash::Hasher;
-use std::ops::Deref;
 use tracing_log::log::{log, Level};
 
 include_addr!();
@@ -27,11 +29,6 @@ struct Data {
     password: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct UserId {
-    id: String,
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct NewStateJson {
     num: i32,
@@ -62,13 +59,50 @@ impl App {
                 .session(SessionType::Cookie)
                 .state(state!(num))
                 .state(state!(tokens))
-                .set_tls_certs(Some(("./certs/certificate.crt", "./certs/private.key"))),
+                .router(Router::new().nest("/api/v1", App::api()))
+                .set_tls_certs(Some(("./certs/certificate.crt", "./certs/private.key")))
+                .http2(Http2Config::new())
+                .fallback(App::fallback)
+                .on_error(App::on_error),
         }
     }
     pub async fn run(self) {
         self.server.run().await.unwrap();
     }
 
+    fn api() -> Router {
+        let version = State::new("v1".to_string());
+        Router::new()
+            .routes(routes!(App::api_version))
+            .state(state!(version))
+            .group("/admin", |group| {
+                group
+                    .middleware(App::admin_only)
+                    .routes(routes!(App::api_version))
+            })
+    }
+
+    async fn admin_only(req: Request) -> Result<Request, Response> {
+        if req.get_cookie_jar().get("admin").is_some() {
+            Ok(req)
+        } else {
+            Err(ResponseBuilder::new(StatusCode::Forbidden, "").build())
+        }
+    }
+
+    #[get("/version")]
+    async fn api_version(version: State<String>) -> Response {
+        version.read().clone().into_response()
+    }
+
+    async fn fallback(req: Request) -> Response {
+        ResponseBuilder::new(StatusCode::NotFound, format!("{} not found", req.get_path())).build()
+    }
+
+    async fn on_error(err: HttpError) -> Response {
+        ResponseBuilder::new(err.get_status(), err.get_message()).build()
+    }
+
     #[get("/")]
     async fn hello() -> Response {
         "hello".into_response()